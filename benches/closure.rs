@@ -0,0 +1,58 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use xmachine::{xasm, Machine, Value};
+
+/// Make a machine with the given number of registers and stack items
+fn machine(size: usize) -> Machine {
+    let mut m = Machine::new();
+    for i in 0..size {
+        m.push(Value::string(format!("item {}", i)));
+        m.push(Value::int(i as i64));
+        m.push(Value::string(format!("register{}", i)));
+        m.store();
+    }
+    m
+}
+
+/// Compares making a function that captures the whole machine
+/// with making a closure that only captures one register,
+/// as the machine they are made in grows
+fn creation(c: &mut Criterion) {
+    let code = xasm::assemble("\"register0\" load 1 add").unwrap();
+    let mut group = c.benchmark_group("closure creation");
+    for size in [10, 100, 1000] {
+        let m = machine(size);
+        group.bench_with_input(BenchmarkId::new("program", size), &m, |b, m| {
+            b.iter(|| Value::program(black_box(code.clone()), m))
+        });
+        group.bench_with_input(BenchmarkId::new("program_closure", size), &m, |b, m| {
+            b.iter(|| Value::program_closure(black_box(code.clone()), m, &["register0"]))
+        });
+    }
+    group.finish();
+}
+
+/// Makes a closure on every iteration of a guest loop, as the
+/// number of registers in the machine running the loop grows
+fn hot_loop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("closure in loop");
+    for size in [10, 100, 1000] {
+        for literal in ["fn", "fn [x]"] {
+            let program = xasm::assemble(&format!(
+                "fn {{ {} {{ \"x\" load }} call }} 0 100 range \"x\" \"i\" for_loop",
+                literal
+            ))
+            .unwrap();
+            let mut m = machine(size);
+            group.bench_function(BenchmarkId::new(literal, size), |b| {
+                b.iter(|| {
+                    m.try_run(&program).unwrap();
+                    m.stack.clear();
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, creation, hot_loop);
+criterion_main!(benches);
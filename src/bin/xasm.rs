@@ -0,0 +1,55 @@
+//! Assemble and run xasm source files, or compile them to bytecode
+//!
+//! Usage: xasm <file.xasm | file.xbc>...
+//!        xasm --compile <file.xasm> <file.xbc>
+extern crate xmachine;
+use xmachine::{bytecode, xasm, Instruction, Machine};
+
+use std::{env, fs, process};
+
+const USAGE: &str =
+    "usage: xasm <file.xasm | file.xbc>...\n       xasm --compile <file.xasm> <file.xbc>";
+
+/// Print an error message and exit
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+/// Load a program from either xasm source or bytecode
+fn load(path: &str) -> Vec<Instruction> {
+    let bytes = fs::read(path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+
+    if bytes.starts_with(bytecode::MAGIC) {
+        bytecode::decode(&bytes).unwrap_or_else(|e| fail(format!("{}: {}", path, e)))
+    } else {
+        let source = String::from_utf8(bytes)
+            .unwrap_or_else(|_| fail(format!("{}: source is not valid UTF-8", path)));
+        xasm::assemble(&source).unwrap_or_else(|e| fail(format!("{}:{}", path, e)))
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => fail(String::from(USAGE)),
+        Some("--compile") => {
+            if args.len() != 3 {
+                fail(String::from(USAGE));
+            }
+            let program = load(&args[1]);
+            fs::write(&args[2], bytecode::encode(&program))
+                .unwrap_or_else(|e| fail(format!("{}: {}", args[2], e)));
+        }
+        Some(_) => {
+            // Every file is run in the same machine, in order
+            let mut machine = Machine::new();
+            for path in &args {
+                if let Err(e) = machine.try_run(&load(path)) {
+                    fail(format!("{}: {}", path, e));
+                }
+            }
+            println!("{}", machine);
+        }
+    }
+}
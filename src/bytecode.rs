@@ -0,0 +1,529 @@
+//! The `.xbc` bytecode format stores compiled programs as bytes.
+//!
+//! Every file starts with a header, followed by a constant pool
+//! and a table of function bodies. All integers are little endian.
+//!
+//! ```text
+//! header:    b"XBC\0" magic, u16 version
+//! constants: u32 count, then for each constant
+//!              0x00, u32 length, UTF-8 bytes   (string)
+//!              0x01, f64 bits                  (number)
+//!              0x02, i64                       (int)
+//! functions: u32 count, then for each function
+//!              u32 length, then each instruction as an
+//!              opcode byte, followed by a u32 operand for
+//!              the opcodes that push a constant or function
+//! ```
+//!
+//! Function 0 is the program itself. Every other function is
+//! the body of a `fn { ... }` literal, and is pushed by exactly
+//! one instruction in a function that comes before it in the table.
+//! Like in xasm, functions can't be nested deeper than `MAX_NESTING`.
+//!
+//! A `fn [...] { ... }` closure is pushed by its own opcode, with
+//! the function as its operand, followed by a u32 count of the
+//! registers it captures and the u32 index of each of their names
+//! in the constant pool.
+use crate::{Instruction, Literal, MAX_NESTING};
+
+// For the constant pool
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+// For implementing Display
+use core::fmt::{Display, Error, Formatter};
+
+/// The bytes that every `.xbc` file starts with
+pub const MAGIC: &[u8; 4] = b"XBC\0";
+/// The version of the format written by `encode`. Each version only
+/// adds constant tags and opcodes to the versions before it, so every
+/// version up to this one can be decoded.
+///
+/// Version 1 had string and number constants, and the opcodes up to
+/// `load` (0x0e). Version 2 added the rest.
+pub const VERSION: u16 = 2;
+
+// Tags for the entries in the constant pool
+const STRING: u8 = 0x00;
+const NUMBER: u8 = 0x01;
+const INT: u8 = 0x02;
+
+// Opcodes for each instruction
+const PUSH_CONSTANT: u8 = 0x00;
+const PUSH_FUNCTION: u8 = 0x01;
+const PUSH_LIST: u8 = 0x02;
+const PUSH_TREE: u8 = 0x03;
+const PUSH_NONE: u8 = 0x04;
+const COPY: u8 = 0x05;
+const ASSIGN: u8 = 0x06;
+const INDEX: u8 = 0x07;
+const METHOD_CALL: u8 = 0x08;
+const CALL: u8 = 0x09;
+const FOR_LOOP: u8 = 0x0a;
+const WHILE_LOOP: u8 = 0x0b;
+const IF_THEN_ELSE: u8 = 0x0c;
+const STORE: u8 = 0x0d;
+const LOAD: u8 = 0x0e;
+const THROW: u8 = 0x0f;
+const TRY_CATCH: u8 = 0x10;
+const ADD: u8 = 0x11;
+const SUB: u8 = 0x12;
+const MUL: u8 = 0x13;
+const DIV: u8 = 0x14;
+const REM: u8 = 0x15;
+const NEG: u8 = 0x16;
+const NOT: u8 = 0x17;
+const EQ: u8 = 0x18;
+const NE: u8 = 0x19;
+const LT: u8 = 0x1a;
+const LE: u8 = 0x1b;
+const GT: u8 = 0x1c;
+const GE: u8 = 0x1d;
+const AND: u8 = 0x1e;
+const OR: u8 = 0x1f;
+const PUSH_TRUE: u8 = 0x20;
+const PUSH_FALSE: u8 = 0x21;
+const SLICE: u8 = 0x22;
+const LEN: u8 = 0x23;
+const RANGE: u8 = 0x24;
+const BREAK: u8 = 0x25;
+const CONTINUE: u8 = 0x26;
+const RETURN: u8 = 0x27;
+const DECLARE: u8 = 0x28;
+const PUSH_SCOPE: u8 = 0x29;
+const POP_SCOPE: u8 = 0x2a;
+const SHARE: u8 = 0x2b;
+const PUSH_CLOSURE: u8 = 0x2c;
+const DOWNGRADE: u8 = 0x2d;
+const UPGRADE: u8 = 0x2e;
+const COROUTINE: u8 = 0x2f;
+const RESUME: u8 = 0x30;
+const YIELD: u8 = 0x31;
+const TAIL_CALL: u8 = 0x32;
+
+/// The reasons that bytes can fail to decode into a program
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The input does not start with `MAGIC`
+    BadMagic,
+    /// The input was written with a version of the format we can't read
+    UnsupportedVersion(u16),
+    /// The input ended in the middle of the program
+    Truncated,
+    /// There are bytes left over after the end of the program
+    TrailingBytes,
+    /// A constant in the pool has an unknown tag or invalid contents
+    InvalidConstant { offset: usize },
+    /// An instruction has an unknown opcode
+    InvalidOpcode { opcode: u8, offset: usize },
+    /// An instruction refers to a constant that doesn't exist
+    InvalidConstantIndex(u32),
+    /// An instruction refers to a function that doesn't exist,
+    /// that doesn't come after the function referring to it,
+    /// or that was already pushed by another instruction
+    InvalidFunctionIndex(u32),
+    /// Functions are nested deeper than `MAX_NESTING`
+    NestedTooDeeply,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Self::BadMagic => write!(f, "Not an xmachine bytecode file"),
+            Self::UnsupportedVersion(v) => write!(f, "Unsupported bytecode version {}", v),
+            Self::Truncated => write!(f, "Bytecode ended unexpectedly"),
+            Self::TrailingBytes => write!(f, "Unexpected bytes after the end of the bytecode"),
+            Self::InvalidConstant { offset } => write!(f, "Invalid constant at byte {}", offset),
+            Self::InvalidOpcode { opcode, offset } => {
+                write!(f, "Invalid opcode {:#04x} at byte {}", opcode, offset)
+            }
+            Self::InvalidConstantIndex(i) => write!(f, "No constant with index {}", i),
+            Self::InvalidFunctionIndex(i) => write!(f, "Invalid function index {}", i),
+            Self::NestedTooDeeply => {
+                write!(f, "Functions are nested deeper than {} levels", MAX_NESTING)
+            }
+        }
+    }
+}
+
+/// Encode a program into bytes
+pub fn encode(program: &[Instruction]) -> Vec<u8> {
+    let mut encoder = Encoder::default();
+    encoder.function(program);
+
+    let mut result = Vec::new();
+    result.extend_from_slice(MAGIC);
+    result.extend_from_slice(&VERSION.to_le_bytes());
+
+    write_u32(&mut result, encoder.constants.len());
+    for constant in encoder.constants {
+        match constant {
+            Literal::String(s) => {
+                result.push(STRING);
+                write_u32(&mut result, s.len());
+                result.extend_from_slice(s.as_bytes());
+            }
+            Literal::Number(n) => {
+                result.push(NUMBER);
+                result.extend_from_slice(&n.to_bits().to_le_bytes());
+            }
+            Literal::Int(n) => {
+                result.push(INT);
+                result.extend_from_slice(&n.to_le_bytes());
+            }
+            _ => unreachable!("Only strings and numbers are constants"),
+        }
+    }
+
+    write_u32(&mut result, encoder.functions.len());
+    for (length, code) in encoder.functions {
+        write_u32(&mut result, length);
+        result.extend_from_slice(&code);
+    }
+
+    result
+}
+
+/// Decode bytes into a program, validating them along the way
+pub fn decode(bytes: &[u8]) -> Result<Vec<Instruction>, DecodeError> {
+    let mut reader = Reader { bytes, offset: 0 };
+
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(DecodeError::BadMagic);
+    }
+    reader.offset = MAGIC.len();
+    let version = u16::from_le_bytes([reader.u8()?, reader.u8()?]);
+    if version == 0 || version > VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    // Read the constant pool
+    let mut constants = Vec::new();
+    for _ in 0..reader.u32()? {
+        let offset = reader.offset;
+        constants.push(match reader.u8()? {
+            STRING => {
+                let length = reader.u32()? as usize;
+                match String::from_utf8(reader.take(length)?.to_vec()) {
+                    Ok(s) => Literal::String(s),
+                    Err(_) => return Err(DecodeError::InvalidConstant { offset }),
+                }
+            }
+            NUMBER => {
+                let mut bits = [0; 8];
+                bits.copy_from_slice(reader.take(8)?);
+                Literal::Number(f64::from_bits(u64::from_le_bytes(bits)))
+            }
+            INT if version >= 2 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(reader.take(8)?);
+                Literal::Int(i64::from_le_bytes(bytes))
+            }
+            _ => return Err(DecodeError::InvalidConstant { offset }),
+        });
+    }
+
+    // Read the function table, without resolving the
+    // functions that each function pushes yet
+    let mut functions = Vec::new();
+    let count = reader.u32()?;
+    for index in 0..count {
+        let mut code = Vec::new();
+        for _ in 0..reader.u32()? {
+            let offset = reader.offset;
+            let opcode = reader.u8()?;
+            if version < 2 && opcode > LOAD {
+                return Err(DecodeError::InvalidOpcode { opcode, offset });
+            }
+            code.push(match opcode {
+                PUSH_CONSTANT => {
+                    let i = reader.u32()?;
+                    match constants.get(i as usize) {
+                        Some(constant) => Raw::Instruction(Instruction::Push(constant.clone())),
+                        None => return Err(DecodeError::InvalidConstantIndex(i)),
+                    }
+                }
+                PUSH_FUNCTION => {
+                    // Only allowing references to later functions
+                    // guarantees that functions can't contain themselves
+                    let i = reader.u32()?;
+                    if i <= index || i >= count {
+                        return Err(DecodeError::InvalidFunctionIndex(i));
+                    }
+                    Raw::Function(i as usize)
+                }
+                PUSH_CLOSURE => {
+                    let i = reader.u32()?;
+                    if i <= index || i >= count {
+                        return Err(DecodeError::InvalidFunctionIndex(i));
+                    }
+                    let mut captures = Vec::new();
+                    for _ in 0..reader.u32()? {
+                        let name = reader.u32()?;
+                        match constants.get(name as usize) {
+                            Some(Literal::String(s)) => captures.push(s.clone()),
+                            _ => return Err(DecodeError::InvalidConstantIndex(name)),
+                        }
+                    }
+                    Raw::Closure(captures, i as usize)
+                }
+                PUSH_LIST => Raw::Instruction(Instruction::Push(Literal::List)),
+                PUSH_TREE => Raw::Instruction(Instruction::Push(Literal::Tree)),
+                PUSH_NONE => Raw::Instruction(Instruction::Push(Literal::None)),
+                PUSH_TRUE => Raw::Instruction(Instruction::Push(Literal::Bool(true))),
+                PUSH_FALSE => Raw::Instruction(Instruction::Push(Literal::Bool(false))),
+                COPY => Raw::Instruction(Instruction::Copy),
+                ASSIGN => Raw::Instruction(Instruction::Assign),
+                INDEX => Raw::Instruction(Instruction::Index),
+                SLICE => Raw::Instruction(Instruction::Slice),
+                LEN => Raw::Instruction(Instruction::Len),
+                RANGE => Raw::Instruction(Instruction::Range),
+                BREAK => Raw::Instruction(Instruction::Break),
+                CONTINUE => Raw::Instruction(Instruction::Continue),
+                RETURN => Raw::Instruction(Instruction::Return),
+                DECLARE => Raw::Instruction(Instruction::Declare),
+                PUSH_SCOPE => Raw::Instruction(Instruction::PushScope),
+                POP_SCOPE => Raw::Instruction(Instruction::PopScope),
+                SHARE => Raw::Instruction(Instruction::Share),
+                DOWNGRADE => Raw::Instruction(Instruction::Downgrade),
+                UPGRADE => Raw::Instruction(Instruction::Upgrade),
+                COROUTINE => Raw::Instruction(Instruction::Coroutine),
+                RESUME => Raw::Instruction(Instruction::Resume),
+                YIELD => Raw::Instruction(Instruction::Yield),
+                TAIL_CALL => Raw::Instruction(Instruction::TailCall),
+                METHOD_CALL => Raw::Instruction(Instruction::MethodCall),
+                CALL => Raw::Instruction(Instruction::Call),
+                FOR_LOOP => Raw::Instruction(Instruction::ForLoop),
+                WHILE_LOOP => Raw::Instruction(Instruction::WhileLoop),
+                IF_THEN_ELSE => Raw::Instruction(Instruction::IfThenElse),
+                STORE => Raw::Instruction(Instruction::Store),
+                LOAD => Raw::Instruction(Instruction::Load),
+                THROW => Raw::Instruction(Instruction::Throw),
+                TRY_CATCH => Raw::Instruction(Instruction::TryCatch),
+                ADD => Raw::Instruction(Instruction::Add),
+                SUB => Raw::Instruction(Instruction::Sub),
+                MUL => Raw::Instruction(Instruction::Mul),
+                DIV => Raw::Instruction(Instruction::Div),
+                REM => Raw::Instruction(Instruction::Rem),
+                NEG => Raw::Instruction(Instruction::Neg),
+                NOT => Raw::Instruction(Instruction::Not),
+                EQ => Raw::Instruction(Instruction::Eq),
+                NE => Raw::Instruction(Instruction::Ne),
+                LT => Raw::Instruction(Instruction::Lt),
+                LE => Raw::Instruction(Instruction::Le),
+                GT => Raw::Instruction(Instruction::Gt),
+                GE => Raw::Instruction(Instruction::Ge),
+                AND => Raw::Instruction(Instruction::And),
+                OR => Raw::Instruction(Instruction::Or),
+                opcode => return Err(DecodeError::InvalidOpcode { opcode, offset }),
+            });
+        }
+        functions.push(code);
+    }
+
+    if reader.offset != bytes.len() {
+        return Err(DecodeError::TrailingBytes);
+    }
+
+    // Functions only push functions that come after them,
+    // so building them from last to first means every body
+    // is ready before it's needed, without recursing. The
+    // finished program is cloned, compared and dropped
+    // recursively though, so its nesting is limited too.
+    let mut bodies: Vec<Option<Vec<Instruction>>> = Vec::new();
+    bodies.resize(functions.len(), None);
+    // How deeply functions are nested inside of each body
+    let mut depths = vec![0; functions.len()];
+    for (index, code) in functions.into_iter().enumerate().rev() {
+        let mut body = Vec::new();
+        for raw in code {
+            if let Raw::Function(i) | Raw::Closure(_, i) = raw {
+                depths[index] = depths[index].max(depths[i] + 1);
+                if depths[index] > MAX_NESTING {
+                    return Err(DecodeError::NestedTooDeeply);
+                }
+            }
+            body.push(match raw {
+                Raw::Instruction(instruction) => instruction,
+                // Each body is moved into the only function that pushes it,
+                // so a function that is pushed twice is rejected
+                Raw::Function(i) => match bodies[i].take() {
+                    Some(body) => Instruction::Push(Literal::Function(body)),
+                    None => return Err(DecodeError::InvalidFunctionIndex(i as u32)),
+                },
+                Raw::Closure(captures, i) => match bodies[i].take() {
+                    Some(body) => Instruction::Push(Literal::Closure(Box::new((captures, body)))),
+                    None => return Err(DecodeError::InvalidFunctionIndex(i as u32)),
+                },
+            });
+        }
+        bodies[index] = Some(body);
+    }
+
+    match bodies.into_iter().next() {
+        Some(Some(program)) => Ok(program),
+        _ => Err(DecodeError::InvalidFunctionIndex(0)),
+    }
+}
+
+/// An instruction whose function literal has not been resolved yet
+enum Raw {
+    Instruction(Instruction),
+    Function(usize),
+    Closure(Vec<String>, usize),
+}
+
+/// Write a length or index as a u32
+fn write_u32(bytes: &mut Vec<u8>, n: usize) {
+    bytes.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+/// Collects the constants and functions of a program while encoding it
+#[derive(Default)]
+struct Encoder {
+    constants: Vec<Literal>,
+    /// Maps strings to their index in the constant pool
+    strings: BTreeMap<String, usize>,
+    /// Maps the bits of numbers to their index in the constant pool
+    numbers: BTreeMap<u64, usize>,
+    /// Maps ints to their index in the constant pool
+    ints: BTreeMap<i64, usize>,
+    /// The number of instructions and the encoded instructions of each function
+    functions: Vec<(usize, Vec<u8>)>,
+}
+
+impl Encoder {
+    /// Get the index of a constant, adding it to the pool if needed
+    fn constant(&mut self, literal: &Literal) -> usize {
+        let constants = &mut self.constants;
+        let index = constants.len();
+        match literal {
+            Literal::String(s) => *self.strings.entry(s.clone()).or_insert_with(|| {
+                constants.push(literal.clone());
+                index
+            }),
+            Literal::Number(n) => *self.numbers.entry(n.to_bits()).or_insert_with(|| {
+                constants.push(literal.clone());
+                index
+            }),
+            Literal::Int(n) => *self.ints.entry(*n).or_insert_with(|| {
+                constants.push(literal.clone());
+                index
+            }),
+            _ => unreachable!("Only strings and numbers are constants"),
+        }
+    }
+
+    /// Encode a function body, and return its index in the function table
+    fn function(&mut self, body: &[Instruction]) -> usize {
+        // Reserve the index before encoding the body, so that
+        // the functions it pushes always come after it
+        let index = self.functions.len();
+        self.functions.push((body.len(), Vec::new()));
+
+        let mut code = Vec::new();
+        for instruction in body {
+            match instruction {
+                Instruction::Push(literal) => match literal {
+                    Literal::String(_) | Literal::Number(_) | Literal::Int(_) => {
+                        code.push(PUSH_CONSTANT);
+                        write_u32(&mut code, self.constant(literal));
+                    }
+                    Literal::Function(body) => {
+                        code.push(PUSH_FUNCTION);
+                        write_u32(&mut code, self.function(body));
+                    }
+                    Literal::Closure(closure) => {
+                        let (captures, body) = &**closure;
+                        code.push(PUSH_CLOSURE);
+                        write_u32(&mut code, self.function(body));
+                        write_u32(&mut code, captures.len());
+                        for name in captures {
+                            let name = self.constant(&Literal::String(name.clone()));
+                            write_u32(&mut code, name);
+                        }
+                    }
+                    Literal::List => code.push(PUSH_LIST),
+                    Literal::Tree => code.push(PUSH_TREE),
+                    Literal::None => code.push(PUSH_NONE),
+                    Literal::Bool(true) => code.push(PUSH_TRUE),
+                    Literal::Bool(false) => code.push(PUSH_FALSE),
+                },
+                Instruction::Copy => code.push(COPY),
+                Instruction::Assign => code.push(ASSIGN),
+                Instruction::Index => code.push(INDEX),
+                Instruction::Slice => code.push(SLICE),
+                Instruction::Len => code.push(LEN),
+                Instruction::Range => code.push(RANGE),
+                Instruction::Break => code.push(BREAK),
+                Instruction::Continue => code.push(CONTINUE),
+                Instruction::Return => code.push(RETURN),
+                Instruction::Declare => code.push(DECLARE),
+                Instruction::PushScope => code.push(PUSH_SCOPE),
+                Instruction::PopScope => code.push(POP_SCOPE),
+                Instruction::Share => code.push(SHARE),
+                Instruction::Downgrade => code.push(DOWNGRADE),
+                Instruction::Upgrade => code.push(UPGRADE),
+                Instruction::Coroutine => code.push(COROUTINE),
+                Instruction::Resume => code.push(RESUME),
+                Instruction::Yield => code.push(YIELD),
+                Instruction::TailCall => code.push(TAIL_CALL),
+                Instruction::MethodCall => code.push(METHOD_CALL),
+                Instruction::Call => code.push(CALL),
+                Instruction::ForLoop => code.push(FOR_LOOP),
+                Instruction::WhileLoop => code.push(WHILE_LOOP),
+                Instruction::IfThenElse => code.push(IF_THEN_ELSE),
+                Instruction::Store => code.push(STORE),
+                Instruction::Load => code.push(LOAD),
+                Instruction::Throw => code.push(THROW),
+                Instruction::TryCatch => code.push(TRY_CATCH),
+                Instruction::Add => code.push(ADD),
+                Instruction::Sub => code.push(SUB),
+                Instruction::Mul => code.push(MUL),
+                Instruction::Div => code.push(DIV),
+                Instruction::Rem => code.push(REM),
+                Instruction::Neg => code.push(NEG),
+                Instruction::Not => code.push(NOT),
+                Instruction::Eq => code.push(EQ),
+                Instruction::Ne => code.push(NE),
+                Instruction::Lt => code.push(LT),
+                Instruction::Le => code.push(LE),
+                Instruction::Gt => code.push(GT),
+                Instruction::Ge => code.push(GE),
+                Instruction::And => code.push(AND),
+                Instruction::Or => code.push(OR),
+            }
+        }
+
+        self.functions[index].1 = code;
+        index
+    }
+}
+
+/// Reads values from the input, failing if it runs out of bytes
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() - self.offset < n {
+            return Err(DecodeError::Truncated);
+        }
+        let result = &self.bytes[self.offset..self.offset + n];
+        self.offset += n;
+        Ok(result)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+}
@@ -0,0 +1,186 @@
+use crate::task::Call;
+use crate::value::Pairs;
+use crate::{Body, ErrorKind, Frame, Function, MachineError, Ref, Value};
+
+// Function bodies are shared with the functions they come from
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+// For implementing Display and PartialOrd
+use core::cmp::Ordering;
+use core::fmt::{Display, Error, Formatter};
+use core::mem;
+
+/// Whether a coroutine can be resumed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    /// Waiting to be resumed, for the first time or after a `yield`
+    Suspended,
+    /// Being run by `Machine::resume`
+    Running,
+    /// Returned, or raised an error, so it can't be resumed again
+    Finished,
+}
+
+/// How to display a Status
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Self::Suspended => write!(f, "suspended"),
+            Self::Running => write!(f, "running"),
+            Self::Finished => write!(f, "finished"),
+        }
+    }
+}
+
+/// A function that can suspend itself with `yield`, and carry on
+/// where it left off when it is resumed, with its own stack and
+/// registers. Coroutines are made by `Machine::coroutine`.
+///
+/// A coroutine keeps the function calls it is running, which are
+/// run the same way the Machine runs every program, so it can stop
+/// between any two instructions and carry on from there later.
+/// The functions it calls can `yield` too, but native functions
+/// run to completion, so the functions they call can't.
+///
+/// Cloning a coroutine, like `copy`, makes a second coroutine
+/// that carries on independently from the same place.
+#[derive(Clone)]
+pub struct Coroutine {
+    status: Status,
+    /// The function calls being run, with the function the coroutine
+    /// was made from first. The coroutine's stack is in the machine
+    /// of the last call.
+    calls: Vec<Call>,
+    /// The call frames of the blocks being run, while suspended
+    frames: Vec<Frame>,
+}
+
+impl Coroutine {
+    /// Make a coroutine that runs a function. The function has to be
+    /// made from instructions, since native functions can't suspend.
+    pub fn new(function: &Function) -> Result<Self, MachineError> {
+        let code = match function.get_body() {
+            Body::Code(code) => Rc::clone(code),
+            Body::Native(_) => {
+                return Err(MachineError::new(
+                    ErrorKind::NotCallable,
+                    format!("Can't make a coroutine from native function {}", function),
+                ))
+            }
+        };
+        Ok(Self {
+            status: Status::Suspended,
+            frames: vec![Frame {
+                function: function.frame_name(),
+                offset: None,
+            }],
+            calls: vec![Call::new(function.get_context().clone(), code)],
+        })
+    }
+
+    /// Return whether the coroutine can be resumed
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    /// Compare two coroutines, see `Value::eq_in`
+    pub(crate) fn eq_in(&self, other: &Self, pairs: &mut Pairs) -> bool {
+        self.status == other.status
+            && self.frames == other.frames
+            && self.calls.len() == other.calls.len()
+            && (self.calls.iter())
+                .zip(&other.calls)
+                .all(|(a, b)| a.eq_in(b, pairs))
+    }
+
+    /// Visit each Ref that the machines of the coroutine hold.
+    /// The Refs held by the loops it is running aren't visited,
+    /// so the collector treats them as if they were on the stack.
+    pub(crate) fn for_each_ref(&self, visit: &mut impl FnMut(&Ref<Value>)) {
+        for call in &self.calls {
+            call.machine.for_each_ref(visit);
+        }
+    }
+
+    /// Take the function calls and call frames of the suspended
+    /// coroutine in a Ref, to resume it. A running placeholder is
+    /// left in the Ref, so it can't be resumed again from inside
+    /// of itself.
+    pub(crate) fn take(coroutine: &Ref<Value>) -> Result<(Vec<Call>, Vec<Frame>), MachineError> {
+        // Other values are printed after the borrow ends,
+        // since they may contain themselves
+        if !matches!(*coroutine.borrow(), Value::Coroutine(_)) {
+            return Err(MachineError::new(
+                ErrorKind::NotCallable,
+                format!("Can't resume non-coroutine {}", coroutine),
+            ));
+        }
+        match &mut *coroutine.borrow_mut() {
+            Value::Coroutine(c) if c.status == Status::Suspended => {
+                let running = mem::replace(c, Self::with_status(Status::Running));
+                Ok((running.calls, running.frames))
+            }
+            Value::Coroutine(c) => Err(MachineError::new(
+                ErrorKind::ControlFlow,
+                format!("Can't resume a {} coroutine", c.status),
+            )),
+            _ => unreachable!("The value was checked to be a coroutine"),
+        }
+    }
+
+    /// Put the function calls and call frames of a coroutine
+    /// that yielded back in its Ref, to be resumed again
+    pub(crate) fn suspend(coroutine: &Ref<Value>, calls: Vec<Call>, frames: Vec<Frame>) {
+        Self::put_back(
+            coroutine,
+            Self {
+                status: Status::Suspended,
+                calls,
+                frames,
+            },
+        )
+    }
+
+    /// Mark a coroutine that returned, or raised an error, as finished
+    pub(crate) fn finish(coroutine: &Ref<Value>) {
+        Self::put_back(coroutine, Self::with_status(Status::Finished))
+    }
+
+    /// Replace the running placeholder in a coroutine's Ref,
+    /// unless the Ref was assigned to while it was running
+    fn put_back(coroutine: &Ref<Value>, new: Self) {
+        if let Value::Coroutine(c) = &mut *coroutine.borrow_mut() {
+            if c.status == Status::Running {
+                *c = new;
+            }
+        }
+    }
+
+    /// A coroutine that isn't running any calls
+    fn with_status(status: Status) -> Self {
+        Self {
+            status,
+            calls: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+}
+
+/// Coroutines are equal if they have the same status, and
+/// are running the same calls with the same values
+impl PartialEq for Coroutine {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_in(other, &mut Pairs::new())
+    }
+}
+
+/// Coroutines are ordered by their status, and
+/// otherwise can only be compared for equality
+impl PartialOrd for Coroutine {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.status.cmp(&other.status) {
+            Ordering::Equal if self != other => None,
+            ordering => Some(ordering),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{Frame, Instruction, Ref, Value};
+
+// For the error messages
+use alloc::string::{String, ToString};
+// For the backtrace
+use alloc::boxed::Box;
+// For implementing Display
+use core::fmt::{Display, Error, Formatter};
+
+/// The kinds of errors that Machine instructions can raise
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorKind {
+    /// Popped from an empty stack
+    StackUnderflow,
+    /// Loaded a register that doesn't exist
+    UndefinedRegister,
+    /// Indexed a value that can't be indexed, or with an invalid index
+    InvalidIndex,
+    /// Called a value that isn't a function
+    NotCallable,
+    /// An operator was used on values it doesn't support
+    Arithmetic,
+    /// A `break`, `continue` or `return` was used outside
+    /// of the loop or function that it should exit, or a
+    /// coroutine couldn't be resumed or suspended
+    ControlFlow,
+    /// The fuel given to the Machine ran out, see `Machine::set_fuel`.
+    /// This stops the program, so `try_catch` can't catch it,
+    /// and FINALLY doesn't run.
+    OutOfFuel,
+    /// A program went over one of the memory limits given
+    /// to the Machine, see `Machine::set_stack_limit`
+    MemoryLimit,
+    /// Calls were nested deeper than the Machine
+    /// allows, see `Machine::set_depth_limit`
+    RecursionLimit,
+    /// Any other kind of error, named by the program that raised it
+    Custom(String),
+    /// Signals the innermost loop to stop. This isn't an error,
+    /// and can't be caught by `try_catch`.
+    Break,
+    /// Signals the innermost loop to skip to its next iteration
+    Continue,
+    /// Signals the innermost function call to return its payload
+    Return,
+    /// Signals the innermost function call to end, and to call its
+    /// payload in its place if it has one, see `Machine::tail_call`
+    TailCall,
+    /// Signals the running coroutine to suspend, and give its
+    /// payload to the machine that resumed it
+    Yield,
+}
+
+impl ErrorKind {
+    /// Returns true for the kinds that signal control flow,
+    /// rather than errors: Break, Continue, Return, TailCall and Yield
+    pub fn is_signal(&self) -> bool {
+        matches!(
+            self,
+            Self::Break | Self::Continue | Self::Return | Self::TailCall | Self::Yield
+        )
+    }
+
+    /// Returns true for the kinds that `try_catch` can catch,
+    /// which are every error except OutOfFuel
+    pub fn is_catchable(&self) -> bool {
+        !self.is_signal() && *self != Self::OutOfFuel
+    }
+}
+
+/// How to display an ErrorKind
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Self::StackUnderflow => write!(f, "StackUnderflow"),
+            Self::UndefinedRegister => write!(f, "UndefinedRegister"),
+            Self::InvalidIndex => write!(f, "InvalidIndex"),
+            Self::NotCallable => write!(f, "NotCallable"),
+            Self::Arithmetic => write!(f, "Arithmetic"),
+            Self::ControlFlow => write!(f, "ControlFlow"),
+            Self::OutOfFuel => write!(f, "OutOfFuel"),
+            Self::MemoryLimit => write!(f, "MemoryLimit"),
+            Self::RecursionLimit => write!(f, "RecursionLimit"),
+            Self::Custom(kind) => write!(f, "{}", kind),
+            Self::Break => write!(f, "Break"),
+            Self::Continue => write!(f, "Continue"),
+            Self::Return => write!(f, "Return"),
+            Self::TailCall => write!(f, "TailCall"),
+            Self::Yield => write!(f, "Yield"),
+        }
+    }
+}
+
+/// Get an ErrorKind from its name
+impl From<&str> for ErrorKind {
+    fn from(kind: &str) -> Self {
+        match kind {
+            "StackUnderflow" => Self::StackUnderflow,
+            "UndefinedRegister" => Self::UndefinedRegister,
+            "InvalidIndex" => Self::InvalidIndex,
+            "NotCallable" => Self::NotCallable,
+            "Arithmetic" => Self::Arithmetic,
+            "ControlFlow" => Self::ControlFlow,
+            "MemoryLimit" => Self::MemoryLimit,
+            "RecursionLimit" => Self::RecursionLimit,
+            // Programs can't throw the signals or OutOfFuel,
+            // so they're custom kinds here
+            other => Self::Custom(other.to_string()),
+        }
+    }
+}
+
+/// The contents of an Error value. Programs can branch
+/// on the kind of an exception by indexing it with "kind",
+/// and get its message and payload the same way.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct Exception {
+    /// What kind of error this is
+    pub kind: ErrorKind,
+    /// A description of what went wrong
+    pub message: String,
+    /// A value thrown along with the error
+    pub payload: Option<Ref<Value>>,
+}
+
+impl Exception {
+    /// Create an exception without a payload
+    pub fn new<S: ToString>(kind: ErrorKind, message: S) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+            payload: None,
+        }
+    }
+}
+
+/// The most call frames an error's trace keeps. Half are taken from
+/// the outermost end of the call stack and half from the innermost.
+pub const TRACE_LIMIT: usize = 64;
+
+/// An error raised by a Machine instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct MachineError {
+    /// What kind of error this is
+    pub kind: ErrorKind,
+    /// A description of what went wrong
+    pub message: String,
+    /// A value thrown along with the error
+    pub payload: Option<Ref<Value>>,
+    /// The offset of the instruction that raised the error,
+    /// in the block of instructions it was run from
+    pub offset: Option<usize>,
+    /// The instruction that raised the error, if the error
+    /// was raised while running a program. It's boxed so that
+    /// the `Result`s of every instruction stay small.
+    pub instruction: Option<Box<Instruction>>,
+    /// The call frames of the Machine when the error was raised,
+    /// with the outermost frame first. Only the outermost and
+    /// innermost frames are kept, up to `TRACE_LIMIT` of them.
+    pub trace: Box<[Frame]>,
+    /// The number of frames left out of the middle of the trace
+    pub omitted: usize,
+}
+
+impl MachineError {
+    /// Create an error that hasn't been attributed to an instruction yet
+    pub fn new<S: ToString>(kind: ErrorKind, message: S) -> Self {
+        Exception::new(kind, message).into()
+    }
+
+    /// Attribute this error to an instruction, unless
+    /// an instruction inside a nested call already raised it
+    pub fn at(mut self, offset: usize, instruction: &Instruction) -> Self {
+        if self.instruction.is_none() {
+            self.offset = Some(offset);
+            self.instruction = Some(Box::new(instruction.clone()));
+        }
+        self
+    }
+
+    /// Create a signal for a `break`, `continue`, `return`,
+    /// `tail_call` or `yield`.
+    /// The message is used if the signal escapes.
+    pub(crate) fn signal(kind: ErrorKind, payload: Option<Ref<Value>>) -> Self {
+        let message = match kind {
+            ErrorKind::Break => "`break` outside of a loop",
+            ErrorKind::Continue => "`continue` outside of a loop",
+            ErrorKind::Yield => "`yield` outside of a coroutine",
+            ErrorKind::TailCall => "`tail_call` outside of a function",
+            _ => "`return` outside of a function",
+        };
+        Self {
+            payload,
+            ..Self::new(kind, message)
+        }
+    }
+
+    /// Turn a signal that escaped the loop or function it
+    /// should exit into a ControlFlow error that can be caught
+    pub(crate) fn escaped(self) -> Self {
+        if self.kind.is_signal() {
+            Self {
+                kind: ErrorKind::ControlFlow,
+                payload: None,
+                ..self
+            }
+        } else {
+            self
+        }
+    }
+
+    /// Attach a snapshot of the call frames to this error,
+    /// unless a snapshot from a deeper frame is already attached.
+    /// Signals never get a snapshot, since they aren't errors.
+    pub fn with_trace(mut self, frames: &[Frame]) -> Self {
+        if self.trace.is_empty() && !self.kind.is_signal() {
+            if frames.len() > TRACE_LIMIT {
+                // Keep half of the frames from each end
+                let end = TRACE_LIMIT / 2;
+                let start = frames.len() - (TRACE_LIMIT - end);
+                self.trace = frames[..end]
+                    .iter()
+                    .chain(&frames[start..])
+                    .cloned()
+                    .collect();
+                self.omitted = start - end;
+            } else {
+                self.trace = frames.into();
+            }
+        }
+        self
+    }
+}
+
+/// How to display a MachineError
+impl Display for MachineError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{}: {}", self.kind, self.message)?;
+        if let (Some(offset), Some(instruction)) = (self.offset, &self.instruction) {
+            write!(f, " (at instruction {}, `{}`)", offset, instruction)?;
+        }
+        // Show the most recent call first
+        let outer = self.trace.len().min(TRACE_LIMIT / 2);
+        for frame in self.trace[outer..].iter().rev() {
+            write!(f, "\n    in {}", frame)?;
+        }
+        if self.omitted > 0 {
+            write!(f, "\n    ... {} frames omitted", self.omitted)?;
+        }
+        for frame in self.trace[..outer].iter().rev() {
+            write!(f, "\n    in {}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+/// Raise an exception as an error
+impl From<Exception> for MachineError {
+    fn from(e: Exception) -> Self {
+        Self {
+            kind: e.kind,
+            message: e.message,
+            payload: e.payload,
+            offset: None,
+            instruction: None,
+            trace: Box::new([]),
+            omitted: 0,
+        }
+    }
+}
+
+/// Catch an error as an exception
+impl From<MachineError> for Exception {
+    fn from(e: MachineError) -> Self {
+        Self {
+            kind: e.kind,
+            message: e.message,
+            payload: e.payload,
+        }
+    }
+}
+
+/// Errors are pushed onto the stack as Error values
+/// by the instructions that don't return a Result
+impl From<MachineError> for Value {
+    fn from(e: MachineError) -> Self {
+        Value::Error(e.into())
+    }
+}
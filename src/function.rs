@@ -1,79 +1,193 @@
-use crate::Ref;
+use crate::value::Pairs;
+use crate::{xasm, Instruction, Machine, MachineError, Ref, Value};
+use core::cmp::Ordering;
 use core::fmt::{Display, Error, Formatter};
 
-use alloc::string::ToString;
+// Function bodies are shared between every copy of a Function
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
-/// Represents a function that takes a &mut I, returns O,
-/// and contains a captured context C.
+/// The body of a function. This is either a native Rust closure,
+/// or a block of instructions that is run by the Machine.
 #[derive(Clone)]
-pub struct Function<I, O, C> {
-    /// Function pointer to call
-    function_ptr: Ref<dyn Fn(&mut I) -> O>,
+pub enum Body {
+    /// A Rust closure that operates directly on the Machine
+    Native(Rc<dyn Fn(&mut Machine)>),
+    /// A block of instructions to run on the Machine
+    Code(Rc<[Instruction]>),
+}
+
+/// Represents a function that takes a &mut Machine,
+/// and contains a captured context Machine.
+#[derive(Clone)]
+pub struct Function {
+    /// The body of the function to call
+    body: Body,
     /// The captured context of the function
-    context: C,
+    context: Machine,
+    /// The name of the function, used in backtraces
+    name: Option<String>,
 }
 
-/// Represents a function that takes a &mut I, returns O,
-/// and contains a captured context C.
-impl<I, O, C> Function<I, O, C> {
+impl Function {
     /// Create a function from a function pointer and captured context
     /// We use a function pointer because a non-capturing lambda can
     /// decay into a function pointer, and because it's sized!
-    pub fn new(function_ptr: impl 'static + Fn(&mut I) -> O, context: C) -> Self {
+    pub fn new(function_ptr: impl 'static + Fn(&mut Machine), context: Machine) -> Self {
         Self {
-            function_ptr: Ref::new(function_ptr),
+            body: Body::Native(Rc::new(function_ptr)),
             context,
+            name: None,
+        }
+    }
+
+    /// Create a function from a block of instructions and captured context
+    pub fn from_code(code: Vec<Instruction>, context: Machine) -> Self {
+        Self {
+            body: Body::Code(Rc::from(code)),
+            context,
+            name: None,
         }
     }
 
     /// Return the captured context of the Function
-    pub fn get_context(&self) -> &C {
+    pub fn get_context(&self) -> &Machine {
         &self.context
     }
 
-    /// Call this function with an input and return the output
-    pub fn call(&self, input: &mut I) -> O {
-        (self.function_ptr)(input)
+    /// Give the Function a register that is shared with another
+    /// machine, rather than its own copy. Storing to the register
+    /// in either machine assigns to the shared value.
+    pub fn share<S: ToString>(&mut self, name: S, cell: Ref<Value>) {
+        self.context
+            .registers
+            .insert(name.to_string(), Ref::clone(&cell));
+        self.context.add_cell(cell);
+    }
+
+    /// Return the name of the Function, if it has one
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Give the Function a name to use in backtraces
+    pub fn set_name<S: ToString>(&mut self, name: S) {
+        self.name = Some(name.to_string());
+    }
+
+    /// Return the name of the Function for a call frame,
+    /// which is its address if it doesn't have a name
+    pub(crate) fn frame_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("<fn at {:?}>", self.address()),
+        }
+    }
+
+    /// Return the body of the Function
+    pub fn get_body(&self) -> &Body {
+        &self.body
+    }
+
+    /// Return the instructions of the Function, if it is
+    /// not a native Rust closure
+    pub fn get_code(&self) -> Option<&[Instruction]> {
+        match &self.body {
+            Body::Native(_) => None,
+            Body::Code(code) => Some(code),
+        }
+    }
+
+    /// Call this function with an input
+    pub fn call(&self, input: &mut Machine) {
+        match &self.body {
+            Body::Native(function_ptr) => {
+                function_ptr(input);
+                // Errors raised by native functions are pushed when they return
+                if let Some(e) = input.take_raised() {
+                    input.push(Ref::new(e.into()));
+                }
+            }
+            Body::Code(code) => input.run(code),
+        }
+    }
+
+    /// Call this function with an input, stopping at the first
+    /// instruction that raises an error. Native functions fail
+    /// if they raised an error with `Machine::raise`.
+    pub fn try_call(&self, input: &mut Machine) -> Result<(), MachineError> {
+        match &self.body {
+            Body::Native(function_ptr) => {
+                function_ptr(input);
+                match input.take_raised() {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                }
+            }
+            Body::Code(code) => input.try_run_code(Rc::clone(code)),
+        }
+    }
+
+    /// Returns the address of the body of this function
+    fn address(&self) -> *const u8 {
+        match &self.body {
+            Body::Native(function_ptr) => Rc::as_ptr(function_ptr) as *const u8,
+            Body::Code(code) => Rc::as_ptr(code) as *const u8,
+        }
     }
 }
 
-impl<I, O, C> Display for Function<I, O, C> {
+/// Functions made from instructions are displayed as the xasm
+/// source of their body, on one line. The alternate form, `{:#}`,
+/// displays the full listing from `xasm::disassemble`.
+/// Native Rust closures can only be displayed by their address.
+impl Display for Function {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        let ptr = Ref::into_raw(self.function_ptr.clone()) as *const u8;
-        write!(f, "<fn at {}>", format!("{:?}", ptr)[..8].to_string())?;
-        unsafe {
-            Ref::from_raw(ptr);
+        match &self.body {
+            Body::Native(_) => write!(f, "<native fn at {:?}>", self.address()),
+            Body::Code(code) if f.alternate() => write!(f, "fn {{\n{}}}", xasm::disassemble(code)),
+            Body::Code(code) => xasm::write_function(f, code),
         }
-        Ok(())
     }
 }
 
 /// == operator for Function
-/// This doesn't compare the function pointer,
-/// but instead compares the contexts. If the contexts are the same,
-/// it's almost guaranteed that these two functions are the same.
-impl<I, O, C> PartialEq for Function<I, O, C> {
+/// Native functions are only equal if they are the same closure.
+/// Functions made from instructions are compared by their code,
+/// and by the contexts they captured.
+impl PartialEq for Function {
     fn eq(&self, rhs: &Self) -> bool {
-        format!("{}", self) == format!("{}", rhs)
+        self.eq_in(rhs, &mut Pairs::new())
     }
 }
 
 /// Ord operators for Function
 /// This doesn't compare the function pointer,
 /// but instead compares the contexts.
-impl<I, O, C: PartialOrd> PartialOrd for Function<I, O, C> {
-    fn partial_cmp(&self, rhs: &Self) -> Option<core::cmp::Ordering> {
-        self.context.partial_cmp(&rhs.context)
+impl PartialOrd for Function {
+    fn partial_cmp(&self, rhs: &Self) -> Option<Ordering> {
+        self.cmp_in(rhs, &mut Pairs::new())
+    }
+}
+
+impl Function {
+    /// Compare two functions, see `Value::eq_in`
+    pub(crate) fn eq_in(&self, rhs: &Self, pairs: &mut Pairs) -> bool {
+        match (&self.body, &rhs.body) {
+            (Body::Code(a), Body::Code(b)) => a == b && self.context.eq_in(&rhs.context, pairs),
+            _ => self.address() == rhs.address(),
+        }
+    }
+
+    /// Order two functions, see `Value::cmp_in`
+    pub(crate) fn cmp_in(&self, rhs: &Self, pairs: &mut Pairs) -> Option<Ordering> {
+        self.context.cmp_in(&rhs.context, pairs)
     }
 }
 
-impl<I, O, C> Default for Function<I, O, C>
-where
-    I: Default,
-    O: Default,
-    C: Default,
-{
+impl Default for Function {
     fn default() -> Self {
-        Self::new(|_: &mut I| Default::default(), Default::default())
+        Self::new(|_: &mut Machine| {}, Machine::default())
     }
 }
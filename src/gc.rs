@@ -0,0 +1,213 @@
+use crate::reference::WeakRef;
+use crate::{Ref, Value};
+
+// For the values being tracked and collected
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::vec::Vec;
+// The Machine derives these, so the Collector has to implement them
+use core::cmp::Ordering;
+
+/// The number of new values tracked between automatic collections,
+/// unless it is changed with `Machine::set_gc_threshold`
+pub const DEFAULT_GC_THRESHOLD: usize = 10_000;
+
+/// Finds cycles of values that can't be reached anymore.
+///
+/// Refs are reference counted, so a value that refers to itself,
+/// like a tree storing itself as one of its members, is never freed
+/// by counting alone. The collector remembers the lists, trees,
+/// functions, errors and coroutines pushed onto the Machine or
+/// assigned to, without keeping them alive, and frees the cycles
+/// among them by trial deletion:
+///
+/// 1) Find every value reachable from the tracked values
+/// 2) Subtract the references that come from those values
+///    from the reference count of each of them
+/// 3) A value with references left over is referred to from
+///    outside of the values being collected, like the stack or
+///    a Rust variable, so it and everything it refers to is alive
+/// 4) Everything else is only referred to by garbage, so it
+///    is cleared, which breaks the cycles and frees them
+#[derive(Clone)]
+pub(crate) struct Collector {
+    /// The values that could be part of a cycle, by address
+    tracked: BTreeMap<usize, WeakRef<Value>>,
+    /// The number of values tracked since the last collection
+    allocations: usize,
+    /// The number of allocations that triggers a collection,
+    /// or None to only collect when asked to
+    threshold: Option<usize>,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self {
+            tracked: BTreeMap::new(),
+            allocations: 0,
+            threshold: Some(DEFAULT_GC_THRESHOLD),
+        }
+    }
+}
+
+/// The state of the collector isn't part of the state of the
+/// Machine, so two Machines are equal whatever they have tracked
+impl PartialEq for Collector {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl PartialOrd for Collector {
+    fn partial_cmp(&self, _: &Self) -> Option<Ordering> {
+        Some(Ordering::Equal)
+    }
+}
+
+/// A value found while collecting
+struct Node {
+    /// A Ref to the value, which adds one to its reference count
+    value: Ref<Value>,
+    /// The reference count that isn't explained by other nodes
+    external: usize,
+    /// The addresses of the values this value refers to
+    children: Vec<usize>,
+    /// False if the value was borrowed, so its children are unknown
+    visited: bool,
+}
+
+impl Collector {
+    /// Remember a value that could be part of a cycle
+    pub(crate) fn track(&mut self, value: &Ref<Value>) {
+        // A value that is being changed could be anything
+        let container = match value.try_borrow() {
+            Ok(value) => matches!(
+                *value,
+                Value::List(_)
+                    | Value::Tree(_)
+                    | Value::Function(_)
+                    | Value::Error(_)
+                    | Value::Coroutine(_)
+            ),
+            Err(_) => true,
+        };
+        if container {
+            let address = Ref::address(value);
+            let weak = Ref::downgrade(value);
+            if self.tracked.insert(address, weak).is_none() {
+                self.allocations += 1;
+            }
+        }
+    }
+
+    /// Returns true if enough values have been tracked to collect
+    pub(crate) fn should_collect(&self) -> bool {
+        matches!(self.threshold, Some(threshold) if self.allocations >= threshold)
+    }
+
+    /// Change the number of allocations that triggers a collection
+    pub(crate) fn set_threshold(&mut self, threshold: Option<usize>) {
+        self.threshold = threshold;
+    }
+
+    /// Free the cycles of tracked values that can't be reached,
+    /// and return the number of values that were freed
+    pub(crate) fn collect(&mut self) -> usize {
+        self.allocations = 0;
+
+        // Forget the values that were freed normally
+        let mut nodes = BTreeMap::new();
+        let mut pending = Vec::new();
+        self.tracked.retain(|address, weak| match weak.upgrade() {
+            Some(value) => {
+                nodes.insert(*address, Node::new(value));
+                pending.push(*address);
+                true
+            }
+            None => false,
+        });
+
+        // 1) Find every value reachable from the tracked values
+        while let Some(address) = pending.pop() {
+            let node = nodes.get_mut(&address).expect("Pending values are nodes");
+            let mut found = Vec::new();
+            if let Ok(value) = node.value.try_borrow() {
+                children(&value, &mut |child| found.push(Ref::clone(child)));
+                node.visited = true;
+            }
+            node.children = found.iter().map(Ref::address).collect();
+
+            for child in found {
+                let child_address = Ref::address(&child);
+                if let Entry::Vacant(entry) = nodes.entry(child_address) {
+                    entry.insert(Node::new(child));
+                    pending.push(child_address);
+                }
+            }
+        }
+
+        // 2) Subtract the references between the values. Each node
+        //    holds one reference itself, which is subtracted too.
+        for node in nodes.values_mut() {
+            node.external = Ref::strong_count(&node.value) - 1;
+        }
+        let edges: Vec<usize> = nodes
+            .values()
+            .flat_map(|node| node.children.iter().copied())
+            .collect();
+        for child in edges {
+            if let Some(node) = nodes.get_mut(&child) {
+                node.external = node.external.saturating_sub(1);
+            }
+        }
+
+        // 3) Keep everything reachable from outside, including the
+        //    values that were borrowed, which are still in use
+        let mut alive = BTreeMap::new();
+        let mut pending: Vec<usize> = nodes
+            .iter()
+            .filter(|(_, node)| node.external > 0 || !node.visited)
+            .map(|(address, _)| *address)
+            .collect();
+        while let Some(address) = pending.pop() {
+            if alive.insert(address, ()).is_none() {
+                pending.extend(nodes[&address].children.iter().copied());
+            }
+        }
+
+        // 4) Clear the garbage. The old contents are dropped after
+        //    every value is cleared, so nothing is borrowed by then.
+        let mut garbage = Vec::new();
+        for (address, node) in &nodes {
+            if !alive.contains_key(address) {
+                garbage.push(node.value.replace(Value::None));
+            }
+        }
+        let freed = garbage.len();
+        drop(garbage);
+        drop(nodes);
+        freed
+    }
+}
+
+impl Node {
+    fn new(value: Ref<Value>) -> Self {
+        Self {
+            value,
+            external: 0,
+            children: Vec::new(),
+            visited: false,
+        }
+    }
+}
+
+/// Visit each Ref that a value holds directly
+fn children(value: &Value, visit: &mut impl FnMut(&Ref<Value>)) {
+    match value {
+        Value::List(l) => l.iter().for_each(visit),
+        Value::Tree(t) => t.values().for_each(visit),
+        Value::Function(f) => f.get_context().for_each_ref(visit),
+        Value::Error(e) => e.payload.iter().for_each(visit),
+        Value::Coroutine(c) => c.for_each_ref(visit),
+        _ => {}
+    }
+}
@@ -0,0 +1,131 @@
+// For the string literals and function bodies
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The deepest that function literals can be nested in a program.
+/// Programs are cloned, compared, rendered and dropped recursively,
+/// so neither the assembler nor the bytecode decoder build deeper ones.
+pub const MAX_NESTING: usize = 256;
+
+/// A literal value that an instruction can push onto the stack
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    /// Push a String, like `Value::string`
+    String(String),
+    /// Push a Number, like `Value::number`
+    Number(f64),
+    /// Push an Int, like `Value::int`
+    Int(i64),
+    /// Push a Bool, like `Value::boolean`
+    Bool(bool),
+    /// Push an empty List, like `Value::list`
+    List,
+    /// Push an empty Tree, like `Value::tree`
+    Tree,
+    /// Push a None value, like `Value::none`
+    None,
+    /// Push a Function whose body is this block of instructions,
+    /// capturing the Machine it is pushed in, like `Value::program`
+    Function(Vec<Instruction>),
+    /// Push a Function whose body is this block of instructions,
+    /// capturing only the named registers, like `Value::program_closure`.
+    /// This is boxed to keep instructions, and errors, small.
+    Closure(Box<(Vec<String>, Vec<Instruction>)>),
+}
+
+/// Each instruction mirrors one of the public instruction
+/// methods on the Machine. A list of instructions is a
+/// program that can be run with `Machine::run`, or used
+/// as the body of a Function.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+    /// Push a literal value onto the stack
+    Push(Literal),
+    /// Calls `Machine::copy`
+    Copy,
+    /// Calls `Machine::assign`
+    Assign,
+    /// Calls `Machine::index`
+    Index,
+    /// Calls `Machine::slice`
+    Slice,
+    /// Calls `Machine::len`
+    Len,
+    /// Calls `Machine::range`
+    Range,
+    /// Calls `Machine::method_call`
+    MethodCall,
+    /// Calls `Machine::call`
+    Call,
+    /// Calls `Machine::for_loop`
+    ForLoop,
+    /// Calls `Machine::while_loop`
+    WhileLoop,
+    /// Calls `Machine::if_then_else`
+    IfThenElse,
+    /// Calls `Machine::store`
+    Store,
+    /// Calls `Machine::load`
+    Load,
+    /// Calls `Machine::throw`
+    Throw,
+    /// Calls `Machine::try_catch`
+    TryCatch,
+    /// Calls `Machine::break_loop`
+    Break,
+    /// Calls `Machine::continue_loop`
+    Continue,
+    /// Calls `Machine::return_early`
+    Return,
+    /// Calls `Machine::tail_call`
+    TailCall,
+    /// Calls `Machine::declare`
+    Declare,
+    /// Calls `Machine::push_scope`
+    PushScope,
+    /// Calls `Machine::pop_scope`
+    PopScope,
+    /// Calls `Machine::share`
+    Share,
+    /// Calls `Machine::downgrade`
+    Downgrade,
+    /// Calls `Machine::upgrade`
+    Upgrade,
+    /// Calls `Machine::coroutine`
+    Coroutine,
+    /// Calls `Machine::resume`
+    Resume,
+    /// Calls `Machine::yield_value`
+    Yield,
+    /// Calls `Machine::add`
+    Add,
+    /// Calls `Machine::sub`
+    Sub,
+    /// Calls `Machine::mul`
+    Mul,
+    /// Calls `Machine::div`
+    Div,
+    /// Calls `Machine::rem`
+    Rem,
+    /// Calls `Machine::neg`
+    Neg,
+    /// Calls `Machine::not`
+    Not,
+    /// Calls `Machine::eq`
+    Eq,
+    /// Calls `Machine::ne`
+    Ne,
+    /// Calls `Machine::lt`
+    Lt,
+    /// Calls `Machine::le`
+    Le,
+    /// Calls `Machine::gt`
+    Gt,
+    /// Calls `Machine::ge`
+    Ge,
+    /// Calls `Machine::and`
+    And,
+    /// Calls `Machine::or`
+    Or,
+}
@@ -0,0 +1,125 @@
+use crate::task::Task;
+use crate::{Key, Machine, MachineError, Ref, Value};
+
+// For the elements that are left to visit
+use alloc::vec::Vec;
+
+/// The COUNTER and ELEMENT of one iteration of a `for_loop`
+type Step = (Ref<Value>, Ref<Value>);
+
+/// The position of a `for_loop` in the value it iterates over.
+///
+/// Iterating over a collection visits a copy of it, so the body
+/// of the loop can change the collection while it runs. The copy
+/// is kept in reverse, so the next element is popped off the end.
+#[derive(Clone, PartialEq)]
+pub(crate) enum Iteration {
+    /// Calls the `next` method of an iterator made by `__iter__`
+    Iterator { iterator: Ref<Value>, index: i64 },
+    /// Resumes a coroutine until it finishes
+    Coroutine { coroutine: Ref<Value>, index: i64 },
+    /// The keys and values of a tree that are left to visit
+    Tree(Vec<(Key, Ref<Value>)>),
+    /// Ranges are iterated without making a list of their Ints
+    Range { next: i64, end: i64, index: i64 },
+    /// The elements of a list, string or bytes that are left to visit
+    Items { items: Vec<Ref<Value>>, index: i64 },
+}
+
+impl Iteration {
+    /// Start iterating over a value. A tree with an `__iter__`
+    /// method is called to get the iterator to use.
+    pub(crate) fn new(machine: &mut Machine, iterable: Ref<Value>) -> Result<Self, MachineError> {
+        // Coroutines are resumed in their Ref, not in a copy
+        if matches!(*iterable.borrow(), Value::Coroutine(_)) {
+            return Ok(Self::Coroutine {
+                coroutine: iterable,
+                index: 0,
+            });
+        }
+
+        if Self::has_iterator(&iterable) {
+            machine.push(iterable);
+            machine.push(Value::string("__iter__"));
+            machine.try_method_call()?;
+            return Ok(Self::Iterator {
+                iterator: machine.try_pop()?,
+                index: 0,
+            });
+        }
+
+        Ok(match iterable.get() {
+            Value::Tree(t) => Self::Tree(t.into_iter().rev().collect()),
+            Value::Range(start, end) => Self::Range {
+                next: start,
+                end,
+                index: 0,
+            },
+            value => {
+                let mut items: Vec<_> = value.into_iter().collect();
+                items.reverse();
+                Self::Items { items, index: 0 }
+            }
+        })
+    }
+
+    /// Returns true if the value is a tree with an `__iter__` method
+    pub(crate) fn has_iterator(iterable: &Ref<Value>) -> bool {
+        matches!(&*iterable.borrow(), Value::Tree(t) if t.contains_key(&Key::from("__iter__")))
+    }
+
+    /// Get the COUNTER for an element that an iterator or
+    /// coroutine gave, and count the element
+    pub(crate) fn count(&mut self) -> Ref<Value> {
+        match self {
+            Self::Iterator { index, .. } | Self::Coroutine { index, .. } => {
+                *index += 1;
+                Value::int(*index - 1)
+            }
+            _ => unreachable!("Only iterators and coroutines are counted"),
+        }
+    }
+
+    /// Get the COUNTER and ELEMENT of the next iteration,
+    /// or None if there are no more elements
+    pub(crate) fn next(&mut self, machine: &mut Machine) -> Result<Option<Step>, MachineError> {
+        Ok(match self {
+            // `next` pushes the next element and then `true`,
+            // or only pushes `false` when there are no more
+            Self::Iterator { iterator, .. } => {
+                machine.push(Ref::clone(iterator));
+                machine.push(Value::string("next"));
+                machine.try_method_call()?;
+                if !bool::from(machine.try_pop()?.get()) {
+                    return Ok(None);
+                }
+                let element = machine.try_pop()?;
+                Some((self.count(), element))
+            }
+            // The value a coroutine returns isn't an element
+            Self::Coroutine { coroutine, .. } => {
+                Task::resume(machine, Ref::clone(coroutine), Value::none(), false)?;
+                let running = bool::from(machine.try_pop()?.get());
+                let element = machine.try_pop()?;
+                if !running {
+                    return Ok(None);
+                }
+                Some((self.count(), element))
+            }
+            Self::Tree(members) => members
+                .pop()
+                .map(|(key, element)| (Ref::new(key.into()), element)),
+            Self::Range { next, end, index } if *next < *end => {
+                let item = (Value::int(*index), Value::int(*next));
+                *next += 1;
+                *index += 1;
+                Some(item)
+            }
+            Self::Range { .. } => None,
+            Self::Items { items, index } => items.pop().map(|element| {
+                *index += 1;
+                (Value::int(*index - 1), element)
+            }),
+        })
+    }
+}
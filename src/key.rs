@@ -0,0 +1,267 @@
+use crate::{ErrorKind, MachineError, Ref, Value};
+
+// For string and byte keys
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+// For ordering and hashing keys
+use core::cmp::Ordering;
+use core::convert::TryFrom;
+use core::hash::{Hash, Hasher};
+// For implementing Display and Debug
+use core::fmt::{Debug, Display, Error, Formatter};
+
+/// A key in a Tree. Keys are ordered and hashable, unlike Values,
+/// so that trees can be keyed by more than just strings.
+///
+/// Numbers that are whole and fit in an Int are always stored
+/// as Ints, so `1` and `1.0` are the same key, like they are equal
+/// as Values. Strings are never converted, so `1` and `"1"` are
+/// different keys.
+#[derive(Clone)]
+pub enum Key {
+    Bool(bool),
+    Int(i64),
+    Number(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    Tuple(Vec<Key>),
+}
+
+impl Key {
+    /// The position of this key's variant, which orders
+    /// keys of different variants. Ints and Numbers are
+    /// ordered together by their numeric value.
+    fn variant(&self) -> u8 {
+        match self {
+            Self::Bool(_) => 0,
+            Self::Int(_) | Self::Number(_) => 1,
+            Self::String(_) => 2,
+            Self::Bytes(_) => 3,
+            Self::Tuple(_) => 4,
+        }
+    }
+}
+
+/// Keys are ordered by variant, and then by their contents.
+/// Numbers are totally ordered, so even NaN can be a key.
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Bool(a), Self::Bool(b)) => a.cmp(b),
+            (Self::Int(a), Self::Int(b)) => a.cmp(b),
+            (Self::Number(a), Self::Number(b)) => a.total_cmp(b),
+            // An Int comes before a Number with the same value, which
+            // only happens if the Number was constructed directly
+            (Self::Int(a), Self::Number(b)) => (*a as f64).total_cmp(b).then(Ordering::Less),
+            (Self::Number(a), Self::Int(b)) => a.total_cmp(&(*b as f64)).then(Ordering::Greater),
+            (Self::String(a), Self::String(b)) => a.cmp(b),
+            (Self::Bytes(a), Self::Bytes(b)) => a.cmp(b),
+            (Self::Tuple(a), Self::Tuple(b)) => a.cmp(b),
+            (a, b) => a.variant().cmp(&b.variant()),
+        }
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Ints and Numbers are never equal, so they can hash differently
+        match self {
+            Self::Bool(b) => (0u8, b).hash(state),
+            Self::Int(n) => (1u8, n).hash(state),
+            Self::Number(n) => (2u8, n.to_bits()).hash(state),
+            Self::String(s) => (3u8, s).hash(state),
+            Self::Bytes(b) => (4u8, b).hash(state),
+            Self::Tuple(t) => (5u8, t).hash(state),
+        }
+    }
+}
+
+/// How to display a key, with strings written as they are
+impl Display for Key {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Self::String(s) => write!(f, "{}", s),
+            key => Debug::fmt(key, f),
+        }
+    }
+}
+
+/// How to display a key inside of a tree, with strings quoted
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Int(n) => write!(f, "{}", n),
+            Self::Number(n) => write!(f, "{}", n),
+            Self::String(s) => write!(f, "{:?}", s),
+            Self::Bytes(b) => write!(f, "{}", Value::Bytes(b.clone())),
+            Self::Tuple(t) => {
+                write!(f, "(")?;
+                for (i, key) in t.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}", key)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+// ############################################################
+// The following traits are for building keys from Rust values!
+// ############################################################
+
+/// Make Key from &str
+impl From<&str> for Key {
+    fn from(s: &str) -> Self {
+        Key::String(s.to_string())
+    }
+}
+
+/// Make Key from String
+impl From<String> for Key {
+    fn from(s: String) -> Self {
+        Key::String(s)
+    }
+}
+
+/// Make Key from bool
+impl From<bool> for Key {
+    fn from(b: bool) -> Self {
+        Key::Bool(b)
+    }
+}
+
+/// Make Key from Int
+impl From<i64> for Key {
+    fn from(n: i64) -> Self {
+        Key::Int(n)
+    }
+}
+
+/// Make Key from Int
+impl From<i32> for Key {
+    fn from(n: i32) -> Self {
+        Key::Int(n.into())
+    }
+}
+
+/// Make Key from Int
+impl From<usize> for Key {
+    fn from(n: usize) -> Self {
+        match i64::try_from(n) {
+            Ok(n) => Key::Int(n),
+            Err(_) => Key::Number(n as f64),
+        }
+    }
+}
+
+/// Make Key from Number, which is an Int if it is whole
+impl From<f64> for Key {
+    fn from(n: f64) -> Self {
+        if n >= i64::MIN as f64 && n < i64::MAX as f64 && n as i64 as f64 == n {
+            Key::Int(n as i64)
+        } else {
+            Key::Number(n)
+        }
+    }
+}
+
+/// Make Key from Bytes
+impl From<Vec<u8>> for Key {
+    fn from(b: Vec<u8>) -> Self {
+        Key::Bytes(b)
+    }
+}
+
+/// Make Key from Bytes
+impl From<&[u8]> for Key {
+    fn from(b: &[u8]) -> Self {
+        Key::Bytes(b.to_vec())
+    }
+}
+
+/// Make Key from Tuple
+impl From<Vec<Key>> for Key {
+    fn from(t: Vec<Key>) -> Self {
+        Key::Tuple(t)
+    }
+}
+
+/// Use a Value as a Key. Lists are used as tuples of their
+/// elements, and values that can't be keys raise InvalidIndex.
+impl TryFrom<&Value> for Key {
+    type Error = MachineError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        Key::try_from_in(value, &mut Vec::new())
+    }
+}
+
+impl Key {
+    /// Use a Value as a Key. PATH holds the addresses of the lists
+    /// being converted around it, so a list that contains itself
+    /// raises InvalidIndex instead of being converted forever.
+    fn try_from_in(value: &Value, path: &mut Vec<usize>) -> Result<Self, MachineError> {
+        Ok(match value {
+            Value::Bool(b) => Key::Bool(*b),
+            Value::Int(n) => Key::Int(*n),
+            Value::Number(n) => Key::from(*n),
+            Value::String(s) => Key::String(s.clone()),
+            Value::Bytes(b) => Key::Bytes(b.clone()),
+            Value::List(l) => {
+                let mut tuple = Vec::with_capacity(l.len());
+                for item in l {
+                    let address = Ref::address(item);
+                    if path.contains(&address) {
+                        return Err(MachineError::new(
+                            ErrorKind::InvalidIndex,
+                            "Can't use a list that contains itself as a key",
+                        ));
+                    }
+                    path.push(address);
+                    let key = Key::try_from_in(&item.borrow(), path);
+                    path.pop();
+                    tuple.push(key?);
+                }
+                Key::Tuple(tuple)
+            }
+            value => {
+                return Err(MachineError::new(
+                    ErrorKind::InvalidIndex,
+                    format!("Can't use {} as a key", value),
+                ))
+            }
+        })
+    }
+}
+
+/// Turn a Key back into a Value, with tuples as lists
+impl From<Key> for Value {
+    fn from(key: Key) -> Self {
+        match key {
+            Key::Bool(b) => Value::Bool(b),
+            Key::Int(n) => Value::Int(n),
+            Key::Number(n) => Value::Number(n),
+            Key::String(s) => Value::String(s),
+            Key::Bytes(b) => Value::Bytes(b),
+            Key::Tuple(t) => Value::List(t.into_iter().map(|k| Ref::new(k.into())).collect()),
+        }
+    }
+}
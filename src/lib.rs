@@ -1,15 +1,36 @@
 #![no_std]
+#![forbid(unsafe_code)]
 #[macro_use]
 extern crate alloc;
-use alloc::rc::Rc;
 
-pub type Ref<T> = Rc<T>;
+mod reference;
+pub use reference::{Ref, WeakRef};
 
 mod value;
-pub use value::Value;
+pub use value::{Elements, Value};
+mod key;
+pub use key::Key;
 
 mod machine;
-pub use machine::Machine;
+pub use machine::{Frame, Machine};
+
+mod gc;
+pub use gc::DEFAULT_GC_THRESHOLD;
+mod iteration;
+mod limits;
+pub use limits::{DEFAULT_DEPTH_LIMIT, DEFAULT_NATIVE_DEPTH_LIMIT};
+mod task;
+mod coroutine;
+pub use coroutine::{Coroutine, Status};
 
 mod function;
-use function::Function;
+pub use function::{Body, Function};
+
+mod error;
+pub use error::{ErrorKind, Exception, MachineError, TRACE_LIMIT};
+
+mod instruction;
+pub use instruction::{Instruction, Literal, MAX_NESTING};
+
+pub mod xasm;
+pub mod bytecode;
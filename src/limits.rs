@@ -0,0 +1,298 @@
+use crate::{ErrorKind, Key, MachineError, Ref, Value};
+
+// For converting the index of a list
+use core::convert::TryFrom;
+// The Machine derives these, so the Limits have to implement them
+use core::cmp::Ordering;
+// For estimating the memory that values use
+use core::mem::size_of;
+
+/// The approximate number of bytes a Ref to a value allocates:
+/// the value, with the reference counts and borrow flag next to it
+const CELL: usize = size_of::<Value>() + 3 * size_of::<usize>();
+
+/// The approximate number of bytes each element of a list
+/// uses, with the value that it refers to
+const ELEMENT: usize = size_of::<Ref<Value>>() + CELL;
+
+/// The approximate number of bytes each member of a tree uses
+const MEMBER: usize = size_of::<Key>() + ELEMENT;
+
+/// The most call frames a program can have, unless
+/// it is changed with `Machine::set_depth_limit`
+pub const DEFAULT_DEPTH_LIMIT: usize = 10_000;
+
+/// The most native functions that can be running inside of each
+/// other, with the functions they call, unless it is changed with
+/// `Machine::set_native_depth_limit`
+pub const DEFAULT_NATIVE_DEPTH_LIMIT: usize = 64;
+
+/// The budgets that bound how much a program run by the Machine
+/// can do. Every function the program calls shares them, so they
+/// are handed to the machine of each call along with the stack.
+#[derive(Clone)]
+pub(crate) struct Limits {
+    /// The number of steps left to run, or None for no limit
+    fuel: Option<u64>,
+    /// The most values the stack can hold
+    stack: Option<usize>,
+    /// The most elements a list or tree can hold
+    length: Option<usize>,
+    /// The most bytes a string or byte string can hold
+    string: Option<usize>,
+    /// The most bytes that can be allocated in total
+    allocation: Option<usize>,
+    /// The approximate number of bytes allocated
+    /// since the allocation limit was set
+    allocated: usize,
+    /// The most call frames there can be
+    depth: Option<usize>,
+    /// The most native functions that can be running at once
+    native: Option<usize>,
+    /// The number of native functions that are running,
+    /// each of which uses the Rust stack
+    nested: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            fuel: None,
+            stack: None,
+            length: None,
+            string: None,
+            allocation: None,
+            allocated: 0,
+            depth: Some(DEFAULT_DEPTH_LIMIT),
+            native: Some(DEFAULT_NATIVE_DEPTH_LIMIT),
+            nested: 0,
+        }
+    }
+}
+
+/// The limits aren't part of the state of the Machine, so
+/// two Machines are equal whatever their budgets are
+impl PartialEq for Limits {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl PartialOrd for Limits {
+    fn partial_cmp(&self, _: &Self) -> Option<Ordering> {
+        Some(Ordering::Equal)
+    }
+}
+
+/// Fail with MemoryLimit if AMOUNT is over the LIMIT, if there is one
+fn check(amount: usize, limit: Option<usize>, what: &str, unit: &str) -> Result<(), MachineError> {
+    match limit {
+        Some(limit) if amount > limit => Err(MachineError::new(
+            ErrorKind::MemoryLimit,
+            format!("{} would be over the limit of {} {}", what, limit, unit),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The approximate number of bytes a new value allocates. The
+/// elements of lists and trees are counted with them, since
+/// copying a collection makes new elements too.
+fn size(value: &Value) -> usize {
+    CELL + match value {
+        Value::String(s) => s.len(),
+        Value::Bytes(b) => b.len(),
+        Value::List(l) => l.len() * ELEMENT,
+        Value::Tree(t) => t.len() * MEMBER,
+        // A function literal copies the stack and registers of its context
+        Value::Function(f) => {
+            let context = f.get_context();
+            (context.stack.len() + context.registers.len()) * MEMBER
+        }
+        _ => 0,
+    }
+}
+
+impl Limits {
+    /// Return the fuel that is left, or None if there is no limit
+    pub(crate) fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Set the fuel that is left, or None for no limit
+    pub(crate) fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Use one unit of fuel for a step, or fail
+    /// with OutOfFuel if there is none left
+    pub(crate) fn try_use_fuel(&mut self) -> Result<(), MachineError> {
+        match &mut self.fuel {
+            Some(0) => Err(MachineError::new(ErrorKind::OutOfFuel, "Ran out of fuel")),
+            Some(fuel) => {
+                *fuel -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Set the most values the stack can hold
+    pub(crate) fn set_stack(&mut self, limit: Option<usize>) {
+        self.stack = limit;
+    }
+
+    /// Set the most elements a list or tree can hold
+    pub(crate) fn set_length(&mut self, limit: Option<usize>) {
+        self.length = limit;
+    }
+
+    /// Set the most bytes a string or byte string can hold
+    pub(crate) fn set_string(&mut self, limit: Option<usize>) {
+        self.string = limit;
+    }
+
+    /// Set the most bytes that can be allocated from now on
+    pub(crate) fn set_allocation(&mut self, limit: Option<usize>) {
+        self.allocation = limit;
+        self.allocated = 0;
+    }
+
+    /// Return the approximate number of bytes allocated
+    /// since the allocation limit was set
+    pub(crate) fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    /// Count the memory used by a new value
+    pub(crate) fn allocate(&mut self, value: &Ref<Value>) {
+        // A value that is being changed is only counted when it is made
+        if let Ok(value) = value.try_borrow() {
+            self.allocated = self.allocated.saturating_add(size(&value));
+        }
+    }
+
+    /// Fail if the stack holds too many values, or too much has been
+    /// allocated. These are checked after each step, since a step
+    /// only pushes a few values.
+    pub(crate) fn try_check(&self, stack: usize) -> Result<(), MachineError> {
+        check(stack, self.stack, "The stack", "values")?;
+        self.try_reserve(0)
+    }
+
+    /// Fail if BYTES more can't be allocated
+    fn try_reserve(&self, bytes: usize) -> Result<(), MachineError> {
+        let total = self.allocated.saturating_add(bytes);
+        check(total, self.allocation, "The memory allocated", "bytes")
+    }
+
+    /// Fail if a value made by an operator is too big
+    pub(crate) fn try_check_value(&self, value: &Value) -> Result<(), MachineError> {
+        match value {
+            Value::String(s) => self.try_check_string(s.len()),
+            Value::Bytes(b) => self.try_check_string(b.len()),
+            Value::List(l) => self.try_check_length(l.len()),
+            Value::Tree(t) => self.try_check_length(t.len()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail if adding LHS and RHS would make a value that is too
+    /// big, before they are concatenated
+    pub(crate) fn try_check_concat(&self, lhs: &Value, rhs: &Value) -> Result<(), MachineError> {
+        match (lhs, rhs) {
+            (Value::String(a), Value::String(b)) => {
+                self.try_check_string(a.len().saturating_add(b.len()))
+            }
+            (Value::Bytes(a), Value::Bytes(b)) => {
+                self.try_check_string(a.len().saturating_add(b.len()))
+            }
+            (Value::List(a), Value::List(b)) => {
+                self.try_check_length(a.len().saturating_add(b.len()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail if a string of BYTES can't be made, before it is made
+    pub(crate) fn try_check_string(&self, bytes: usize) -> Result<(), MachineError> {
+        check(bytes, self.string, "The string", "bytes")?;
+        self.try_reserve(bytes)
+    }
+
+    /// Fail if a list or tree can't hold LENGTH elements
+    fn try_check_length(&self, length: usize) -> Result<(), MachineError> {
+        check(length, self.length, "The collection", "elements")
+    }
+
+    /// Count the elements that indexing TABLE with KEY adds to it,
+    /// before they are added. A list grows up to the index, and a
+    /// tree gets a new member if it doesn't have the key.
+    pub(crate) fn try_grow(&mut self, table: &Value, key: &Key) -> Result<(), MachineError> {
+        let (length, added) = match (table, key) {
+            (Value::List(l), Key::Int(n)) => match usize::try_from(*n) {
+                Ok(n) if n >= l.len() => {
+                    let length = n.saturating_add(1);
+                    (length, length - l.len())
+                }
+                _ => return Ok(()),
+            },
+            (Value::Tree(t), key) if !t.contains_key(key) => (t.len() + 1, 1),
+            _ => return Ok(()),
+        };
+        self.try_check_length(length)?;
+        let bytes = added.saturating_mul(match table {
+            Value::Tree(_) => MEMBER,
+            _ => ELEMENT,
+        });
+        self.try_reserve(bytes)?;
+        self.allocated = self.allocated.saturating_add(bytes);
+        Ok(())
+    }
+
+    /// Set the most call frames there can be
+    pub(crate) fn set_depth(&mut self, limit: Option<usize>) {
+        self.depth = limit;
+    }
+
+    /// Set the most native functions that can be running at once
+    pub(crate) fn set_native(&mut self, limit: Option<usize>) {
+        self.native = limit;
+    }
+
+    /// Fail with RecursionLimit if there are already FRAMES
+    /// call frames, and another one can't be pushed
+    pub(crate) fn try_check_depth(&self, frames: usize) -> Result<(), MachineError> {
+        match self.depth {
+            Some(limit) if frames >= limit => Err(MachineError::new(
+                ErrorKind::RecursionLimit,
+                format!("Calls are nested deeper than the limit of {} frames", limit),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count a native function that starts running, or fail with
+    /// RecursionLimit if too many are running already. Each one
+    /// has to be counted out with `Limits::unnest` when it returns.
+    pub(crate) fn try_nest(&mut self) -> Result<(), MachineError> {
+        match self.native {
+            Some(limit) if self.nested >= limit => Err(MachineError::new(
+                ErrorKind::RecursionLimit,
+                format!(
+                    "Native functions are nested deeper than the limit of {}",
+                    limit
+                ),
+            )),
+            _ => {
+                self.nested += 1;
+                Ok(())
+            }
+        }
+    }
+
+    /// Count a native function that returned
+    pub(crate) fn unnest(&mut self) {
+        self.nested -= 1;
+    }
+}
@@ -1,20 +1,77 @@
-use crate::{Ref, Value};
+use crate::coroutine::Coroutine;
+use crate::gc::Collector;
+use crate::iteration::Iteration;
+use crate::limits::Limits;
+use crate::task::Task;
+use crate::value::{list_cmp, list_eq, map_cmp, map_eq, Pairs};
+use crate::{Body, ErrorKind, Exception, Instruction, Key, Literal, MachineError, Ref, Value};
 
 // We need BTreeMap to implement the 'Heap' (registers)
 use alloc::collections::BTreeMap;
+// The collector is boxed to keep Machines small
+use alloc::boxed::Box;
+// Programs are shared with the task that runs them
+use alloc::rc::Rc;
 // For ToString generics
 use alloc::string::{String, ToString};
 // We need Vec for the dynamically allocated stack
 use alloc::vec::Vec;
+// For comparing the contexts of functions
+use core::cmp::Ordering;
+// For converting indexes and repetitions
+use core::convert::TryFrom;
 // For implementing Display and Debug
 use core::fmt::{Display, Error, Formatter};
 
+/// A record of a function that is being called,
+/// used to show a backtrace of the guest program
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct Frame {
+    /// The name of the function, or its address if it doesn't have one
+    pub function: String,
+    /// The offset of the instruction being run in the function,
+    /// or None if the function is a native Rust closure
+    pub offset: Option<usize>,
+}
+
+/// How to display a Frame in a backtrace
+impl Display for Frame {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self.offset {
+            Some(offset) => write!(f, "{} at instruction {}", self.function, offset),
+            None => write!(f, "{} (native)", self.function),
+        }
+    }
+}
+
 #[derive(Default, Clone, PartialEq, PartialOrd)]
 pub struct Machine {
     /// A dynamically allocated stack to push and pop values onto and off of
     pub stack: Vec<Ref<Value>>,
     /// The place to store named values (variables)
     pub registers: BTreeMap<String, Ref<Value>>,
+    /// The registers declared in each block that is running,
+    /// with the innermost block last. These are searched
+    /// before `registers`, and are dropped when their block ends.
+    pub scopes: Vec<BTreeMap<String, Ref<Value>>>,
+    /// The registers that are shared with closures by `Machine::share`.
+    /// Storing to one of these assigns to it, instead of replacing it,
+    /// so that the closures see the new value.
+    pub(crate) cells: Vec<Ref<Value>>,
+    /// An error raised by a foreign function, which is
+    /// raised by the Machine when the function returns
+    raised: Option<Exception>,
+    /// The functions being called, with the caller of each
+    /// function before it and the current function last
+    pub(crate) frames: Vec<Frame>,
+    /// Frees the cycles of values that can't be reached anymore.
+    /// This is boxed because every function's context is a Machine.
+    pub(crate) collector: Box<Collector>,
+    /// The fuel left for the program being run
+    pub(crate) limits: Box<Limits>,
+    /// The program that ran out of fuel, to carry on
+    /// with `Machine::try_continue`
+    pub(crate) paused: Option<Box<Task>>,
 }
 
 impl Machine {
@@ -23,34 +80,339 @@ impl Machine {
         Machine {
             stack: Vec::new(),
             registers: BTreeMap::new(),
+            scopes: Vec::new(),
+            cells: Vec::new(),
+            raised: None,
+            frames: Vec::new(),
+            collector: Box::default(),
+            limits: Box::default(),
+            paused: None,
         }
     }
 
-    /// ####################################################
-    /// The following functions are meant to be used to
-    /// interface and interact with the virtual machine
-    /// ####################################################
+    // ####################################################
+    // The following functions are meant to be used to
+    // interface and interact with the virtual machine
+    // ####################################################
 
     /// FOR FOREIGN FUNCTIONS
     /// This gets an argument from the call to this foreign
     /// function by popping a value off the stack, and removing
     /// the reference
     pub fn get_arg(&mut self) -> Value {
-        (*self.pop()).clone()
+        self.pop().get()
     }
 
     /// FOR FOREIGN FUNCTIONS
-    /// This pushes a return value onto the stack
+    /// This pushes a return value onto the stack.
+    /// Returning an Error value raises it instead,
+    /// so failed arithmetic can be caught by `try_catch`.
     pub fn return_value(&mut self, value: Value) {
-        self.push(Ref::new(value))
+        if value.is_err() {
+            self.raise(value)
+        } else {
+            self.push(Ref::new(value))
+        }
+    }
+
+    /// FOR FOREIGN FUNCTIONS
+    /// This raises an error when the foreign function returns.
+    /// An Error value is raised as it is, and any other value
+    /// is raised as the payload of an Exception.
+    pub fn raise(&mut self, value: Value) {
+        self.raised = Some(match value {
+            Value::Error(e) => e,
+            value => Exception {
+                kind: ErrorKind::Custom(String::from("Exception")),
+                message: value.to_string(),
+                payload: Some(Ref::new(value)),
+            },
+        });
+    }
+
+    /// Free the values that can't be reached anymore because they
+    /// only refer to each other, like a tree that stores itself, and
+    /// return the number of values that were freed. This happens
+    /// automatically too, see `Machine::set_gc_threshold`.
+    ///
+    /// Only the lists, trees, functions, errors and coroutines that
+    /// were pushed onto this machine or assigned to by it, and the
+    /// values they refer to, are collected.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.collector.collect()
+    }
+
+    /// Collect garbage automatically between instructions once this
+    /// many lists, trees, functions, errors and coroutines have been
+    /// pushed since the last collection, or never if the threshold
+    /// is None.
+    /// The default is `DEFAULT_GC_THRESHOLD`.
+    pub fn set_gc_threshold(&mut self, threshold: Option<usize>) {
+        self.collector.set_threshold(threshold);
+    }
+
+    /// Give the machine a budget of fuel, or None to let programs run
+    /// for as long as they like, which is the default. Each step of
+    /// a program uses one unit of fuel: each instruction, including
+    /// the ones inside of the functions it calls, and the end of each
+    /// block of instructions, like each iteration of a loop.
+    ///
+    /// When the fuel runs out, `Machine::try_run` fails with OutOfFuel
+    /// and the program is paused between two steps. Give the machine
+    /// more fuel, and call `Machine::try_continue` to carry on from
+    /// where it stopped. Running another program abandons it.
+    ///
+    /// Native functions, and the functions they call, run to
+    /// completion, so running out of fuel inside of them
+    /// stops the program for good.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.limits.set_fuel(fuel);
+    }
+
+    /// Return the fuel that is left, or None if there is no limit
+    pub fn fuel(&self) -> Option<u64> {
+        self.limits.fuel()
+    }
+
+    /// Limit the number of values on the stack, or None for no
+    /// limit, which is the default. A program that pushes more
+    /// values than this fails with MemoryLimit, which `try_catch`
+    /// can catch, since it drops the values its BODY pushed.
+    pub fn set_stack_limit(&mut self, limit: Option<usize>) {
+        self.limits.set_stack(limit);
+    }
+
+    /// Limit the number of elements in a list or tree, or None for
+    /// no limit, which is the default. Indexing a list past its end,
+    /// adding a member to a tree or adding lists together fails with
+    /// MemoryLimit if the result would be too long, before the
+    /// memory for it is allocated.
+    pub fn set_length_limit(&mut self, limit: Option<usize>) {
+        self.limits.set_length(limit);
+    }
+
+    /// Limit the number of bytes in a string or byte string, or None
+    /// for no limit, which is the default. Adding or repeating strings
+    /// fails with MemoryLimit if the result would be too long, before
+    /// the memory for it is allocated.
+    pub fn set_string_limit(&mut self, limit: Option<usize>) {
+        self.limits.set_string(limit);
+    }
+
+    /// Limit the approximate number of bytes that programs allocate
+    /// from now on, or None for no limit, which is the default.
+    ///
+    /// Each value that is pushed onto the stack for the first time
+    /// counts, with the elements of the lists and trees it holds, and
+    /// so do the elements that indexing adds to a list or tree. Values
+    /// count even after they are freed, so this bounds the total that
+    /// is allocated, rather than the memory in use at any one time.
+    /// Going over the limit fails with MemoryLimit.
+    pub fn set_allocation_limit(&mut self, limit: Option<usize>) {
+        self.limits.set_allocation(limit);
+    }
+
+    /// Return the approximate number of bytes allocated since
+    /// the allocation limit was set, see `Machine::set_allocation_limit`
+    pub fn allocated(&self) -> usize {
+        self.limits.allocated()
+    }
+
+    /// Limit the number of call frames, or None for no limit. The
+    /// default is `DEFAULT_DEPTH_LIMIT`. A function call, or a block
+    /// like the body of a loop, that would have more frames than this
+    /// fails with RecursionLimit, which `try_catch` can catch.
+    ///
+    /// Functions made from instructions are run without using the
+    /// Rust stack, so this only bounds the memory that their frames
+    /// use, see `Machine::set_native_depth_limit` for the Rust stack.
+    pub fn set_depth_limit(&mut self, limit: Option<usize>) {
+        self.limits.set_depth(limit);
+    }
+
+    /// Limit the number of native functions that can be running
+    /// inside of each other, or None for no limit. The default is
+    /// `DEFAULT_NATIVE_DEPTH_LIMIT`. A native function, and each
+    /// function it calls, runs on the Rust stack, so a program that
+    /// recurses through a native function would overflow it without
+    /// this. Calling one more fails with RecursionLimit instead.
+    ///
+    /// Each one uses a few kilobytes of the Rust stack, or tens of
+    /// kilobytes in a debug build, so a thread with a small stack
+    /// may need a lower limit.
+    pub fn set_native_depth_limit(&mut self, limit: Option<usize>) {
+        self.limits.set_native(limit);
+    }
+
+    /// Returns true if a program ran out of fuel, and can
+    /// be carried on with `Machine::try_continue`
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// Carry on running the program that ran out of fuel, from where
+    /// it stopped. This fails like `Machine::try_run`, including with
+    /// OutOfFuel if the fuel runs out again.
+    pub fn try_continue(&mut self) -> Result<(), MachineError> {
+        match self.paused.take() {
+            Some(task) if task.is_program() => self.run_main(*task),
+            Some(task) => task.run(self, true),
+            None => Err(MachineError::new(
+                ErrorKind::ControlFlow,
+                "There is no paused program to continue",
+            )),
+        }
+    }
+
+    /// Give another machine the state that every machine running
+    /// a program shares: the call frames, collector and limits.
+    /// The machine gets the other machine's state in return.
+    pub(crate) fn hand_over(&mut self, to: &mut Machine) {
+        core::mem::swap(&mut self.frames, &mut to.frames);
+        core::mem::swap(&mut self.collector, &mut to.collector);
+        core::mem::swap(&mut self.limits, &mut to.limits);
+    }
+
+    /// Visit each Ref that the machine holds, which
+    /// are the values that a function's context keeps alive
+    pub(crate) fn for_each_ref(&self, visit: &mut impl FnMut(&Ref<Value>)) {
+        self.stack.iter().for_each(&mut *visit);
+        self.registers.values().for_each(&mut *visit);
+        for scope in &self.scopes {
+            scope.values().for_each(&mut *visit);
+        }
+        self.cells.iter().for_each(&mut *visit);
+        if let Some(e) = &self.raised {
+            e.payload.iter().for_each(&mut *visit);
+        }
+    }
+
+    /// Compare the values in two machines, which are the
+    /// contexts of functions, see `Value::eq_in`
+    pub(crate) fn eq_in(&self, other: &Self, pairs: &mut Pairs) -> bool {
+        list_eq(&self.stack, &other.stack, pairs)
+            && map_eq(&self.registers, &other.registers, pairs)
+            && self.scopes.len() == other.scopes.len()
+            && (self.scopes.iter())
+                .zip(&other.scopes)
+                .all(|(a, b)| map_eq(a, b, pairs))
+    }
+
+    /// Order the values in two machines by their
+    /// stacks, and then by their registers
+    pub(crate) fn cmp_in(&self, other: &Self, pairs: &mut Pairs) -> Option<Ordering> {
+        match list_cmp(&self.stack, &other.stack, pairs) {
+            Some(Ordering::Equal) => map_cmp(&self.registers, &other.registers, pairs),
+            ordering => ordering,
+        }
+    }
+
+    /// Collect garbage if enough values were pushed since the last time
+    pub(crate) fn maybe_collect_garbage(&mut self) {
+        if self.collector.should_collect() {
+            self.collector.collect();
+        }
+    }
+
+    /// Return the call frames of the functions being called,
+    /// with the outermost frame first
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Run a function in a new call frame. Errors raised inside
+    /// it get a backtrace, if a deeper frame didn't give them one.
+    pub(crate) fn in_frame(
+        &mut self,
+        function: String,
+        body: impl FnOnce(&mut Self) -> Result<(), MachineError>,
+    ) -> Result<(), MachineError> {
+        self.try_check_depth()?;
+        // The body runs on the Rust stack
+        self.limits.try_nest()?;
+        self.frames.push(Frame {
+            function,
+            offset: None,
+        });
+        let result = body(self).map_err(|e| e.with_trace(&self.frames));
+        self.frames.pop();
+        self.limits.unnest();
+        result
+    }
+
+    /// Fail with RecursionLimit if another call frame can't be pushed
+    pub(crate) fn try_check_depth(&self) -> Result<(), MachineError> {
+        self.limits.try_check_depth(self.frames.len())
+    }
+
+    /// Run a block in a new scope, so the registers it declares
+    /// are dropped when it ends, even if it fails.
+    pub(crate) fn in_scope<T>(&mut self, body: impl FnOnce(&mut Self) -> T) -> T {
+        let depth = self.scopes.len();
+        self.scopes.push(BTreeMap::new());
+        let result = body(self);
+        self.scopes.truncate(depth);
+        result
+    }
+
+    /// Find the innermost register named KEY
+    fn lookup(&self, key: &str) -> Option<&Ref<Value>> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(key))
+            .or_else(|| self.registers.get(key))
+    }
+
+    /// Returns true if the register is shared with a closure
+    fn is_cell(cells: &[Ref<Value>], register: &Ref<Value>) -> bool {
+        cells.iter().any(|cell| Ref::ptr_eq(cell, register))
+    }
+
+    /// Share a register with a closure, so that storing to it in
+    /// either machine assigns to it. Cells that nothing else
+    /// refers to anymore are forgotten.
+    pub(crate) fn add_cell(&mut self, cell: Ref<Value>) {
+        self.cells.retain(|cell| !Ref::is_unique(cell));
+        if !Self::is_cell(&self.cells, &cell) {
+            self.cells.push(cell);
+        }
+    }
+
+    /// Handle the signals that reach the end of a function call.
+    /// A `return` pushes its value, and a `break` or `continue`
+    /// has escaped the loop it was in and becomes an error.
+    pub(crate) fn catch_return(
+        &mut self,
+        result: Result<(), MachineError>,
+    ) -> Result<(), MachineError> {
+        match result {
+            Err(e) if e.kind == ErrorKind::Return => {
+                self.push(e.payload.unwrap_or_else(Value::none));
+                Ok(())
+            }
+            result => result.map_err(MachineError::escaped),
+        }
+    }
+
+    /// Record the offset of the instruction being run in the current frame
+    pub(crate) fn set_offset(&mut self, offset: usize) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.offset = Some(offset);
+        }
+    }
+
+    /// Take the error raised by a foreign function, if there is one
+    pub(crate) fn take_raised(&mut self) -> Option<MachineError> {
+        self.raised.take().map(MachineError::from)
     }
 
-    /// ####################################################
-    /// The following functions represent instructions that
-    /// are natively supported by the virtual machine. These
-    /// are not meant to be used by foreign functions, but
-    /// they CAN be used without worry.
-    /// ####################################################
+    // ####################################################
+    // The following functions represent instructions that
+    // are natively supported by the virtual machine. These
+    // are not meant to be used by foreign functions, but
+    // they CAN be used without worry.
+    // ####################################################
 
     /// This function duplicates the current machine. This is
     /// VERY IMPORTANT. It iterates through the stack and copies
@@ -63,40 +425,230 @@ impl Machine {
     ///
     /// The addition of this method fixes the memory leak.
     pub fn duplicate(self) -> Self {
+        self.context()
+    }
+
+    /// Make the context for a function, like `Machine::duplicate`,
+    /// without cloning the machine first. A machine that is running
+    /// a program holds its call frames and collector, which would
+    /// make defining a function slower the deeper it is called.
+    pub(crate) fn context(&self) -> Self {
         let mut new = Self::new();
         // Copy the stack for the new machine
-        for item in self.stack {
-            new.push((*item).clone().copy());
+        for item in &self.stack {
+            new.push(item.copy());
         }
 
-        // Copy the registers for the new machine
-        for (key, value) in self.registers {
-            new.registers.insert(key.to_string(), value.copy());
+        // Copy the registers for the new machine, including the
+        // ones declared in blocks, which shadow the outer ones
+        for scope in core::iter::once(&self.registers).chain(&self.scopes) {
+            for (key, value) in scope {
+                new.registers.insert(key.clone(), value.copy());
+            }
         }
 
         // Return new machine
         new
     }
 
+    /// Make a new machine with copies of only the named registers,
+    /// as they are seen from the current scope. This is the context
+    /// of closures, and unlike `Machine::duplicate` its cost doesn't
+    /// depend on the size of the stack or the number of registers.
+    ///
+    /// Names that aren't registers are left out, so loading them
+    /// in the closure raises UndefinedRegister.
+    pub fn capture<S: AsRef<str>>(&self, names: &[S]) -> Self {
+        let mut new = Self::new();
+        for name in names {
+            let name = name.as_ref();
+            if let Some(value) = self.lookup(name) {
+                new.registers.insert(name.to_string(), value.copy());
+            }
+        }
+        new
+    }
+
+    /// Run each instruction in a program in order.
+    /// If an instruction raises an error, the error is pushed
+    /// onto the stack as an Error value and execution carries on.
+    /// Running out of fuel pushes OutOfFuel, and going over a
+    /// memory limit pushes MemoryLimit, and both stop the program.
+    pub fn run(&mut self, program: &[Instruction]) {
+        for (offset, instruction) in program.iter().enumerate() {
+            if let Err(e) = self.limits.try_use_fuel() {
+                self.push(Ref::new(e.at(offset, instruction).into()));
+                return;
+            }
+            self.maybe_collect_garbage();
+            self.set_offset(offset);
+            self.execute(instruction);
+            if let Err(e) = self.try_check_limits() {
+                self.push(Ref::new(e.at(offset, instruction).into()));
+                return;
+            }
+        }
+    }
+
+    /// Fail if the stack holds too many values,
+    /// or too much memory has been allocated
+    pub(crate) fn try_check_limits(&self) -> Result<(), MachineError> {
+        self.limits.try_check(self.stack.len())
+    }
+
+    /// Run each instruction in a program in order, stopping at
+    /// the first instruction that raises an error. The error
+    /// records the offset of the instruction that raised it.
+    pub fn try_run(&mut self, program: &[Instruction]) -> Result<(), MachineError> {
+        self.try_run_code(Rc::from(program))
+    }
+
+    /// Run a block of instructions, like `Machine::try_run`
+    pub(crate) fn try_run_code(&mut self, code: Rc<[Instruction]>) -> Result<(), MachineError> {
+        // Programs run by the host get a frame of their
+        // own, and are paused if they run out of fuel
+        if self.frames.is_empty() {
+            self.paused = None;
+            return self.run_main(Task::new(code));
+        }
+        Task::new(code).run(self, false)
+    }
+
+    /// Run a program for the host, in a frame of its own
+    fn run_main(&mut self, task: Task) -> Result<(), MachineError> {
+        self.in_frame(String::from("<main>"), |machine| {
+            let result = task.run(machine, true);
+            machine.catch_return(result)
+        })
+    }
+
+    /// Run a single instruction by calling the Machine
+    /// method that it corresponds to
+    pub fn execute(&mut self, instruction: &Instruction) {
+        self.lenient(|machine| machine.try_execute(instruction))
+    }
+
+    /// Run a single instruction by calling the fallible
+    /// Machine method that it corresponds to
+    pub fn try_execute(&mut self, instruction: &Instruction) -> Result<(), MachineError> {
+        match instruction {
+            Instruction::Push(literal) => {
+                let value = match literal {
+                    Literal::String(s) => Value::string(s),
+                    Literal::Number(n) => Value::number(*n),
+                    Literal::Int(n) => Value::int(*n),
+                    Literal::Bool(b) => Value::boolean(*b),
+                    Literal::List => Value::list(),
+                    Literal::Tree => Value::tree(),
+                    Literal::None => Value::none(),
+                    Literal::Function(code) => Value::program(code.clone(), self),
+                    Literal::Closure(closure) => {
+                        let (captures, code) = &**closure;
+                        Value::program_closure(code.clone(), self, captures)
+                    }
+                };
+                self.push(value);
+                Ok(())
+            }
+            Instruction::Copy => self.try_copy(),
+            Instruction::Assign => self.try_assign(),
+            Instruction::Index => self.try_index(),
+            Instruction::Slice => self.try_slice(),
+            Instruction::Len => self.try_len(),
+            Instruction::Range => self.try_range(),
+            Instruction::MethodCall => self.try_method_call(),
+            Instruction::Call => self.try_call(),
+            Instruction::ForLoop => self.try_for_loop(),
+            Instruction::WhileLoop => self.try_while_loop(),
+            Instruction::IfThenElse => self.try_if_then_else(),
+            Instruction::Store => self.try_store(),
+            Instruction::Load => self.try_load(),
+            Instruction::Throw => self.try_throw(),
+            Instruction::TryCatch => self.try_try_catch(),
+            Instruction::Break => self.try_break_loop(),
+            Instruction::Continue => self.try_continue_loop(),
+            Instruction::Return => self.try_return_early(),
+            Instruction::TailCall => self.try_tail_call(),
+            Instruction::Declare => self.try_declare(),
+            Instruction::PushScope => {
+                self.push_scope();
+                Ok(())
+            }
+            Instruction::PopScope => self.try_pop_scope(),
+            Instruction::Share => self.try_share(),
+            Instruction::Downgrade => self.try_downgrade(),
+            Instruction::Upgrade => self.try_upgrade(),
+            Instruction::Coroutine => self.try_coroutine(),
+            Instruction::Resume => self.try_resume(),
+            Instruction::Yield => self.try_yield_value(),
+            Instruction::Add => self.try_add(),
+            Instruction::Sub => self.try_sub(),
+            Instruction::Mul => self.try_mul(),
+            Instruction::Div => self.try_div(),
+            Instruction::Rem => self.try_rem(),
+            Instruction::Neg => self.try_neg(),
+            Instruction::Not => self.try_not(),
+            Instruction::Eq => self.try_eq(),
+            Instruction::Ne => self.try_ne(),
+            Instruction::Lt => self.try_lt(),
+            Instruction::Le => self.try_le(),
+            Instruction::Gt => self.try_gt(),
+            Instruction::Ge => self.try_ge(),
+            Instruction::And => self.try_and(),
+            Instruction::Or => self.try_or(),
+        }
+    }
+
+    /// Run a fallible instruction, and push the error
+    /// onto the stack as an Error value if it fails
+    fn lenient(&mut self, instruction: impl FnOnce(&mut Self) -> Result<(), MachineError>) {
+        if let Err(e) = instruction(self) {
+            self.push(Ref::new(e.escaped().into()));
+        }
+    }
+
     /// Push an item onto the stack
     pub fn push(&mut self, value: Ref<Value>) {
+        self.collector.track(&value);
+        // Values that nothing else refers to yet were just made
+        if Ref::is_unique(&value) {
+            self.limits.allocate(&value);
+        }
         self.stack.push(value);
     }
 
     /// Pop an item off of the stack, and return it
     /// If the stack is empty, return an Error
     pub fn pop(&mut self) -> Ref<Value> {
+        match self.try_pop() {
+            Ok(v) => v,
+            Err(e) => Ref::new(e.into()),
+        }
+    }
+
+    /// Pop an item off of the stack, and return it
+    /// If the stack is empty, fail with a StackUnderflow
+    pub fn try_pop(&mut self) -> Result<Ref<Value>, MachineError> {
         match self.stack.pop() {
-            Some(v) => v,
-            None => Value::error("Popped from empty stack, called function with too few arguments"),
+            Some(v) => Ok(v),
+            None => Err(MachineError::new(
+                ErrorKind::StackUnderflow,
+                "Popped from empty stack, called function with too few arguments",
+            )),
         }
     }
 
     /// 1) Pop off a REFERENCE value from the stack
     /// 2) Push a copy the object and remove the reference
     pub fn copy(&mut self) {
-        let value = self.pop();
+        self.lenient(Self::try_copy)
+    }
+
+    /// The fallible version of `Machine::copy`
+    pub fn try_copy(&mut self) -> Result<(), MachineError> {
+        let value = self.try_pop()?;
         self.push(value.copy());
+        Ok(())
     }
 
     /// 1) Pop off a REFERENCE value from the stack
@@ -105,40 +657,166 @@ impl Machine {
     ///
     /// This can be used to assign to an indexed value from a list or table
     pub fn assign(&mut self) {
-        let reference = self.pop();
-        let value = self.pop();
+        self.lenient(Self::try_assign)
+    }
 
-        // We cant 'safely' modify a shared reference to a value,
-        // so we need to convert to a mutable pointer in an unsafe block
-        unsafe {
-            // Take the Ref and convert it to a mutable pointer
-            let ptr = Ref::into_raw(reference) as *mut Value;
-            // Assign to the contents of the mutable pointer
-            *ptr = (*value).clone();
-            // Re-wrap the pointer in a Ref value to properly manage it again
-            Ref::from_raw(ptr as *const Value);
-        }
+    /// The fallible version of `Machine::assign`
+    pub fn try_assign(&mut self) -> Result<(), MachineError> {
+        let reference = self.try_pop()?;
+        let value = self.try_pop()?.get();
+
+        // Every Ref that shares this memory location sees the new value
+        reference.replace(value);
+        // The location might hold a list or tree now, which could be a cycle
+        self.collector.track(&reference);
+        Ok(())
+    }
+
+    /// 1) Pop off a REFERENCE value from the stack
+    /// 2) Push a Weak value that refers to the memory location of
+    ///    REFERENCE, without keeping it alive
+    ///
+    /// This can be used for links back to a parent, or to observers
+    pub fn downgrade(&mut self) {
+        self.lenient(Self::try_downgrade)
+    }
+
+    /// The fallible version of `Machine::downgrade`
+    pub fn try_downgrade(&mut self) -> Result<(), MachineError> {
+        let reference = self.try_pop()?;
+        self.push(Value::weak(&reference));
+        Ok(())
+    }
+
+    /// 1) Pop off a VALUE from the stack
+    /// 2) If VALUE is a Weak value, push the reference it refers to,
+    ///    or None if the value it referred to has been dropped
+    /// 3) Otherwise, push VALUE back onto the stack
+    pub fn upgrade(&mut self) {
+        self.lenient(Self::try_upgrade)
+    }
+
+    /// The fallible version of `Machine::upgrade`
+    pub fn try_upgrade(&mut self) -> Result<(), MachineError> {
+        let value = self.try_pop()?;
+        let upgraded = match &*value.borrow() {
+            Value::Weak(w) => Some(w.upgrade().unwrap_or_else(Value::none)),
+            _ => None,
+        };
+        self.push(upgraded.unwrap_or(value));
+        Ok(())
     }
 
     /// 1) Pop off the INDEX value from the stack
     /// 2) Pop off a TABLE value from the stack
     /// 3) Push the TABLE[INDEX] reference onto the stack
     pub fn index(&mut self) {
-        let index = self.pop();
-        let table = self.pop();
-
-        let result;
-        // We cant 'safely' modify a shared reference to a value,
-        // so we need to convert to a mutable pointer in an unsafe block
-        unsafe {
-            // Take the Ref and convert it to a mutable pointer
-            let ptr = Ref::into_raw(table) as *mut Value;
-            // Get the indexed value from the pointer to the table in memory
-            result = (*ptr).index(index);
-            // Re-wrap the pointer in a Ref value to properly manage it again
-            Ref::from_raw(ptr as *const Value);
-        }
+        self.lenient(Self::try_index)
+    }
+
+    /// The fallible version of `Machine::index`
+    pub fn try_index(&mut self) -> Result<(), MachineError> {
+        // Get the key before borrowing the table, in
+        // case the index and the table are the same value
+        let index = self.try_pop()?.get();
+        let table = self.try_pop()?;
+        let index = Key::try_from(&index)?;
+
+        // Make sure the table can grow before it does
+        self.limits.try_grow(&table.borrow(), &index)?;
+        // Get the indexed value from the table in memory
+        let result = table.borrow_mut().try_index(index)?;
+        self.push(result);
+        Ok(())
+    }
+
+    /// 1) Pop off an END value from the stack
+    /// 2) Pop off a START value from the stack
+    /// 3) Pop off a VALUE from the stack
+    /// 4) Push the part of VALUE from START up to END, which is
+    ///    the rest of VALUE if END is None
+    pub fn slice(&mut self) {
+        self.lenient(Self::try_slice)
+    }
+
+    /// The fallible version of `Machine::slice`
+    pub fn try_slice(&mut self) -> Result<(), MachineError> {
+        let end = self.try_pop()?.get();
+        let start = Self::try_offset(&self.try_pop()?.get())?;
+        let value = self.try_pop()?;
+        let value = value.borrow();
+
+        let end = match (end, &*value) {
+            (Value::None, Value::Bytes(b)) => b.len(),
+            (Value::None, Value::List(l)) => l.len(),
+            (Value::None, Value::String(s)) => s.chars().count(),
+            (end, _) => Self::try_offset(&end)?,
+        };
+        let result = value.try_slice(start, end)?;
         self.push(result);
+        Ok(())
+    }
+
+    /// Convert a value to an offset into a list, string or bytes
+    fn try_offset(value: &Value) -> Result<usize, MachineError> {
+        match *value {
+            Value::Int(n) if n >= 0 => Ok(n as usize),
+            Value::Number(n) if n >= 0.0 && n as usize as f64 == n => Ok(n as usize),
+            _ => Err(MachineError::new(
+                ErrorKind::InvalidIndex,
+                format!("Can't slice with {}", value),
+            )),
+        }
+    }
+
+    /// 1) Pop off an END value from the stack
+    /// 2) Pop off a START value from the stack
+    /// 3) Push a range of the Ints from START up to, but not
+    ///    including, END
+    pub fn range(&mut self) {
+        self.lenient(Self::try_range)
+    }
+
+    /// The fallible version of `Machine::range`
+    pub fn try_range(&mut self) -> Result<(), MachineError> {
+        let end = self.try_pop()?.get();
+        let start = self.try_pop()?.get();
+        match (&start, &end) {
+            (Value::Int(start), Value::Int(end)) => {
+                self.push(Value::range(*start, *end));
+                Ok(())
+            }
+            _ => Err(MachineError::new(
+                ErrorKind::Arithmetic,
+                format!("Can't make a range from {} to {}", start, end),
+            )),
+        }
+    }
+
+    /// 1) Pop off a VALUE from the stack
+    /// 2) Push the number of elements in a list or tree, the
+    ///    number of characters in a string or the number of bytes
+    pub fn len(&mut self) {
+        self.lenient(Self::try_len)
+    }
+
+    /// The fallible version of `Machine::len`
+    pub fn try_len(&mut self) -> Result<(), MachineError> {
+        let length = match &*self.try_pop()?.borrow() {
+            Value::Bytes(b) => b.len(),
+            Value::List(l) => l.len(),
+            Value::Tree(t) => t.len(),
+            Value::Range(start, end) => end.saturating_sub(*start).max(0) as usize,
+            Value::String(s) => s.chars().count(),
+            value => {
+                return Err(MachineError::new(
+                    ErrorKind::InvalidIndex,
+                    format!("Can't get the length of {}", value),
+                ))
+            }
+        };
+        self.push(Value::int(length as i64));
+        Ok(())
     }
 
     /// 1) Pop off the INDEX value from the stack
@@ -146,26 +824,47 @@ impl Machine {
     /// 3) Push the TABLE onto the stack
     /// 4) Call the value at TABLE[INDEX] as a function
     pub fn method_call(&mut self) {
-        let index = self.pop();
-        let table = self.pop();
+        self.lenient(Self::try_method_call)
+    }
+
+    /// The fallible version of `Machine::method_call`
+    pub fn try_method_call(&mut self) -> Result<(), MachineError> {
+        let (function, name) = self.try_method()?;
+        function.try_call_as(self, Some(name))
+    }
+
+    /// Get the method to call for `Machine::method_call`, with the
+    /// name of its call frame, after pushing the `self` value
+    pub(crate) fn try_method(&mut self) -> Result<(Value, String), MachineError> {
+        let index = self.try_pop()?;
+        let table = self.try_pop()?;
 
         // This is the `self` value to be passed to the function
         // The `self` value cannot be directly assigned to,
         // HOWEVER, its members / attributes can be assigned to
         self.push(Ref::clone(&table));
         self.push(table);
-        self.push(index);
-        self.index();
-        self.call();
+        self.push(Ref::clone(&index));
+        self.try_index()?;
+
+        // The call frame is named after the method
+        let function = self.try_pop()?.get();
+        Ok((function, index.to_string()))
     }
 
     /// 1) Pop off function from the stack
     /// 2) Call it with this Machine instance
     pub fn call(&mut self) {
-        let function = self.pop();
-        function.call(self);
+        self.lenient(Self::try_call)
     }
 
+    /// The fallible version of `Machine::call`
+    pub fn try_call(&mut self) -> Result<(), MachineError> {
+        // Clone the function out of its Ref so that the function
+        // can assign to the memory location it was stored in
+        let function = self.try_pop()?.get();
+        function.try_call(self)
+    }
 
     /// 1) Pop off a COUNTER identifier from the stack
     /// 2) Pop off an ELEMENT identifier from the stack
@@ -175,16 +874,54 @@ impl Machine {
     /// 6)   Store LIST[COUNTER] in ELEMENT
     /// 7)   Call BODY with current instance
     /// 8)   Increment COUNTER
+    ///
+    /// LIST can also be a string, bytes or a range. For a tree,
+    /// COUNTER is each key and ELEMENT is the value of the key.
+    /// A tree with an `__iter__` method is iterated by calling
+    /// it to get an iterator, and then calling the `next` method
+    /// of the iterator. `next` pushes the next element and then
+    /// `true`, or only pushes `false` when there are no more.
+    /// A coroutine is resumed with None for each ELEMENT it
+    /// yields, until it finishes.
     pub fn for_loop(&mut self) {
-        let counter_name = self.pop();
-        let element_name = self.pop();
-        let iterator = (*self.pop()).clone();
-        let body = self.pop();
+        self.lenient(Self::try_for_loop)
+    }
+
+    /// The fallible version of `Machine::for_loop`
+    pub fn try_for_loop(&mut self) -> Result<(), MachineError> {
+        let counter_name = self.try_pop()?.to_string();
+        let element_name = self.try_pop()?.to_string();
+        let iterable = self.try_pop()?;
+        let body = self.try_pop()?.get();
+
+        // Each iteration declares the element and counter in a
+        // scope of its own, so they don't replace the caller's registers.
+        // Returns false if the body signalled `break`.
+        let visit = |machine: &mut Self, counter: Ref<Value>, element: Ref<Value>| {
+            machine.in_scope(|machine| {
+                machine.declare_register(element_name.clone(), element);
+                machine.declare_register(counter_name.clone(), counter);
+                machine.try_loop_body(&body)
+            })
+        };
 
-        for (index, item) in iterator.into_iter().enumerate() {
-            self.registers.insert(element_name.to_string(), item);
-            self.registers.insert(counter_name.to_string(), Value::number(index as f64));
-            body.call_global(self);
+        let mut iteration = Iteration::new(self, iterable)?;
+        while let Some((counter, element)) = iteration.next(self)? {
+            if !visit(self, counter, element)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the body of a loop, and return false if the loop should stop
+    /// because the body signalled `break`. A `continue` skips the rest of
+    /// the body, and any other signal or error stops the loop and is raised.
+    fn try_loop_body(&mut self, body: &Value) -> Result<bool, MachineError> {
+        match body.try_call_global(self) {
+            Err(e) if e.kind == ErrorKind::Break => Ok(false),
+            Err(e) if e.kind == ErrorKind::Continue => Ok(true),
+            result => result.map(|()| true),
         }
     }
 
@@ -194,19 +931,29 @@ impl Machine {
     /// 4) If the return value is true, run the BODY function with the context of this instance
     /// 5) Goto step 3
     pub fn while_loop(&mut self) {
-        let condition = self.pop();
-        let body = self.pop();
+        self.lenient(Self::try_while_loop)
+    }
+
+    /// The fallible version of `Machine::while_loop`
+    pub fn try_while_loop(&mut self) -> Result<(), MachineError> {
+        let condition = self.try_pop()?.get();
+        let body = self.try_pop()?.get();
         // This will take the top item of the stack and convert it to a bool
-        let get_condition = |machine: &mut Machine| -> bool { (*machine.pop()).clone().into() };
+        let get_condition = |machine: &mut Machine| -> Result<bool, MachineError> {
+            Ok(machine.try_pop()?.get().into())
+        };
 
         // First, get the condition
-        condition.call_global(self);
-        while get_condition(self) {
+        condition.try_call_global(self)?;
+        while get_condition(self)? {
             // If the condition is true, run the body of the while loop
-            body.call_global(self);
+            if !self.in_scope(|machine| machine.try_loop_body(&body))? {
+                break;
+            }
             // Push the condition again to test on the next iteration
-            condition.call_global(self);
+            condition.try_call_global(self)?;
         }
+        Ok(())
     }
 
     /// 1) Pop off a CONDITION function from the stack
@@ -216,48 +963,647 @@ impl Machine {
     /// 5) If the return value is true, run the THEN function with the context of this instance
     /// 6) If the return value is false, run the ELSE function with the context of this instance
     pub fn if_then_else(&mut self) {
-        let condition = self.pop();
-        let then_fn = self.pop();
-        let else_fn = self.pop();
+        self.lenient(Self::try_if_then_else)
+    }
 
-        // This will take the top item of the stack and convert it to a bool
-        let get_condition = |machine: &mut Machine| -> bool { (*machine.pop()).clone().into() };
+    /// The fallible version of `Machine::if_then_else`
+    pub fn try_if_then_else(&mut self) -> Result<(), MachineError> {
+        let condition = self.try_pop()?.get();
+        let then_fn = self.try_pop()?.get();
+        let else_fn = self.try_pop()?.get();
 
         // First, get the condition
-        condition.call_global(self);
-        if get_condition(self) {
-            // If the condition is true, run the body of the while loop
-            then_fn.call_global(self);
+        condition.try_call_global(self)?;
+        if self.try_pop()?.get().into() {
+            // If the condition is true, run the THEN function
+            self.in_scope(|machine| then_fn.try_call_global(machine))
         } else {
-            // Push the condition again to test on the next iteration
-            else_fn.call_global(self);
+            // Otherwise, run the ELSE function
+            self.in_scope(|machine| else_fn.try_call_global(machine))
         }
     }
 
+    /// 1) Pop off a KIND value from the stack
+    /// 2) If KIND is an Error value, raise it again
+    /// 3) Otherwise, pop off a MESSAGE value and a PAYLOAD value
+    /// 4) Raise an error with KIND, MESSAGE and PAYLOAD,
+    ///    or without a payload if PAYLOAD is None
+    ///
+    /// Unless the error is caught with `try_catch`,
+    /// this pushes the error onto the stack
+    pub fn throw(&mut self) {
+        self.lenient(Self::try_throw)
+    }
+
+    /// The fallible version of `Machine::throw`, which always fails
+    pub fn try_throw(&mut self) -> Result<(), MachineError> {
+        let kind = self.try_pop()?.get();
+        if let Value::Error(e) = kind {
+            return Err(e.into());
+        }
+
+        let message = self.try_pop()?.to_string();
+        let payload = self.try_pop()?;
+        let payload = match *payload.borrow() {
+            Value::None => None,
+            _ => Some(Ref::clone(&payload)),
+        };
+
+        Err(Exception {
+            kind: ErrorKind::from(kind.to_string().as_str()),
+            message,
+            payload,
+        }
+        .into())
+    }
+
+    /// 1) Pop off a BODY function from the stack
+    /// 2) Pop off a HANDLER function from the stack
+    /// 3) Pop off a FINALLY function from the stack
+    /// 4) Call the BODY function with the context of this instance
+    /// 5) If BODY raises an error, drop the values that BODY pushed,
+    ///    push the error, and call the HANDLER function
+    /// 6) Call the FINALLY function, whether or not there was an error
+    ///
+    /// HANDLER and FINALLY may be None to leave them out. Without
+    /// a HANDLER, an error from BODY is raised again after FINALLY.
+    /// An error raised by HANDLER is also raised after FINALLY.
+    pub fn try_catch(&mut self) {
+        self.lenient(Self::try_try_catch)
+    }
+
+    /// The fallible version of `Machine::try_catch`, which fails
+    /// if an error escapes from the HANDLER or FINALLY functions
+    pub fn try_try_catch(&mut self) -> Result<(), MachineError> {
+        let body = self.try_pop()?.get();
+        let handler = self.try_pop()?.get();
+        let finally = self.try_pop()?.get();
+
+        // Remember how tall the stack was to unwind it on an error
+        let height = self.stack.len();
+        let mut result = self.in_scope(|machine| body.try_call_global(machine));
+        // Signals pass through to the loop or function they exit
+        let caught = matches!(&result, Err(e) if e.kind.is_catchable());
+        if let (Err(e), true) = (&result, caught && handler != Value::None) {
+            self.stack.truncate(height);
+            self.push(Ref::new(e.clone().into()));
+            result = self.in_scope(|machine| handler.try_call_global(machine));
+        }
+
+        let stopped = matches!(&result, Err(e) if e.kind == ErrorKind::OutOfFuel);
+        if finally != Value::None && !stopped {
+            self.in_scope(|machine| finally.try_call_global(machine))?;
+        }
+        result
+    }
+
+    /// Stop the innermost `for_loop` or `while_loop`.
+    /// Any `finally` blocks inside of the loop still run.
+    pub fn break_loop(&mut self) {
+        self.lenient(Self::try_break_loop)
+    }
+
+    /// The fallible version of `Machine::break_loop`, which
+    /// always fails with a Break signal for the loop to handle
+    pub fn try_break_loop(&mut self) -> Result<(), MachineError> {
+        Err(MachineError::signal(ErrorKind::Break, None))
+    }
+
+    /// Skip the rest of the body of the innermost
+    /// `for_loop` or `while_loop`, and start its next iteration
+    pub fn continue_loop(&mut self) {
+        self.lenient(Self::try_continue_loop)
+    }
+
+    /// The fallible version of `Machine::continue_loop`, which
+    /// always fails with a Continue signal for the loop to handle
+    pub fn try_continue_loop(&mut self) -> Result<(), MachineError> {
+        Err(MachineError::signal(ErrorKind::Continue, None))
+    }
+
+    /// 1) Pop off a VALUE from the stack
+    /// 2) Return from the innermost function call, even from
+    ///    inside of a loop, and push VALUE for the caller
+    pub fn return_early(&mut self) {
+        self.lenient(Self::try_return_early)
+    }
+
+    /// The fallible version of `Machine::return_early`, which
+    /// fails with a Return signal for the function call to handle
+    pub fn try_return_early(&mut self) -> Result<(), MachineError> {
+        let value = self.try_pop()?;
+        Err(MachineError::signal(ErrorKind::Return, Some(value)))
+    }
+
+    /// 1) Pop off a FUNCTION from the stack
+    /// 2) Return from the innermost function call, like `return`
+    ///    does, and call FUNCTION in its place
+    ///
+    /// FUNCTION reuses the call frame of the function that called it,
+    /// so a function can call itself as the last thing it does for
+    /// as long as it likes, without using more memory each time.
+    /// It gets the values left on the stack as its arguments, and
+    /// the values it leaves are the results of the call. Like
+    /// `return`, this leaves loops and `try_catch` blocks first,
+    /// running their FINALLY functions, so they don't catch the
+    /// errors that FUNCTION raises.
+    ///
+    /// A native function is called straight away instead,
+    /// and then the innermost function call ends.
+    pub fn tail_call(&mut self) {
+        self.lenient(Self::try_tail_call)
+    }
+
+    /// The fallible version of `Machine::tail_call`, which
+    /// fails with a TailCall signal for the function call to handle
+    pub fn try_tail_call(&mut self) -> Result<(), MachineError> {
+        let function = self.try_pop()?.get();
+        match &function {
+            Value::Function(f) if matches!(f.get_body(), Body::Code(_)) => Err(
+                MachineError::signal(ErrorKind::TailCall, Some(Ref::new(function))),
+            ),
+            _ => {
+                function.try_call(self)?;
+                Err(MachineError::signal(ErrorKind::TailCall, None))
+            }
+        }
+    }
+
+    /// 1) Pop off a FUNCTION value from the stack
+    /// 2) Push a coroutine that runs FUNCTION
+    ///
+    /// FUNCTION has to be made from instructions,
+    /// since native functions can't suspend
+    pub fn coroutine(&mut self) {
+        self.lenient(Self::try_coroutine)
+    }
+
+    /// The fallible version of `Machine::coroutine`
+    pub fn try_coroutine(&mut self) -> Result<(), MachineError> {
+        let coroutine = match self.try_pop()?.get() {
+            Value::Function(f) => Coroutine::new(&f)?,
+            other => {
+                return Err(MachineError::new(
+                    ErrorKind::NotCallable,
+                    format!("Can't make a coroutine from non-function {}", other),
+                ))
+            }
+        };
+        self.push(Ref::new(Value::Coroutine(coroutine)));
+        Ok(())
+    }
+
+    /// 1) Pop off a COROUTINE value from the stack
+    /// 2) Pop off a VALUE from the stack
+    /// 3) Push VALUE onto the stack of COROUTINE, as the argument
+    ///    of its function the first time it is resumed, and as the
+    ///    result of the `yield` that suspended it after that
+    /// 4) Run COROUTINE until it yields or returns
+    /// 5) Push the value it yielded and `true`, or the value
+    ///    it returned and `false` if it finished
+    ///
+    /// Returning without a value returns None. A coroutine that
+    /// raises an error finishes, and the error is raised here.
+    pub fn resume(&mut self) {
+        self.lenient(Self::try_resume)
+    }
+
+    /// The fallible version of `Machine::resume`
+    pub fn try_resume(&mut self) -> Result<(), MachineError> {
+        // The coroutine isn't copied out of its Ref, so
+        // it carries on from here the next time too
+        let coroutine = self.try_pop()?;
+        let value = self.try_pop()?;
+        // Coroutines resumed by the host are paused if they run out of fuel
+        let pausable = self.frames.is_empty();
+        if pausable {
+            self.paused = None;
+        }
+        Task::resume(self, coroutine, value, pausable)
+    }
+
+    /// 1) Pop off a VALUE from the stack
+    /// 2) Suspend the running coroutine, and give VALUE
+    ///    to the machine that resumed it
+    pub fn yield_value(&mut self) {
+        self.lenient(Self::try_yield_value)
+    }
+
+    /// The fallible version of `Machine::yield_value`, which
+    /// fails with a Yield signal for the coroutine to handle
+    pub fn try_yield_value(&mut self) -> Result<(), MachineError> {
+        let value = self.try_pop()?;
+        Err(MachineError::signal(ErrorKind::Yield, Some(value)))
+    }
+
     /// 1) Pop off a KEY value from the stack
     /// 2) Pop off a VALUE value from the stack
     /// 3) Assign the value of VALUE to the register named KEY
     pub fn store(&mut self) {
+        self.lenient(Self::try_store)
+    }
+
+    /// The fallible version of `Machine::store`
+    pub fn try_store(&mut self) -> Result<(), MachineError> {
         // The register to assign to
-        let key = self.pop();
+        let key = self.try_pop()?;
         // The value to assign to it
-        let value = self.pop();
+        let value = self.try_pop()?;
+        let key = key.to_string();
+
+        // Functions without a name are named after the first
+        // register they're stored in, for backtraces. The register
+        // gets a named copy, so other references to the function
+        // keep it as it was.
+        let named = match &*value.borrow() {
+            Value::Function(f) if f.get_name().is_none() => {
+                let mut f = f.clone();
+                f.set_name(key.clone());
+                Some(Ref::new(Value::Function(f)))
+            }
+            _ => None,
+        };
+        let value = named.unwrap_or(value);
 
-        // registers[key] = value
-        self.registers.insert(key.to_string(), value);
+        // Assign to the innermost register named KEY, or
+        // make a new register that outlives the current block
+        let scope = match self.scopes.iter_mut().rev().find(|s| s.contains_key(&key)) {
+            Some(scope) => scope,
+            None => &mut self.registers,
+        };
+        match scope.get(&key) {
+            // Registers shared with closures are assigned to,
+            // so that the closures see the new value
+            Some(cell) if Self::is_cell(&self.cells, cell) => {
+                cell.replace(value.get());
+                self.collector.track(cell);
+            }
+            _ => {
+                scope.insert(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    /// 1) Pop off a KEY value from the stack
+    /// 2) Pop off a VALUE value from the stack
+    /// 3) Make a new register named KEY in the current scope with the
+    ///    value of VALUE, hiding any register named KEY outside of it
+    ///
+    /// Unlike `Machine::store`, this never assigns to an outer
+    /// register, and the register is dropped when the block ends.
+    pub fn declare(&mut self) {
+        self.lenient(Self::try_declare)
+    }
+
+    /// The fallible version of `Machine::declare`
+    pub fn try_declare(&mut self) -> Result<(), MachineError> {
+        let key = self.try_pop()?.to_string();
+        let value = self.try_pop()?;
+        self.declare_register(key, value);
+        Ok(())
+    }
+
+    /// Make a register in the innermost scope, which
+    /// is the registers of the machine outside of any block
+    pub(crate) fn declare_register(&mut self, key: String, value: Ref<Value>) {
+        match self.scopes.last_mut() {
+            Some(scope) => scope.insert(key, value),
+            None => self.registers.insert(key, value),
+        };
+    }
+
+    /// 1) Pop off a NAME value from the stack
+    /// 2) Pop off a FUNCTION value from the stack
+    /// 3) Share the register named NAME with FUNCTION, instead of
+    ///    the copy of it that FUNCTION captured when it was made
+    /// 4) Push FUNCTION back onto the stack
+    ///
+    /// Storing to a shared register, in FUNCTION or in this machine,
+    /// assigns to it, so the change is seen by both of them and by
+    /// every other function it is shared with.
+    pub fn share(&mut self) {
+        self.lenient(Self::try_share)
+    }
+
+    /// The fallible version of `Machine::share`
+    pub fn try_share(&mut self) -> Result<(), MachineError> {
+        let name = self.try_pop()?.to_string();
+        let function = self.try_pop()?;
+
+        let cell = match self.lookup(&name) {
+            Some(cell) => Ref::clone(cell),
+            None => {
+                return Err(MachineError::new(
+                    ErrorKind::UndefinedRegister,
+                    format!("No register named {}", name),
+                ))
+            }
+        };
+
+        // The value is printed without borrowing it mutably,
+        // since it may contain itself
+        if !matches!(*function.borrow(), Value::Function(_)) {
+            return Err(MachineError::new(
+                ErrorKind::NotCallable,
+                format!("Can't share {} with non-function {}", name, function),
+            ));
+        }
+        if let Value::Function(f) = &mut *function.borrow_mut() {
+            f.share(name, Ref::clone(&cell));
+        }
+        self.add_cell(cell);
+        self.push(function);
+        Ok(())
+    }
+
+    /// Start a new scope. The registers declared until the
+    /// matching `Machine::pop_scope` are dropped when it ends.
+    pub fn push_scope(&mut self) {
+        self.scopes.push(BTreeMap::new());
+    }
+
+    /// End the innermost scope, and drop the registers declared in it
+    pub fn pop_scope(&mut self) {
+        self.lenient(Self::try_pop_scope)
+    }
+
+    /// The fallible version of `Machine::pop_scope`,
+    /// which fails if there is no scope to end
+    pub fn try_pop_scope(&mut self) -> Result<(), MachineError> {
+        match self.scopes.pop() {
+            Some(_) => Ok(()),
+            None => Err(MachineError::new(
+                ErrorKind::StackUnderflow,
+                "No scope to pop",
+            )),
+        }
     }
 
     /// 1) Pop off a KEY value from the stack
     /// 2) Push the value in the register named KEY to the stack
     pub fn load(&mut self) {
-        let key = &self.pop().to_string();
+        self.lenient(Self::try_load)
+    }
 
-        // The reason we don't do an if-let expression here is the fact
-        // that we can't borrow self as both mutable and immutable at once
-        if self.registers.contains_key(key) {
-            self.push(Ref::clone(self.registers.get(key).unwrap()));
+    /// The fallible version of `Machine::load`
+    pub fn try_load(&mut self) -> Result<(), MachineError> {
+        let key = &self.try_pop()?.to_string();
+
+        // The register is cloned out of the scope before pushing, because
+        // we can't borrow self as both mutable and immutable at once
+        if let Some(value) = self.lookup(key).cloned() {
+            // A shared register is loaded as a new reference to its value,
+            // so storing to the register later doesn't change what we pushed.
+            // Values that are changed in place are loaded as the register
+            // itself, so that the changes are seen through the register.
+            let in_place = matches!(
+                *value.borrow(),
+                Value::List(_) | Value::Tree(_) | Value::Function(_) | Value::Coroutine(_)
+            );
+            if Self::is_cell(&self.cells, &value) && !in_place {
+                self.push(Ref::new(value.get()));
+            } else {
+                self.push(value);
+            }
+            Ok(())
         } else {
-            self.push(Value::error(format!("No register named {}", key)));
+            Err(MachineError::new(
+                ErrorKind::UndefinedRegister,
+                format!("No register named {}", key),
+            ))
+        }
+    }
+
+    // ####################################################
+    // The following instructions apply the operators that
+    // Value implements. Binary operators take their right
+    // hand side from the top of the stack, so `a b sub`
+    // computes `a - b`. Comparisons and logic push Bools,
+    // and logic uses the truthiness of `From<Value> for bool`.
+    // ####################################################
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push LHS + RHS
+    pub fn add(&mut self) {
+        self.lenient(Self::try_add)
+    }
+
+    /// The fallible version of `Machine::add`
+    pub fn try_add(&mut self) -> Result<(), MachineError> {
+        let rhs = self.try_pop()?.get();
+        let lhs = self.try_pop()?.get();
+
+        // Make sure concatenated values fit the limits before they are made
+        self.limits.try_check_concat(&lhs, &rhs)?;
+        self.try_push_result(lhs + rhs)
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push LHS - RHS
+    pub fn sub(&mut self) {
+        self.lenient(Self::try_sub)
+    }
+
+    /// The fallible version of `Machine::sub`
+    pub fn try_sub(&mut self) -> Result<(), MachineError> {
+        self.try_binary(|lhs, rhs| lhs - rhs)
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push LHS * RHS, which repeats LHS if it is a String.
+    ///    Strings can only be repeated a whole number of times.
+    pub fn mul(&mut self) {
+        self.lenient(Self::try_mul)
+    }
+
+    /// The fallible version of `Machine::mul`
+    pub fn try_mul(&mut self) -> Result<(), MachineError> {
+        let rhs = self.try_pop()?.get();
+        let lhs = self.try_pop()?.get();
+
+        // Make sure a repeated string fits the limits before it is made.
+        // Multiplying fails by itself if there isn't enough memory.
+        if let Value::String(s) = &lhs {
+            let times = match rhs {
+                Value::Int(n) => usize::try_from(n).unwrap_or(0),
+                Value::Number(n) => n as usize,
+                _ => 0,
+            };
+            self.limits
+                .try_check_string(s.len().saturating_mul(times))?;
+        }
+        self.try_push_result(lhs * rhs)
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push LHS / RHS
+    pub fn div(&mut self) {
+        self.lenient(Self::try_div)
+    }
+
+    /// The fallible version of `Machine::div`
+    pub fn try_div(&mut self) -> Result<(), MachineError> {
+        self.try_binary(|lhs, rhs| lhs / rhs)
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push the remainder of LHS / RHS
+    pub fn rem(&mut self) {
+        self.lenient(Self::try_rem)
+    }
+
+    /// The fallible version of `Machine::rem`
+    pub fn try_rem(&mut self) -> Result<(), MachineError> {
+        self.try_binary(|lhs, rhs| lhs % rhs)
+    }
+
+    /// 1) Pop off a VALUE from the stack
+    /// 2) Push -VALUE
+    pub fn neg(&mut self) {
+        self.lenient(Self::try_neg)
+    }
+
+    /// The fallible version of `Machine::neg`
+    pub fn try_neg(&mut self) -> Result<(), MachineError> {
+        let value = self.try_pop()?.get();
+        self.try_push_result(-value)
+    }
+
+    /// 1) Pop off a VALUE from the stack
+    /// 2) Push true if VALUE is a false value, or false otherwise
+    pub fn not(&mut self) {
+        self.lenient(Self::try_not)
+    }
+
+    /// The fallible version of `Machine::not`
+    pub fn try_not(&mut self) -> Result<(), MachineError> {
+        let value = self.try_pop()?.get();
+        self.try_push_result(!value)
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push true if LHS == RHS, or false otherwise
+    pub fn eq(&mut self) {
+        self.lenient(Self::try_eq)
+    }
+
+    /// The fallible version of `Machine::eq`
+    pub fn try_eq(&mut self) -> Result<(), MachineError> {
+        self.try_binary(|lhs, rhs| Value::from(lhs == rhs))
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push true if LHS != RHS, or false otherwise
+    pub fn ne(&mut self) {
+        self.lenient(Self::try_ne)
+    }
+
+    /// The fallible version of `Machine::ne`
+    pub fn try_ne(&mut self) -> Result<(), MachineError> {
+        self.try_binary(|lhs, rhs| Value::from(lhs != rhs))
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push true if LHS < RHS, or false otherwise
+    pub fn lt(&mut self) {
+        self.lenient(Self::try_lt)
+    }
+
+    /// The fallible version of `Machine::lt`
+    pub fn try_lt(&mut self) -> Result<(), MachineError> {
+        self.try_binary(|lhs, rhs| Value::from(lhs < rhs))
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push true if LHS <= RHS, or false otherwise
+    pub fn le(&mut self) {
+        self.lenient(Self::try_le)
+    }
+
+    /// The fallible version of `Machine::le`
+    pub fn try_le(&mut self) -> Result<(), MachineError> {
+        self.try_binary(|lhs, rhs| Value::from(lhs <= rhs))
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push true if LHS > RHS, or false otherwise
+    pub fn gt(&mut self) {
+        self.lenient(Self::try_gt)
+    }
+
+    /// The fallible version of `Machine::gt`
+    pub fn try_gt(&mut self) -> Result<(), MachineError> {
+        self.try_binary(|lhs, rhs| Value::from(lhs > rhs))
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push true if LHS >= RHS, or false otherwise
+    pub fn ge(&mut self) {
+        self.lenient(Self::try_ge)
+    }
+
+    /// The fallible version of `Machine::ge`
+    pub fn try_ge(&mut self) -> Result<(), MachineError> {
+        self.try_binary(|lhs, rhs| Value::from(lhs >= rhs))
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push true if LHS and RHS are both true values, or false otherwise
+    pub fn and(&mut self) {
+        self.lenient(Self::try_and)
+    }
+
+    /// The fallible version of `Machine::and`
+    pub fn try_and(&mut self) -> Result<(), MachineError> {
+        self.try_binary(|lhs, rhs| Value::from(bool::from(lhs) && bool::from(rhs)))
+    }
+
+    /// 1) Pop off a RHS value from the stack
+    /// 2) Pop off a LHS value from the stack
+    /// 3) Push true if either LHS or RHS is a true value, or false otherwise
+    pub fn or(&mut self) {
+        self.lenient(Self::try_or)
+    }
+
+    /// The fallible version of `Machine::or`
+    pub fn try_or(&mut self) -> Result<(), MachineError> {
+        self.try_binary(|lhs, rhs| Value::from(bool::from(lhs) || bool::from(rhs)))
+    }
+
+    /// Pop off the RHS and then the LHS of a binary operator,
+    /// and push the result of applying the operator to them
+    fn try_binary(
+        &mut self,
+        operator: impl FnOnce(Value, Value) -> Value,
+    ) -> Result<(), MachineError> {
+        let rhs = self.try_pop()?.get();
+        let lhs = self.try_pop()?.get();
+        self.try_push_result(operator(lhs, rhs))
+    }
+
+    /// Push the result of an operator, or raise it
+    /// if the operator failed and returned an Error
+    fn try_push_result(&mut self, result: Value) -> Result<(), MachineError> {
+        match result {
+            Value::Error(e) => Err(e.into()),
+            value => {
+                self.limits.try_check_value(&value)?;
+                self.push(Ref::new(value));
+                Ok(())
+            }
         }
     }
 }
@@ -268,8 +1614,8 @@ impl Display for Machine {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         write!(
             f,
-            "Machine {{\n\tstack: {:?}\n\theap:  {:?}\n}}",
-            self.stack, self.registers
+            "Machine {{\n\tstack: {:?}\n\theap:  {:?}\n\tscopes: {:?}\n}}",
+            self.stack, self.registers, self.scopes
         )
     }
 }
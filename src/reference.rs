@@ -0,0 +1,146 @@
+use alloc::rc::{Rc, Weak};
+use core::cell::{self, BorrowError, RefCell};
+// For implementing Display and Debug
+use core::cmp::Ordering;
+use core::fmt::{Debug, Display, Error, Formatter};
+
+/// A shared, mutable reference to a value.
+///
+/// Cloning a Ref gives another reference to the same value,
+/// so assigning through one Ref is seen by all of its clones.
+/// This is how the Machine gives values reference semantics:
+/// the items of lists and the members of trees are Refs, so
+/// assigning to an indexed value changes the collection.
+pub struct Ref<T>(Rc<RefCell<T>>);
+
+impl<T> Ref<T> {
+    /// Create a new reference to a value
+    pub fn new(value: T) -> Self {
+        Self(Rc::new(RefCell::new(value)))
+    }
+
+    /// Immutably borrow the referenced value.
+    /// This panics if the value is currently mutably borrowed.
+    pub fn borrow(&self) -> cell::Ref<'_, T> {
+        self.0.borrow()
+    }
+
+    /// Mutably borrow the referenced value.
+    /// This panics if the value is currently borrowed.
+    pub fn borrow_mut(&self) -> cell::RefMut<'_, T> {
+        self.0.borrow_mut()
+    }
+
+    /// Replace the referenced value, and return the old one
+    pub fn replace(&self, value: T) -> T {
+        self.0.replace(value)
+    }
+
+    /// Returns true if both Refs refer to the same value
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Rc::ptr_eq(&this.0, &other.0)
+    }
+
+    /// Returns true if this is the only Ref to its value
+    pub(crate) fn is_unique(this: &Self) -> bool {
+        Rc::strong_count(&this.0) == 1
+    }
+
+    /// The number of Refs to this value
+    pub(crate) fn strong_count(this: &Self) -> usize {
+        Rc::strong_count(&this.0)
+    }
+
+    /// The address of the referenced value, which
+    /// identifies it while there are Refs to it
+    pub(crate) fn address(this: &Self) -> usize {
+        Rc::as_ptr(&this.0) as *const () as usize
+    }
+
+    /// Immutably borrow the referenced value,
+    /// or fail if it is currently mutably borrowed
+    pub(crate) fn try_borrow(&self) -> Result<cell::Ref<'_, T>, BorrowError> {
+        self.0.try_borrow()
+    }
+
+    /// Make a reference that doesn't keep the value alive
+    pub fn downgrade(this: &Self) -> WeakRef<T> {
+        WeakRef(Rc::downgrade(&this.0))
+    }
+}
+
+/// A reference to a value that doesn't keep it alive,
+/// made with `Ref::downgrade`
+pub struct WeakRef<T>(Weak<RefCell<T>>);
+
+impl<T> WeakRef<T> {
+    /// Get a Ref to the value, if it is still alive
+    pub fn upgrade(&self) -> Option<Ref<T>> {
+        self.0.upgrade().map(Ref)
+    }
+
+    /// Returns true if the value has been dropped
+    pub fn is_dropped(&self) -> bool {
+        self.0.strong_count() == 0
+    }
+
+    /// Returns true if both WeakRefs refer to the same value
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        Weak::ptr_eq(&this.0, &other.0)
+    }
+
+    /// The address of the referenced value
+    pub(crate) fn address(this: &Self) -> usize {
+        Weak::as_ptr(&this.0) as *const () as usize
+    }
+}
+
+/// Cloning a WeakRef creates another weak reference to the same value
+impl<T> Clone for WeakRef<T> {
+    fn clone(&self) -> Self {
+        Self(Weak::clone(&self.0))
+    }
+}
+
+impl<T: Clone> Ref<T> {
+    /// Return a clone of the referenced value
+    pub fn get(&self) -> T {
+        self.borrow().clone()
+    }
+}
+
+/// Cloning a Ref does not clone the value, it
+/// creates another reference to the same value
+impl<T> Clone for Ref<T> {
+    fn clone(&self) -> Self {
+        Self(Rc::clone(&self.0))
+    }
+}
+
+/// Refs are compared by the values they refer to
+impl<T: PartialEq> PartialEq for Ref<T> {
+    fn eq(&self, rhs: &Self) -> bool {
+        *self.borrow() == *rhs.borrow()
+    }
+}
+
+/// Refs are ordered by the values they refer to
+impl<T: PartialOrd> PartialOrd for Ref<T> {
+    fn partial_cmp(&self, rhs: &Self) -> Option<Ordering> {
+        self.borrow().partial_cmp(&*rhs.borrow())
+    }
+}
+
+/// Display the referenced value
+impl<T: Display> Display for Ref<T> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        Display::fmt(&*self.borrow(), f)
+    }
+}
+
+/// Debug the referenced value
+impl<T: Debug> Debug for Ref<T> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        Debug::fmt(&*self.borrow(), f)
+    }
+}
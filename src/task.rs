@@ -0,0 +1,832 @@
+use crate::iteration::Iteration;
+use crate::value::Pairs;
+use crate::{
+    Body, Coroutine, ErrorKind, Frame, Function, Instruction, Machine, MachineError, Ref, Value,
+};
+
+// For the scopes of the blocks being run
+use alloc::collections::BTreeMap;
+// A paused task is boxed to keep Machines small
+use alloc::boxed::Box;
+// Function bodies are shared with the functions they come from
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+// The Machine derives these, so a paused Task has to implement them
+use core::cmp::Ordering;
+use core::mem;
+
+/// The blocks of instructions a program is running, and the
+/// function calls they made.
+///
+/// Running a function on the Rust stack means that it can't be
+/// stopped halfway through and picked up again later. A task keeps
+/// each block it is running in a list instead, with the offset of
+/// its next instruction, so it can stop between any two steps:
+/// a coroutine yielding, or a program running out of fuel. The
+/// functions that a program calls, and the blocks of its loops,
+/// `if_then_else` and `try_catch` instructions, are run this way.
+/// Only native functions, and the functions they call, still run
+/// on the Rust stack.
+#[derive(Clone, Default)]
+pub(crate) struct Task {
+    /// The blocks being run in the machine the task was started on
+    blocks: Vec<Block>,
+    /// The function calls being run, with the innermost call last
+    calls: Vec<Call>,
+    /// The call frames of the blocks being run, while paused
+    frames: Vec<Frame>,
+}
+
+/// A paused program isn't part of the state of the Machine,
+/// so two Machines are equal whatever they were running
+impl PartialEq for Task {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl PartialOrd for Task {
+    fn partial_cmp(&self, _: &Self) -> Option<Ordering> {
+        Some(Ordering::Equal)
+    }
+}
+
+/// A function call being run by a task
+#[derive(Clone)]
+pub(crate) struct Call {
+    /// The machine the function runs in, made from its context
+    pub(crate) machine: Machine,
+    /// The blocks being run in the function, with its body first
+    blocks: Vec<Block>,
+    /// The coroutine that this is the function of, if the
+    /// task resumed it, which suspends when it yields
+    resumed: Option<Resumed>,
+}
+
+/// A coroutine resumed by a task
+#[derive(Clone, PartialEq)]
+struct Resumed {
+    coroutine: Ref<Value>,
+    /// The number of call frames of the machine that resumed it
+    depth: usize,
+}
+
+/// A block of instructions being run by a task
+#[derive(Clone, PartialEq)]
+struct Block {
+    /// The instructions of the block, or None for a block that is
+    /// waiting for a native function or a function call to finish.
+    /// Blocks of instructions have a call frame of their own.
+    code: Option<Rc<[Instruction]>>,
+    /// The offset of the next instruction to run
+    offset: usize,
+    /// The number of scopes the machine had before the block started
+    depth: usize,
+    kind: Kind,
+}
+
+/// The functions of a `while_loop`
+#[derive(Clone, PartialEq)]
+struct WhileLoop {
+    condition: Value,
+    body: Value,
+}
+
+/// The body of a `for_loop`, and the names of its registers
+#[derive(Clone, PartialEq)]
+struct ForLoop {
+    body: Value,
+    element: String,
+    counter: String,
+}
+
+/// What happens when a block runs out of instructions
+#[derive(Clone, PartialEq)]
+enum Kind {
+    /// The program the task was started with, which ends the task
+    Root,
+    /// The body of a function, which returns
+    Body,
+    /// A block that just ends, like a branch of an `if_then_else`
+    Branch,
+    /// The CONDITION of an `if_then_else`, which runs THEN or ELSE
+    If { then_fn: Value, else_fn: Value },
+    /// The CONDITION of a `while_loop`, which runs the BODY if it is true
+    Condition(Rc<WhileLoop>),
+    /// The BODY of a `while_loop`, which runs the CONDITION again
+    While(Rc<WhileLoop>),
+    /// The `__iter__` method of a tree being iterated over,
+    /// which returns the iterator to iterate with
+    Iter(Rc<ForLoop>),
+    /// The `next` method of an iterator, or a coroutine,
+    /// giving the next element of a `for_loop`
+    Next(Rc<ForLoop>, Iteration),
+    /// The BODY of a `for_loop`, which runs again for each element
+    For(Rc<ForLoop>, Iteration),
+    /// The BODY of a `try_catch`, which runs the HANDLER if it fails,
+    /// with the stack cut back to the height it had at the start
+    Try {
+        handler: Value,
+        finally: Value,
+        height: usize,
+    },
+    /// The HANDLER of a `try_catch`, which runs FINALLY when it ends
+    Catch(Value),
+    /// The FINALLY of a `try_catch`, which raises the error that
+    /// stopped the BODY or HANDLER again when it ends
+    Finally(Option<MachineError>),
+}
+
+/// Runs a task on the machine it was started on
+struct Executor<'a> {
+    machine: &'a mut Machine,
+    task: Task,
+    /// Whether the host started the task, so it can be
+    /// paused when it runs out of fuel instead of failing
+    pausable: bool,
+    /// The number of call frames the machine had before
+    /// the task started, which aren't part of the task
+    depth: usize,
+}
+
+impl Task {
+    /// Make a task that runs a program in the machine it is started on
+    pub(crate) fn new(code: Rc<[Instruction]>) -> Self {
+        Self {
+            blocks: vec![Block {
+                code: Some(code),
+                offset: 0,
+                depth: 0,
+                kind: Kind::Root,
+            }],
+            ..Self::default()
+        }
+    }
+
+    /// Returns true if the task runs a program, rather than
+    /// only resuming a coroutine for the host
+    pub(crate) fn is_program(&self) -> bool {
+        !self.blocks.is_empty()
+    }
+
+    /// Run the task until it finishes. A task that the host started
+    /// is paused when it runs out of fuel, and kept by the machine
+    /// for `Machine::try_continue`.
+    pub(crate) fn run(self, machine: &mut Machine, pausable: bool) -> Result<(), MachineError> {
+        Executor::new(machine, self, pausable).run()
+    }
+
+    /// Resume a coroutine and run it until it yields or returns,
+    /// then push what it gave onto the stack of the machine, like
+    /// `Machine::resume`
+    pub(crate) fn resume(
+        machine: &mut Machine,
+        coroutine: Ref<Value>,
+        value: Ref<Value>,
+        pausable: bool,
+    ) -> Result<(), MachineError> {
+        let mut executor = Executor::new(machine, Self::default(), pausable);
+        executor.resume(coroutine, value)?;
+        executor.run()
+    }
+}
+
+impl Call {
+    /// Make a call that runs the body of a function in a machine
+    /// made from its context. The call frame of the body is
+    /// pushed by whoever runs the call.
+    pub(crate) fn new(machine: Machine, code: Rc<[Instruction]>) -> Self {
+        let depth = machine.scopes.len();
+        Self {
+            machine,
+            blocks: vec![Block {
+                code: Some(code),
+                offset: 0,
+                depth,
+                kind: Kind::Body,
+            }],
+            resumed: None,
+        }
+    }
+
+    /// Compare the calls of two coroutines, see `Coroutine::eq_in`.
+    /// The blocks are compared by where they are in their code, since
+    /// the functions of their loops could refer back to the coroutine.
+    pub(crate) fn eq_in(&self, other: &Self, pairs: &mut Pairs) -> bool {
+        self.machine.eq_in(&other.machine, pairs)
+            && self.blocks.len() == other.blocks.len()
+            && self.blocks.iter().zip(&other.blocks).all(|(a, b)| {
+                a.code == b.code
+                    && a.offset == b.offset
+                    && a.depth == b.depth
+                    && mem::discriminant(&a.kind) == mem::discriminant(&b.kind)
+            })
+    }
+}
+
+impl<'a> Executor<'a> {
+    /// Start running a task, giving the calls it was running
+    /// back the state that the machine kept while it was paused
+    fn new(machine: &'a mut Machine, task: Task, pausable: bool) -> Self {
+        let depth = machine.frames.len();
+        let mut executor = Self {
+            machine,
+            task,
+            pausable,
+            depth,
+        };
+        let mut frames = mem::take(&mut executor.task.frames);
+        executor.swap_shared();
+        executor.current().0.frames.append(&mut frames);
+        executor
+    }
+
+    /// Swap the state that the calls of the task share with the
+    /// machine the task was started on: the stack of the calls
+    /// that weren't made by a coroutine, and the call frames,
+    /// collector and limits of the innermost call
+    fn swap_shared(&mut self) {
+        let calls = &mut self.task.calls;
+        let end = calls
+            .iter()
+            .position(|call| call.resumed.is_some())
+            .unwrap_or(calls.len());
+        if let Some(call) = end.checked_sub(1).map(|index| &mut calls[index]) {
+            mem::swap(&mut call.machine.stack, &mut self.machine.stack);
+        }
+        if let Some(call) = calls.last_mut() {
+            self.machine.hand_over(&mut call.machine);
+        }
+    }
+
+    /// The machine and blocks of the function call being run
+    fn current(&mut self) -> (&mut Machine, &mut Vec<Block>) {
+        match self.task.calls.last_mut() {
+            Some(call) => (&mut call.machine, &mut call.blocks),
+            None => (&mut *self.machine, &mut self.task.blocks),
+        }
+    }
+
+    /// The machine of the function call being run
+    fn machine(&mut self) -> &mut Machine {
+        self.current().0
+    }
+
+    /// Run the innermost block one step at a time, until every
+    /// block has ended. Each step is an instruction, or the end
+    /// of a block, and uses one unit of fuel.
+    fn run(&mut self) -> Result<(), MachineError> {
+        loop {
+            let (machine, blocks) = self.current();
+            let block = match blocks.last_mut() {
+                Some(block) => block,
+                None => return Ok(()),
+            };
+            let (code, offset) = (block.code.clone(), block.offset);
+            let instruction = code.as_deref().and_then(|code| code.get(offset));
+
+            let step = match machine.limits.try_use_fuel() {
+                Err(e) => Err(self.out_of_fuel(e, offset, instruction)?),
+                Ok(()) => match instruction {
+                    Some(instruction) => {
+                        block.offset += 1;
+                        machine.maybe_collect_garbage();
+                        machine.set_offset(offset);
+                        self.execute(instruction)
+                            .and_then(|()| self.current().0.try_check_limits())
+                            .map_err(|e| e.at(offset, instruction))
+                    }
+                    // Loops start their next iteration, and functions return
+                    None => self.end_block().map_err(|e| self.attribute(e)),
+                },
+            };
+            if let Err(e) = step {
+                self.unwind(e)?;
+            }
+        }
+    }
+
+    /// Handle running out of fuel before a step. A task that the host
+    /// started is paused, and the error is returned to the host. Any
+    /// other task fails, since it is running on the Rust stack.
+    fn out_of_fuel(
+        &mut self,
+        e: MachineError,
+        offset: usize,
+        instruction: Option<&Instruction>,
+    ) -> Result<MachineError, MachineError> {
+        let e = match instruction {
+            Some(instruction) => {
+                self.machine().set_offset(offset);
+                e.at(offset, instruction)
+            }
+            None => self.attribute(e),
+        };
+        if !self.pausable {
+            return Ok(e);
+        }
+
+        let e = e.with_trace(&self.machine().frames);
+        self.swap_shared();
+        self.task.frames = self.machine.frames.split_off(self.depth);
+        self.machine.paused = Some(Box::new(mem::take(&mut self.task)));
+        Err(e)
+    }
+
+    /// Run an instruction. The instructions that run functions
+    /// push a block for the function instead, which runs next.
+    /// Every other instruction is run by the Machine.
+    fn execute(&mut self, instruction: &Instruction) -> Result<(), MachineError> {
+        let machine = self.machine();
+        match instruction {
+            Instruction::Call => {
+                let function = machine.try_pop()?.get();
+                self.call(function, None)
+            }
+            Instruction::MethodCall => {
+                let (function, name) = machine.try_method()?;
+                self.call(function, Some(name))
+            }
+            Instruction::IfThenElse => {
+                let condition = machine.try_pop()?.get();
+                let then_fn = machine.try_pop()?.get();
+                let else_fn = machine.try_pop()?.get();
+                self.enter(&condition, Kind::If { then_fn, else_fn }, false, None)
+            }
+            Instruction::WhileLoop => {
+                let condition = machine.try_pop()?.get();
+                let body = machine.try_pop()?.get();
+                let whole = Rc::new(WhileLoop { condition, body });
+                self.enter(
+                    &whole.condition,
+                    Kind::Condition(Rc::clone(&whole)),
+                    false,
+                    None,
+                )
+            }
+            Instruction::ForLoop => {
+                let counter = machine.try_pop()?.to_string();
+                let element = machine.try_pop()?.to_string();
+                let iterable = machine.try_pop()?;
+                let body = machine.try_pop()?.get();
+                let whole = Rc::new(ForLoop {
+                    body,
+                    element,
+                    counter,
+                });
+                if Iteration::has_iterator(&iterable) {
+                    machine.push(iterable);
+                    machine.push(Value::string("__iter__"));
+                    let (function, name) = machine.try_method()?;
+                    self.wait(Kind::Iter(whole));
+                    return self.call(function, Some(name));
+                }
+                let iteration = Iteration::new(machine, iterable)?;
+                self.advance(whole, iteration)
+            }
+            Instruction::TryCatch => {
+                let body = machine.try_pop()?.get();
+                let handler = machine.try_pop()?.get();
+                let finally = machine.try_pop()?.get();
+                let height = machine.stack.len();
+                let kind = Kind::Try {
+                    handler,
+                    finally,
+                    height,
+                };
+                self.enter(&body, kind, true, None)
+            }
+            Instruction::Resume => {
+                let coroutine = machine.try_pop()?;
+                let value = machine.try_pop()?;
+                self.resume(coroutine, value)
+            }
+            Instruction::Yield => {
+                let value = machine.try_pop()?;
+                self.suspend(value)
+            }
+            instruction => machine.try_execute(instruction),
+        }
+    }
+
+    /// Get a function and its instructions, if it isn't native
+    fn code(value: &Value) -> Option<(&Function, Rc<[Instruction]>)> {
+        match value {
+            Value::Function(f) => match f.get_body() {
+                Body::Code(code) => Some((f, Rc::clone(code))),
+                Body::Native(_) => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Call a function in a call frame with the given name, or with
+    /// the name of the function if no name is given. A function made
+    /// from instructions starts running in a call of its own, and
+    /// anything else is called by the Machine.
+    fn call(&mut self, function: Value, name: Option<String>) -> Result<(), MachineError> {
+        let (f, code) = match Self::code(&function) {
+            Some(found) => found,
+            None => return function.try_call_as(self.machine(), name),
+        };
+        let name = name.unwrap_or_else(|| f.frame_name());
+
+        // The function runs in a machine made from its context,
+        // which is given the stack of the caller
+        let caller = self.machine();
+        caller.try_check_depth()?;
+        let mut machine = f.get_context().clone();
+        mem::swap(&mut machine.stack, &mut caller.stack);
+        caller.hand_over(&mut machine);
+        machine.frames.push(Frame {
+            function: name,
+            offset: None,
+        });
+        self.task.calls.push(Call::new(machine, code));
+        Ok(())
+    }
+
+    /// Start running a function as a block of the current call, in
+    /// the scope of the machine, or in a scope of its own with the
+    /// given registers declared in it. A native function is run
+    /// straight away, in a block that ends when it returns.
+    fn enter(
+        &mut self,
+        function: &Value,
+        kind: Kind,
+        scoped: bool,
+        bindings: Option<[(String, Ref<Value>); 2]>,
+    ) -> Result<(), MachineError> {
+        let (machine, blocks) = self.current();
+        machine.try_check_depth()?;
+        let depth = machine.scopes.len();
+        if scoped {
+            machine.scopes.push(BTreeMap::new());
+        }
+        for (name, value) in bindings.into_iter().flatten() {
+            machine.declare_register(name, value);
+        }
+
+        match Self::code(function) {
+            Some((f, code)) => {
+                machine.frames.push(Frame {
+                    function: f.frame_name(),
+                    offset: None,
+                });
+                blocks.push(Block {
+                    code: Some(code),
+                    offset: 0,
+                    depth,
+                    kind,
+                });
+                Ok(())
+            }
+            None => {
+                blocks.push(Block {
+                    code: None,
+                    offset: 0,
+                    depth,
+                    kind,
+                });
+                function.try_call_global(machine)
+            }
+        }
+    }
+
+    /// Push a block that waits for a function call to return
+    fn wait(&mut self, kind: Kind) {
+        let (machine, blocks) = self.current();
+        let depth = machine.scopes.len();
+        blocks.push(Block {
+            code: None,
+            offset: 0,
+            depth,
+            kind,
+        });
+    }
+
+    /// Drop the scopes and call frame of a block that has ended
+    fn exit(machine: &mut Machine, block: &Block) {
+        // The program the task was started with runs in
+        // the frame and scope of the machine it runs on
+        if block.kind == Kind::Root {
+            return;
+        }
+        if block.code.is_some() {
+            machine.frames.pop();
+        }
+        machine.scopes.truncate(block.depth);
+    }
+
+    /// Attribute an error raised when a block ended to the
+    /// instruction that started the block, in the block outside it
+    fn attribute(&mut self, e: MachineError) -> MachineError {
+        let (_, blocks) = self.current();
+        let found = blocks
+            .iter()
+            .rev()
+            .find_map(|block| block.code.as_ref().map(|code| (code, block.offset)));
+        match found {
+            Some((code, offset)) if offset > 0 => e.at(offset - 1, &code[offset - 1]),
+            _ => e,
+        }
+    }
+
+    /// Pop a value off the stack, as a condition
+    fn try_pop_condition(&mut self) -> Result<bool, MachineError> {
+        Ok(self.machine().try_pop()?.get().into())
+    }
+
+    /// Finish the innermost block, after its last instruction
+    fn end_block(&mut self) -> Result<(), MachineError> {
+        let (machine, blocks) = self.current();
+        let block = blocks.pop().expect("There is a block to end");
+        Self::exit(machine, &block);
+        self.finish(block.kind)
+    }
+
+    /// Do what a block does after it has ended
+    fn finish(&mut self, kind: Kind) -> Result<(), MachineError> {
+        match kind {
+            Kind::Root | Kind::Branch => Ok(()),
+            Kind::Body => {
+                self.return_from(None);
+                Ok(())
+            }
+            Kind::If { then_fn, else_fn } => {
+                let branch = if self.try_pop_condition()? {
+                    then_fn
+                } else {
+                    else_fn
+                };
+                self.enter(&branch, Kind::Branch, true, None)
+            }
+            Kind::Condition(whole) if self.try_pop_condition()? => {
+                self.enter(&whole.body, Kind::While(Rc::clone(&whole)), true, None)
+            }
+            Kind::Condition(_) => Ok(()),
+            Kind::While(whole) => self.enter(
+                &whole.condition,
+                Kind::Condition(Rc::clone(&whole)),
+                false,
+                None,
+            ),
+            Kind::Iter(whole) => {
+                let iterator = self.machine().try_pop()?;
+                self.advance(whole, Iteration::Iterator { iterator, index: 0 })
+            }
+            // `next` pushes the next element and then `true`, or only
+            // pushes `false` when there are no more. Coroutines push
+            // the value they return too, which isn't an element.
+            Kind::Next(whole, mut iteration) => {
+                if !self.try_pop_condition()? {
+                    if let Iteration::Coroutine { .. } = iteration {
+                        self.machine().try_pop()?;
+                    }
+                    return Ok(());
+                }
+                let element = self.machine().try_pop()?;
+                let counter = iteration.count();
+                self.visit(whole, iteration, counter, element)
+            }
+            Kind::For(whole, iteration) => self.advance(whole, iteration),
+            Kind::Try { finally, .. } | Kind::Catch(finally) if finally != Value::None => {
+                self.enter(&finally, Kind::Finally(None), true, None)
+            }
+            Kind::Try { .. } | Kind::Catch(_) => Ok(()),
+            Kind::Finally(Some(e)) => Err(e),
+            Kind::Finally(None) => Ok(()),
+        }
+    }
+
+    /// Start the next iteration of a `for_loop`, or get
+    /// its next element first if that calls a function
+    fn advance(
+        &mut self,
+        whole: Rc<ForLoop>,
+        mut iteration: Iteration,
+    ) -> Result<(), MachineError> {
+        match &iteration {
+            Iteration::Iterator { iterator, .. } => {
+                let machine = self.machine();
+                machine.push(Ref::clone(iterator));
+                machine.push(Value::string("next"));
+                let (function, name) = machine.try_method()?;
+                self.wait(Kind::Next(whole, iteration));
+                self.call(function, Some(name))
+            }
+            Iteration::Coroutine { coroutine, .. } => {
+                let coroutine = Ref::clone(coroutine);
+                self.wait(Kind::Next(whole, iteration));
+                self.resume(coroutine, Value::none())
+            }
+            _ => match iteration.next(self.machine())? {
+                Some((counter, element)) => self.visit(whole, iteration, counter, element),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Run the body of a `for_loop` for an element, with
+    /// the ELEMENT and COUNTER registers declared in its scope
+    fn visit(
+        &mut self,
+        whole: Rc<ForLoop>,
+        iteration: Iteration,
+        counter: Ref<Value>,
+        element: Ref<Value>,
+    ) -> Result<(), MachineError> {
+        let bindings = [
+            (whole.element.clone(), element),
+            (whole.counter.clone(), counter),
+        ];
+        let kind = Kind::For(Rc::clone(&whole), iteration);
+        self.enter(&whole.body, kind, true, Some(bindings))
+    }
+
+    /// Return from the innermost function call, and push VALUE for
+    /// the caller if there is one. The body of the call has ended.
+    /// Returning from the function of a coroutine finishes it.
+    fn return_from(&mut self, value: Option<Ref<Value>>) {
+        let resumed = self.leave_call();
+        let machine = self.machine();
+        match resumed {
+            Some(resumed) => {
+                Coroutine::finish(&resumed.coroutine);
+                machine.push(value.unwrap_or_else(Value::none));
+                machine.push(Value::boolean(false));
+            }
+            None => {
+                if let Some(value) = value {
+                    machine.push(value);
+                }
+            }
+        }
+    }
+
+    /// Call a function made from instructions in place of the
+    /// innermost function call, after its body has ended. The
+    /// function gets the call's stack, and the coroutine it is
+    /// running if it is the function of one.
+    fn replace_call(&mut self, function: Value) {
+        let (f, code) = Self::code(&function).expect("Only functions made from instructions");
+        let call = self
+            .task
+            .calls
+            .last_mut()
+            .expect("There is a call to replace");
+        let mut machine = f.get_context().clone();
+        mem::swap(&mut machine.stack, &mut call.machine.stack);
+        call.machine.hand_over(&mut machine);
+        machine.frames.push(Frame {
+            function: f.frame_name(),
+            offset: None,
+        });
+        let resumed = call.resumed.take();
+        *call = Call {
+            resumed,
+            ..Call::new(machine, code)
+        };
+    }
+
+    /// Drop the innermost function call, after its body has ended,
+    /// and give its caller back the stack and the state it shared.
+    /// Returns the coroutine the call was the function of, if any,
+    /// which keeps its stack.
+    fn leave_call(&mut self) -> Option<Resumed> {
+        let mut callee = self.task.calls.pop().expect("There is a call to leave");
+        let caller = self.machine();
+        if callee.resumed.is_none() {
+            mem::swap(&mut callee.machine.stack, &mut caller.stack);
+        }
+        callee.machine.hand_over(caller);
+        callee.resumed
+    }
+
+    /// Resume a coroutine, pushing VALUE onto its stack. The calls
+    /// it was running are run by the task until it yields or returns.
+    fn resume(&mut self, coroutine: Ref<Value>, value: Ref<Value>) -> Result<(), MachineError> {
+        let (mut calls, mut frames) = Coroutine::take(&coroutine)?;
+        let resumer = self.machine();
+        let depth = resumer.frames.len();
+        let machine = &mut calls.last_mut().expect("Coroutines run a call").machine;
+        resumer.hand_over(machine);
+        machine.frames.append(&mut frames);
+        machine.push(value);
+
+        calls[0].resumed = Some(Resumed { coroutine, depth });
+        self.task.calls.append(&mut calls);
+        Ok(())
+    }
+
+    /// Suspend the innermost coroutine the task resumed, keeping
+    /// the calls it was running, and push the value it yields and
+    /// `true` for the machine that resumed it
+    fn suspend(&mut self, value: Ref<Value>) -> Result<(), MachineError> {
+        let calls = &self.task.calls;
+        let index = match calls.iter().rposition(|call| call.resumed.is_some()) {
+            Some(index) => index,
+            None => return Err(MachineError::signal(ErrorKind::Yield, Some(value))),
+        };
+
+        let mut calls = self.task.calls.split_off(index);
+        let resumed = calls[0].resumed.take().expect("The call was resumed");
+        let resumer = self.machine();
+        calls
+            .last_mut()
+            .expect("Coroutines run a call")
+            .machine
+            .hand_over(resumer);
+        let frames = resumer.frames.split_off(resumed.depth);
+        Coroutine::suspend(&resumed.coroutine, calls, frames);
+        resumer.push(value);
+        resumer.push(Value::boolean(true));
+        Ok(())
+    }
+
+    /// Leave blocks until one of them handles an error or signal.
+    /// Loops handle `break` and `continue`, function calls handle
+    /// `return` and `tail_call`, and `try_catch` handles errors. Fails with the error
+    /// if it leaves every block, which finishes the task.
+    fn unwind(&mut self, mut e: MachineError) -> Result<(), MachineError> {
+        loop {
+            let (machine, blocks) = self.current();
+            let block = match blocks.pop() {
+                Some(block) => block,
+                None => return Err(e),
+            };
+            // A signal that reaches the end of a function call has
+            // escaped the loop or function it was meant to exit
+            let ends_call = matches!(e.kind, ErrorKind::Return | ErrorKind::TailCall);
+            if block.kind == Kind::Body && !ends_call {
+                e = e.escaped();
+            }
+            // Errors get the frames of the block that raised them
+            e = e.with_trace(&machine.frames);
+            Self::exit(machine, &block);
+
+            let handled = match (block.kind, &e.kind) {
+                // A tail call from the program skips the rest
+                // of it, and calls the function instead
+                (Kind::Root, ErrorKind::TailCall) => match e.payload {
+                    Some(function) => self.call(function.get(), None),
+                    None => return Ok(()),
+                },
+                // Signals and errors leave the program to be handled
+                // by whoever started the task
+                (Kind::Root, _) => return Err(e),
+                (Kind::Body, ErrorKind::Return) => {
+                    self.return_from(Some(e.payload.unwrap_or_else(Value::none)));
+                    return Ok(());
+                }
+                (Kind::Body, ErrorKind::TailCall) => {
+                    match e.payload {
+                        Some(function) => self.replace_call(function.get()),
+                        // A native function was called already
+                        None => self.return_from(None),
+                    }
+                    return Ok(());
+                }
+                (Kind::Body, _) => {
+                    // A coroutine that fails has finished
+                    if let Some(resumed) = self.leave_call() {
+                        Coroutine::finish(&resumed.coroutine);
+                    }
+                    continue;
+                }
+                (Kind::While(_), ErrorKind::Break) | (Kind::For(..), ErrorKind::Break) => {
+                    return Ok(());
+                }
+                (kind @ Kind::While(_), ErrorKind::Continue)
+                | (kind @ Kind::For(..), ErrorKind::Continue) => self.finish(kind),
+                (
+                    Kind::Try {
+                        handler,
+                        finally,
+                        height,
+                    },
+                    kind,
+                ) if kind.is_catchable() && handler != Value::None => {
+                    let machine = self.machine();
+                    machine.stack.truncate(height);
+                    machine.push(Ref::new(e.clone().into()));
+                    self.enter(&handler, Kind::Catch(finally), true, None)
+                }
+                (Kind::Try { finally, .. }, kind) | (Kind::Catch(finally), kind)
+                    if finally != Value::None && *kind != ErrorKind::OutOfFuel =>
+                {
+                    let finally_fn = finally;
+                    self.enter(&finally_fn, Kind::Finally(Some(e.clone())), true, None)
+                }
+                _ => continue,
+            };
+
+            // The block that handled it can fail too
+            match handled {
+                Ok(()) => return Ok(()),
+                Err(raised) => e = self.attribute(raised),
+            }
+        }
+    }
+}
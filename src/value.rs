@@ -1,8 +1,13 @@
-use crate::{Function, Machine, Ref};
-use core::ops::{Add, Div, Mul, Not, Rem, Sub};
+use crate::{
+    Coroutine, ErrorKind, Exception, Function, Instruction, Key, Machine, MachineError, Ref,
+    Status, WeakRef,
+};
+use core::cmp::Ordering;
+use core::convert::TryFrom;
+use core::ops::{Add, Div, Mul, Neg, Not, Rem, Sub};
 
 // We need BTreeMap to implement the Tree type
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
 // For ToString generics
 use alloc::string::{String, ToString};
 // We need Vec for dynamically allocated lists
@@ -10,14 +15,23 @@ use alloc::vec::Vec;
 // For implementing Display and Debug
 use core::fmt::{Debug, Display, Error, Formatter};
 
-#[derive(Clone, PartialEq, PartialOrd)]
+#[derive(Clone)]
 pub enum Value {
     String(String),
     Number(f64),
+    Int(i64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    /// The Ints from the first number up to, but not including, the second
+    Range(i64, i64),
     List(Vec<Ref<Self>>),
-    Tree(BTreeMap<String, Ref<Self>>),
-    Function(Function<Machine, (), Machine>),
-    Error(String),
+    Tree(BTreeMap<Key, Ref<Self>>),
+    Function(Function),
+    Error(Exception),
+    /// A reference that doesn't keep the value it refers to alive
+    Weak(WeakRef<Self>),
+    /// A function that can suspend itself and be resumed later
+    Coroutine(Coroutine),
     None,
 }
 
@@ -27,6 +41,26 @@ impl Value {
         Ref::new(Self::Number(n.into()))
     }
 
+    /// Creates a new reference to an Int
+    pub fn int<N: Into<i64>>(n: N) -> Ref<Self> {
+        Ref::new(Self::Int(n.into()))
+    }
+
+    /// Creates a new reference to a Bool
+    pub fn boolean(b: bool) -> Ref<Self> {
+        Ref::new(Self::Bool(b))
+    }
+
+    /// Creates a new reference to Bytes
+    pub fn bytes<B: Into<Vec<u8>>>(b: B) -> Ref<Self> {
+        Ref::new(Self::Bytes(b.into()))
+    }
+
+    /// Creates a new reference to a Range of Ints from START up to END
+    pub fn range(start: i64, end: i64) -> Ref<Self> {
+        Ref::new(Self::Range(start, end))
+    }
+
     /// Creates a new reference to a String
     pub fn string<S: ToString>(s: S) -> Ref<Self> {
         Ref::new(Self::String(s.to_string()))
@@ -43,16 +77,56 @@ impl Value {
     }
 
     /// Creates a reference to a Function with a captured context, basically a Closure
-    pub fn function(f: impl 'static + Fn(&mut Machine) -> (), context: &Machine) -> Ref<Self> {
-        Ref::new(Self::Function(Function::new(
-            f,
-            context.clone().duplicate(),
+    pub fn function(f: impl 'static + Fn(&mut Machine), context: &Machine) -> Ref<Self> {
+        Ref::new(Self::Function(Function::new(f, context.context())))
+    }
+
+    /// Creates a reference to a Function whose body is a block of
+    /// instructions rather than a Rust closure. Like `Value::function`,
+    /// the function captures a copy of the given context.
+    pub fn program(code: Vec<Instruction>, context: &Machine) -> Ref<Self> {
+        Ref::new(Self::Function(Function::from_code(code, context.context())))
+    }
+
+    /// Creates a reference to a Function like `Value::function`, but
+    /// the function only captures copies of the named registers,
+    /// rather than the whole machine. See `Machine::capture`.
+    pub fn closure<S: AsRef<str>>(
+        f: impl 'static + Fn(&mut Machine),
+        context: &Machine,
+        captures: &[S],
+    ) -> Ref<Self> {
+        Ref::new(Self::Function(Function::new(f, context.capture(captures))))
+    }
+
+    /// Creates a reference to a Function like `Value::program`, but
+    /// the function only captures copies of the named registers,
+    /// rather than the whole machine. See `Machine::capture`.
+    pub fn program_closure<S: AsRef<str>>(
+        code: Vec<Instruction>,
+        context: &Machine,
+        captures: &[S],
+    ) -> Ref<Self> {
+        Ref::new(Self::Function(Function::from_code(
+            code,
+            context.capture(captures),
         )))
     }
 
     /// Creates a reference to an Error value
     pub fn error<S: ToString>(s: S) -> Ref<Self> {
-        Ref::new(Self::Error(s.to_string()))
+        Self::exception(ErrorKind::Custom(String::from("Exception")), s)
+    }
+
+    /// Creates a reference to an Error value of a specific kind
+    pub fn exception<S: ToString>(kind: ErrorKind, s: S) -> Ref<Self> {
+        Ref::new(Self::Error(Exception::new(kind, s)))
+    }
+
+    /// Creates a reference to a Weak value, which refers to
+    /// the same value as the Ref without keeping it alive
+    pub fn weak(value: &Ref<Self>) -> Ref<Self> {
+        Ref::new(Self::Weak(Ref::downgrade(value)))
     }
 
     /// Creates a reference to an None value
@@ -60,119 +134,372 @@ impl Value {
         Ref::new(Self::None)
     }
 
-    /// Copies the contents of this value
+    /// Copies the contents of this value. The values inside of lists
+    /// and trees are copied too, and a value that is inside of them
+    /// more than once is only copied once, so the copy of a value
+    /// that contains itself contains the copy.
     pub fn copy(&self) -> Ref<Self> {
-        // In the future, if memory leaks become a problem,
-        // we could try replacing the item clone with an
-        // item copy.
-        // This would recursively call copy to ensure no
-        // Refs are the same. It might be that we never
-        // need to change this, though.
+        Ref::new(self.copy_with(&mut BTreeMap::new()))
+    }
+
+    /// Copies the contents of this value, with COPIES holding
+    /// the copies already made, by the address of the original
+    fn copy_with(&self, copies: &mut BTreeMap<usize, Ref<Self>>) -> Self {
         match self {
-            Self::List(l) => {
-                let mut list = vec![];
-                for item in l {
-                    list.push(item.copy());
-                }
-                Ref::new(Self::List(list))
-            }
-            Self::Tree(l) => {
-                let mut map = BTreeMap::new();
-                for (name, item) in l {
-                    map.insert(name.clone(), item.copy());
-                }
-                Ref::new(Self::Tree(map))
-            }
-            _ => Ref::new(self.clone()),
+            Self::List(l) => Self::List(l.iter().map(|item| item.copy_with(copies)).collect()),
+            Self::Tree(t) => Self::Tree(
+                t.iter()
+                    .map(|(name, item)| (name.clone(), item.copy_with(copies)))
+                    .collect(),
+            ),
+            _ => self.clone(),
         }
     }
 
     /// Call this function in the context of the Machine
     /// captured when this instance of the function was created
     pub fn call(&self, machine: &mut Machine) {
-        if let Self::Function(f) = self {
-            // Get the captured machine back from the function
-            let mut temp_machine = f.get_context().clone();
-            // Give it the current machine's stack
-            temp_machine.stack = machine.stack.clone();
-            // Call the function with the new machine
-            f.call(&mut temp_machine);
-            // Give back the modified stack
-            machine.stack = temp_machine.stack;
+        if let Err(e) = self.try_call(machine) {
+            machine.push(Ref::new(e.into()));
         }
     }
 
+    /// The fallible version of `Value::call`
+    pub fn try_call(&self, machine: &mut Machine) -> Result<(), MachineError> {
+        self.try_call_as(machine, None)
+    }
+
+    /// Call this function in a call frame with the given name,
+    /// or with the name of the function if no name is given
+    pub(crate) fn try_call_as(
+        &self,
+        machine: &mut Machine,
+        name: Option<String>,
+    ) -> Result<(), MachineError> {
+        let f = self.as_function()?;
+        // Get the captured machine back from the function
+        let mut temp_machine = f.get_context().clone();
+        // Give it the current machine's stack and call frames
+        core::mem::swap(&mut temp_machine.stack, &mut machine.stack);
+        machine.hand_over(&mut temp_machine);
+        // Call the function with the new machine
+        let name = name.unwrap_or_else(|| f.frame_name());
+        let result = temp_machine.in_frame(name, |m| {
+            let result = f.try_call(m);
+            m.catch_return(result)
+        });
+        // Give back the modified stack, even if the function failed
+        core::mem::swap(&mut machine.stack, &mut temp_machine.stack);
+        temp_machine.hand_over(machine);
+        result
+    }
+
     /// Call this function in the context of the current machine,
     /// meaning, execute the instructions of this function as if
     /// they were not in a function.
     pub fn call_global(&self, machine: &mut Machine) {
-        if let Self::Function(f) = self {
-            // Call the function with the given machine
-            f.call(machine);
+        if let Err(e) = self.try_call_global(machine) {
+            machine.push(Ref::new(e.escaped().into()));
+        }
+    }
+
+    /// The fallible version of `Value::call_global`
+    pub fn try_call_global(&self, machine: &mut Machine) -> Result<(), MachineError> {
+        // Call the function with the given machine
+        let f = self.as_function()?;
+        machine.in_frame(f.frame_name(), |m| f.try_call(m))
+    }
+
+    /// Get the function to call, or fail if this isn't a function
+    fn as_function(&self) -> Result<&Function, MachineError> {
+        match self {
+            Self::Function(f) => Ok(f),
+            other => Err(MachineError::new(
+                ErrorKind::NotCallable,
+                format!("Can't call non-function {}", other),
+            )),
         }
     }
 
     pub fn is_err(&self) -> bool {
+        matches!(self, Self::Error(_))
+    }
+
+    /// The position of this value's variant in the Value enum,
+    /// which orders values of different variants
+    fn variant(&self) -> u8 {
         match self {
-            Self::Error(_) => true,
-            _ => false,
+            Self::String(_) => 0,
+            Self::Number(_) => 1,
+            Self::Int(_) => 2,
+            Self::Bool(_) => 3,
+            Self::Bytes(_) => 4,
+            Self::Range(_, _) => 5,
+            Self::List(_) => 6,
+            Self::Tree(_) => 7,
+            Self::Function(_) => 8,
+            Self::Error(_) => 9,
+            Self::Weak(_) => 10,
+            Self::Coroutine(_) => 11,
+            Self::None => 12,
+        }
+    }
+
+    /// The result of an Int operator, which is None if it overflowed
+    fn checked_int(result: Option<i64>, message: impl FnOnce() -> String) -> Self {
+        match result {
+            Some(n) => Self::Int(n),
+            None => Self::arithmetic_error(message()),
         }
     }
 
+    /// Repeat a string N times, or return an error if the repeated
+    /// string would be too long to make, or there isn't enough memory
+    fn repeat(s: String, n: usize) -> Self {
+        let length = match s.len().checked_mul(n) {
+            Some(length) if length <= isize::MAX as usize => length,
+            _ => {
+                return Self::arithmetic_error(format!(
+                    "String overflow repeating {} bytes {} times",
+                    s.len(),
+                    n
+                ))
+            }
+        };
+        let mut repeated = Vec::new();
+        if repeated.try_reserve_exact(length).is_err() {
+            return Self::Error(Exception::new(
+                ErrorKind::MemoryLimit,
+                format!("Not enough memory to repeat {} bytes {} times", s.len(), n),
+            ));
+        }
+        // Double the repeated bytes until there are enough of them
+        if length > 0 {
+            repeated.extend_from_slice(s.as_bytes());
+        }
+        while repeated.len() < length {
+            repeated.extend_from_within(..repeated.len().min(length - repeated.len()));
+        }
+        Self::String(String::from_utf8(repeated).expect("Repeated strings are valid UTF-8"))
+    }
+
+    /// The error returned when an operator is used on values it doesn't support
+    fn arithmetic_error(message: String) -> Self {
+        Self::Error(Exception::new(ErrorKind::Arithmetic, message))
+    }
+
     /// Return a reference to a value contained within a collection
-    pub fn index<S: ToString>(&mut self, s: S) -> Ref<Self> {
-        let key = s.to_string();
+    pub fn index<K: Into<Key>>(&mut self, key: K) -> Ref<Self> {
+        match self.try_index(key) {
+            Ok(value) => value,
+            Err(e) => Ref::new(e.into()),
+        }
+    }
+
+    /// Return a reference to a value contained within a collection,
+    /// or fail if this value can't be indexed with this key.
+    /// Lists, strings and bytes are indexed by Ints, and
+    /// trees can be indexed by any key.
+    pub fn try_index<K: Into<Key>>(&mut self, key: K) -> Result<Ref<Self>, MachineError> {
+        let key = key.into();
+        let error = |message: String| MachineError::new(ErrorKind::InvalidIndex, message);
+        // Lists, strings and bytes are indexed by non-negative Ints
+        let offset = match key {
+            Key::Int(n) if n < 0 => Err(error(format!("Can't index with negative integer {}", n))),
+            Key::Int(n) => Ok(n as usize),
+            _ => Err(error(format!("Can't index with non-integer {:?}", key))),
+        };
         match self {
-            Self::String(s) => {
-                match key.parse::<usize>() {
-                    Ok(n) => {
-                        if s.len() > n {
-                            Value::string(s.chars().nth(n).unwrap().to_string())
-                        } else {
-                            Self::error("String index out of bounds")
-                        }
-                    }
-                    Err(_) => Self::error("Can't index string with non-integer"),
-                }
-            }
+            Self::String(s) => match s.chars().nth(offset?) {
+                Some(ch) => Ok(Value::string(ch)),
+                None => Err(error(String::from("String index out of bounds"))),
+            },
+            Self::Bytes(b) => match b.get(offset?) {
+                Some(byte) => Ok(Value::int(*byte)),
+                None => Err(error(String::from("Bytes index out of bounds"))),
+            },
+            Self::Range(start, end) => match start.checked_add(offset? as i64) {
+                Some(n) if n < *end => Ok(Value::int(n)),
+                _ => Err(error(String::from("Range index out of bounds"))),
+            },
             Self::Tree(t) => {
-                // If the current tree does not have a
-                // key with this name, create one
-                if !t.contains_key(&key) {
-                    t.insert(key.clone(), Self::none());
-                }
-
-                // Return a reference to this object in the table
-                Ref::clone(t.get(&key).unwrap())
+                // If the current tree does not have this key, create it,
+                // and return a reference to this object in the table
+                Ok(Ref::clone(t.entry(key).or_insert_with(Self::none)))
             }
             Self::List(l) => {
-                // Convert to usize to index this value as a list
-                match key.parse::<usize>() {
-                    Ok(n) => {
-                        // If the requested index is too high, allocate space for it and continue
-                        if n >= l.len() {
-                            // Reserve space for new size
-                            // This is good because it minimizes the
-                            // number of numerous, small allocations.
-                            l.reserve(n - l.len() + 1);
-
-                            // Fill the space with None
-                            for _ in l.len()..=n {
-                                l.push(Self::none());
-                            }
-                        }
-
-                        // Return reference to the requested item in the list
-                        Ref::clone(&l[n])
+                let n = offset?;
+                // If the requested index is too high, allocate space for it and continue
+                if n >= l.len() {
+                    // Reserve space for new size
+                    // This is good because it minimizes the
+                    // number of numerous, small allocations.
+                    if l.try_reserve(n - l.len() + 1).is_err() {
+                        return Err(MachineError::new(
+                            ErrorKind::MemoryLimit,
+                            format!("Not enough memory for a list of {} elements", n + 1),
+                        ));
+                    }
+
+                    // Fill the space with None
+                    for _ in l.len()..=n {
+                        l.push(Self::none());
                     }
-                    // Could not convert key to usize
-                    Err(_) => Self::error("Can't index list with non-integer"),
                 }
+
+                // Return reference to the requested item in the list
+                Ok(Ref::clone(&l[n]))
             }
+            // Errors can be indexed to get their contents
+            Self::Error(e) => match key {
+                Key::String(s) if s == "kind" => Ok(Value::string(&e.kind)),
+                Key::String(s) if s == "message" => Ok(Value::string(&e.message)),
+                Key::String(s) if s == "payload" => {
+                    Ok(e.payload.clone().unwrap_or_else(Self::none))
+                }
+                _ => Err(error(String::from(
+                    "Errors only have a kind, message and payload",
+                ))),
+            },
             // Tried to index something other than list or tree
-            _ => Self::error("Can't index non-list or non-tree"),
+            _ => Err(error(String::from("Can't index non-list or non-tree"))),
+        }
+    }
+
+    /// Return a reference to a value contained within a collection,
+    /// using another value as the key
+    pub fn index_value(&mut self, key: &Value) -> Ref<Self> {
+        match self.try_index_value(key) {
+            Ok(v) => v,
+            Err(e) => Ref::new(e.into()),
         }
     }
+
+    /// Index this value with another value, which is converted to a Key
+    /// first. Fails if the value can't be used as a key.
+    pub fn try_index_value(&mut self, key: &Value) -> Result<Ref<Self>, MachineError> {
+        self.try_index(Key::try_from(key)?)
+    }
+
+    /// Return the part of this value from START up to END
+    pub fn slice(&self, start: usize, end: usize) -> Ref<Self> {
+        match self.try_slice(start, end) {
+            Ok(v) => v,
+            Err(e) => Ref::new(e.into()),
+        }
+    }
+
+    /// Return the part of this value from START up to END, or fail if
+    /// this value can't be sliced or the range is out of bounds. The
+    /// slice of a list shares its elements with the list.
+    pub fn try_slice(&self, start: usize, end: usize) -> Result<Ref<Self>, MachineError> {
+        let error = |message| Err(MachineError::new(ErrorKind::InvalidIndex, message));
+        let out_of_bounds = |length| {
+            error(format!(
+                "Slice {}..{} out of bounds for length {}",
+                start, end, length
+            ))
+        };
+        match self {
+            Self::Bytes(b) => match b.get(start..end) {
+                Some(b) => Ok(Value::bytes(b)),
+                None => out_of_bounds(b.len()),
+            },
+            Self::List(l) => match l.get(start..end) {
+                Some(l) => Ok(Ref::new(Self::List(l.to_vec()))),
+                None => out_of_bounds(l.len()),
+            },
+            // Strings are sliced by characters, like they are indexed
+            Self::String(s) => {
+                let length = s.chars().count();
+                if start > end || end > length {
+                    return out_of_bounds(length);
+                }
+                Ok(Value::string(
+                    s.chars().skip(start).take(end - start).collect::<String>(),
+                ))
+            }
+            _ => error(format!("Can't slice {}", self)),
+        }
+    }
+}
+
+/// Shortcuts for using a reference to a Value like the Value itself
+impl Ref<Value> {
+    /// Copies the contents of the referenced value, see `Value::copy`
+    pub fn copy(&self) -> Ref<Value> {
+        self.copy_with(&mut BTreeMap::new())
+    }
+
+    /// Copies the referenced value, or returns its copy if it was
+    /// already copied. The copy is remembered before its contents are
+    /// copied, so the values inside of it that refer back to it refer
+    /// to the copy instead.
+    fn copy_with(&self, copies: &mut BTreeMap<usize, Ref<Value>>) -> Ref<Value> {
+        let address = Ref::address(self);
+        if let Some(copy) = copies.get(&address) {
+            return Ref::clone(copy);
+        }
+        let copy = Value::none();
+        copies.insert(address, Ref::clone(&copy));
+        copy.replace(self.borrow().copy_with(copies));
+        copy
+    }
+
+    /// Compare the referenced values, see `Value::eq_in`.
+    /// If the values are already being compared, they
+    /// are assumed to be equal.
+    pub(crate) fn eq_in(&self, other: &Self, pairs: &mut Pairs) -> bool {
+        let pair = (Ref::address(self), Ref::address(other));
+        if !pairs.insert(pair) {
+            return true;
+        }
+        let equal = self.borrow().eq_in(&other.borrow(), pairs);
+        pairs.remove(&pair);
+        equal
+    }
+
+    /// Order the referenced values, see `Value::cmp_in`.
+    /// If the values are already being compared, they
+    /// are assumed to be equal.
+    pub(crate) fn cmp_in(&self, other: &Self, pairs: &mut Pairs) -> Option<Ordering> {
+        let pair = (Ref::address(self), Ref::address(other));
+        if !pairs.insert(pair) {
+            return Some(Ordering::Equal);
+        }
+        let ordering = self.borrow().cmp_in(&other.borrow(), pairs);
+        pairs.remove(&pair);
+        ordering
+    }
+
+    /// Write the referenced value, see `Value::fmt_in`
+    fn fmt_in(&self, f: &mut Formatter, path: &mut Vec<usize>) -> Result<(), Error> {
+        let address = Ref::address(self);
+        let value = self.borrow();
+        if path.contains(&address) {
+            return match *value {
+                Value::Tree(_) => write!(f, "{{...}}"),
+                _ => write!(f, "[...]"),
+            };
+        }
+        path.push(address);
+        let result = value.fmt_in(f, path);
+        path.pop();
+        result
+    }
+
+    /// Call the referenced function, see `Value::call`.
+    /// The function is cloned out of the Ref first, so that
+    /// the function can assign to the Ref while it runs.
+    pub fn call(&self, machine: &mut Machine) {
+        self.get().call(machine)
+    }
+
+    /// Call the referenced function in the context of the
+    /// current machine, see `Value::call_global`
+    pub fn call_global(&self, machine: &mut Machine) {
+        self.get().call_global(machine)
+    }
 }
 
 /// This implementation is a hack for implementing Display for Value
@@ -188,30 +515,214 @@ impl Display for Value {
         match self {
             Self::String(s) => write!(f, "{}", s),
             Self::Number(n) => write!(f, "{}", n),
-            Self::List(l) => write!(f, "{:?}", l), // Requires the dummy debug implementation above
-            Self::Tree(t) => write!(f, "{:?}", t), // Requires the dummy debug implementation above
-            Self::Function(func) => write!(f, "{}", func),
-            Self::Error(s) => write!(f, "<Exception: '{}'>", s),
+            Self::Int(n) => write!(f, "{}", n),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Range(start, end) => write!(f, "{}..{}", start, end),
+            // Written like a Rust byte string literal
+            Self::Bytes(b) => {
+                write!(f, "b\"")?;
+                for byte in b {
+                    write!(f, "{}", core::ascii::escape_default(*byte))?;
+                }
+                write!(f, "\"")
+            }
+            Self::List(_) | Self::Tree(_) => self.fmt_in(f, &mut Vec::new()),
+            Self::Function(func) => Display::fmt(func, f), // Keeps the `{:#}` flag
+            Self::Error(e) => write!(f, "<{}: '{}'>", e.kind, e.message),
+            // The value isn't written, because it often refers back to this one
+            Self::Weak(w) if w.is_dropped() => write!(f, "<weak (dropped)>"),
+            Self::Weak(w) => write!(f, "<weak at {:#x}>", WeakRef::address(w)),
+            Self::Coroutine(c) => write!(f, "<coroutine ({})>", c.status()),
             Self::None => write!(f, "None"),
         }
     }
 }
 
-/// ############################################################
-/// The following traits are for implementing foreign functions!
-/// ############################################################
+/// Ints and Numbers are equal if they have the same numeric
+/// value, and every other value is only equal to the same variant
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_in(other, &mut Pairs::new())
+    }
+}
+
+/// Ints and Numbers are ordered by their numeric value, values of
+/// the same variant are compared by their contents, and values of
+/// different variants are ordered by the order of the variants
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.cmp_in(other, &mut Pairs::new())
+    }
+}
+
+/// The addresses of the pairs of values being compared. Lists, trees
+/// and functions can contain themselves, so a pair that is compared
+/// again inside of its own comparison is assumed to be equal, rather
+/// than being compared forever.
+pub(crate) type Pairs = BTreeSet<(usize, usize)>;
+
+impl Value {
+    /// Compare two values for equality, see `PartialEq for Value`
+    pub(crate) fn eq_in(&self, other: &Self, pairs: &mut Pairs) -> bool {
+        match (self, other) {
+            (Self::String(a), Self::String(b)) => a == b,
+            (Self::Number(a), Self::Number(b)) => a == b,
+            (Self::Int(a), Self::Int(b)) => a == b,
+            (Self::Int(a), Self::Number(b)) | (Self::Number(b), Self::Int(a)) => {
+                // The Number must be a whole number in the range of an Int
+                *a as f64 == *b && *b < i64::MAX as f64 && *b as i64 == *a
+            }
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::Bytes(a), Self::Bytes(b)) => a == b,
+            (Self::Range(a, b), Self::Range(c, d)) => (a, b) == (c, d),
+            (Self::List(a), Self::List(b)) => list_eq(a, b, pairs),
+            (Self::Tree(a), Self::Tree(b)) => map_eq(a, b, pairs),
+            (Self::Function(a), Self::Function(b)) => a.eq_in(b, pairs),
+            (Self::Error(a), Self::Error(b)) => {
+                a.kind == b.kind
+                    && a.message == b.message
+                    && match (&a.payload, &b.payload) {
+                        (Some(a), Some(b)) => a.eq_in(b, pairs),
+                        (a, b) => a.is_none() && b.is_none(),
+                    }
+            }
+            // Weak values are equal if they refer to the same value
+            (Self::Weak(a), Self::Weak(b)) => WeakRef::ptr_eq(a, b),
+            (Self::Coroutine(a), Self::Coroutine(b)) => a.eq_in(b, pairs),
+            (Self::None, Self::None) => true,
+            _ => false,
+        }
+    }
+
+    /// Order two values, see `PartialOrd for Value`
+    pub(crate) fn cmp_in(&self, other: &Self, pairs: &mut Pairs) -> Option<Ordering> {
+        match (self, other) {
+            (Self::String(a), Self::String(b)) => a.partial_cmp(b),
+            (Self::Number(a), Self::Number(b)) => a.partial_cmp(b),
+            (Self::Int(a), Self::Int(b)) => a.partial_cmp(b),
+            (Self::Int(a), Self::Number(b)) => (*a as f64).partial_cmp(b),
+            (Self::Number(a), Self::Int(b)) => a.partial_cmp(&(*b as f64)),
+            (Self::Bool(a), Self::Bool(b)) => a.partial_cmp(b),
+            (Self::Bytes(a), Self::Bytes(b)) => a.partial_cmp(b),
+            (Self::Range(a, b), Self::Range(c, d)) => (a, b).partial_cmp(&(c, d)),
+            (Self::List(a), Self::List(b)) => list_cmp(a, b, pairs),
+            (Self::Tree(a), Self::Tree(b)) => map_cmp(a, b, pairs),
+            (Self::Function(a), Self::Function(b)) => a.cmp_in(b, pairs),
+            (Self::Error(a), Self::Error(b)) => match (&a.kind, &a.message)
+                .partial_cmp(&(&b.kind, &b.message))
+            {
+                Some(Ordering::Equal) => match (&a.payload, &b.payload) {
+                    (Some(a), Some(b)) => a.cmp_in(b, pairs),
+                    (a, b) => a.is_some().partial_cmp(&b.is_some()),
+                },
+                ordering => ordering,
+            },
+            (Self::Weak(a), Self::Weak(b)) => WeakRef::address(a).partial_cmp(&WeakRef::address(b)),
+            (Self::Coroutine(a), Self::Coroutine(b)) => a.partial_cmp(b),
+            (Self::None, Self::None) => Some(Ordering::Equal),
+            (a, b) => a.variant().partial_cmp(&b.variant()),
+        }
+    }
+
+    /// Write a list or tree. PATH holds the addresses of the lists
+    /// and trees being written around it, and a value that is
+    /// inside of itself is written as `[...]` or `{...}`.
+    fn fmt_in(&self, f: &mut Formatter, path: &mut Vec<usize>) -> Result<(), Error> {
+        match self {
+            Self::List(l) => {
+                write!(f, "[")?;
+                for (i, item) in l.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    item.fmt_in(f, path)?;
+                }
+                write!(f, "]")
+            }
+            Self::Tree(t) => {
+                write!(f, "{{")?;
+                for (i, (key, item)) in t.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: ", key)?;
+                    item.fmt_in(f, path)?;
+                }
+                write!(f, "}}")
+            }
+            value => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Compare two lists of values, see `Value::eq_in`
+pub(crate) fn list_eq(a: &[Ref<Value>], b: &[Ref<Value>], pairs: &mut Pairs) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_in(b, pairs))
+}
+
+/// Compare two maps of values, like trees or registers
+pub(crate) fn map_eq<K: PartialEq>(
+    a: &BTreeMap<K, Ref<Value>>,
+    b: &BTreeMap<K, Ref<Value>>,
+    pairs: &mut Pairs,
+) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|((ka, a), (kb, b))| ka == kb && a.eq_in(b, pairs))
+}
+
+/// Order two lists of values by their first unequal elements
+pub(crate) fn list_cmp(a: &[Ref<Value>], b: &[Ref<Value>], pairs: &mut Pairs) -> Option<Ordering> {
+    for (a, b) in a.iter().zip(b) {
+        match a.cmp_in(b, pairs) {
+            Some(Ordering::Equal) => {}
+            ordering => return ordering,
+        }
+    }
+    a.len().partial_cmp(&b.len())
+}
+
+/// Order two maps of values by their first unequal members
+pub(crate) fn map_cmp<K: PartialOrd>(
+    a: &BTreeMap<K, Ref<Value>>,
+    b: &BTreeMap<K, Ref<Value>>,
+    pairs: &mut Pairs,
+) -> Option<Ordering> {
+    for ((ka, a), (kb, b)) in a.iter().zip(b) {
+        match ka.partial_cmp(kb) {
+            Some(Ordering::Equal) => {}
+            ordering => return ordering,
+        }
+        match a.cmp_in(b, pairs) {
+            Some(Ordering::Equal) => {}
+            ordering => return ordering,
+        }
+    }
+    a.len().partial_cmp(&b.len())
+}
+
+// ############################################################
+// The following traits are for implementing foreign functions!
+// ############################################################
 
 /// Convert Value into a bool
 impl From<Value> for bool {
     fn from(v: Value) -> Self {
         match v {
-            Value::String(s) => s != "", // self != ""
-            Value::Number(n) => (if n < 0.0 { -n } else { n }) > 0.000_000_000_1, // self is non-zero
-            Value::List(l) => !l.is_empty(),                                      // self is not []
-            Value::Tree(t) => !t.is_empty(),                                      // self is not {}
-            Value::Function(_) => true, // functions are true values
-            Value::Error(_) => false,   // errors are false values
-            Value::None => false,       // nones are false values
+            Value::String(s) => !s.is_empty(),           // self != ""
+            Value::Number(n) => n != 0.0 && !n.is_nan(), // self is non-zero, and a number
+            Value::Int(n) => n != 0,                     // self is non-zero
+            Value::Bool(b) => b,
+            Value::Bytes(b) => !b.is_empty(), // self is not b""
+            Value::Range(start, end) => start < end, // self is not empty
+            Value::List(l) => !l.is_empty(),  // self is not []
+            Value::Tree(t) => !t.is_empty(),  // self is not {}
+            Value::Function(_) => true,       // functions are true values
+            Value::Error(_) => false,         // errors are false values
+            Value::Weak(w) => !w.is_dropped(), // self still refers to a value
+            Value::Coroutine(c) => c.status() != Status::Finished, // self can be resumed
+            Value::None => false,             // nones are false values
         }
     }
 }
@@ -221,18 +732,30 @@ impl From<Value> for String {
     fn from(v: Value) -> Self {
         match v {
             Value::String(s) => s,
-            Value::Error(e) => e,
+            Value::Error(e) => e.message,
             _ => String::from(""),
         }
     }
 }
 
 /// Get a function from the value
-impl From<Value> for Function<Machine, (), Machine> {
+impl From<Value> for Function {
     fn from(v: Value) -> Self {
         match v {
             Value::Function(f) => f,
-            _ => Function::new(|_: &mut Machine| {}, Machine::new()),
+            _ => Function::default(),
+        }
+    }
+}
+
+/// Convert Value to unwrapped Bytes
+impl From<Value> for Vec<u8> {
+    fn from(v: Value) -> Self {
+        match v {
+            Value::Bytes(b) => b,
+            // Strings are converted to their UTF-8 encoding
+            Value::String(s) => s.into_bytes(),
+            _ => Vec::new(),
         }
     }
 }
@@ -248,7 +771,7 @@ impl From<Value> for Vec<Ref<Value>> {
 }
 
 /// Convert Value to unwrapped Tree
-impl From<Value> for BTreeMap<String, Ref<Value>> {
+impl From<Value> for BTreeMap<Key, Ref<Value>> {
     fn from(v: Value) -> Self {
         match v {
             Value::Tree(t) => t,
@@ -262,6 +785,7 @@ impl From<Value> for f64 {
     fn from(v: Value) -> Self {
         match v {
             Value::Number(n) => n,
+            Value::Int(n) => n as f64,
             _ => 0.0,
         }
     }
@@ -272,6 +796,19 @@ impl From<Value> for i32 {
     fn from(v: Value) -> Self {
         match v {
             Value::Number(n) => n as i32,
+            // Saturate instead of wrapping around
+            Value::Int(n) => n.max(i32::MIN.into()).min(i32::MAX.into()) as i32,
+            _ => 0,
+        }
+    }
+}
+
+/// Convert to integer value
+impl From<Value> for i64 {
+    fn from(v: Value) -> Self {
+        match v {
+            Value::Number(n) => n as i64,
+            Value::Int(n) => n,
             _ => 0,
         }
     }
@@ -293,8 +830,8 @@ impl From<&str> for Value {
 
 /// Make Value from bool
 impl From<bool> for Value {
-    fn from(n: bool) -> Self {
-        Value::Number(f64::from(n as i32))
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
     }
 }
 
@@ -305,10 +842,24 @@ impl From<f64> for Value {
     }
 }
 
-/// Make Value from Number
+/// Make Value from Int
 impl From<i32> for Value {
     fn from(n: i32) -> Self {
-        Value::Number(f64::from(n))
+        Value::Int(n.into())
+    }
+}
+
+/// Make Value from Int
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+/// Make Value from Bytes
+impl From<&[u8]> for Value {
+    fn from(b: &[u8]) -> Self {
+        Value::Bytes(b.to_vec())
     }
 }
 
@@ -319,23 +870,31 @@ impl From<Vec<Ref<Value>>> for Value {
     }
 }
 
-/// Make Value from Tree
+/// Make Value from a Tree with string keys. Trees with
+/// other keys can be made with `Value::Tree` directly.
 impl From<BTreeMap<String, Ref<Value>>> for Value {
     fn from(t: BTreeMap<String, Ref<Value>>) -> Self {
-        Value::Tree(t)
+        Value::Tree(t.into_iter().map(|(k, v)| (Key::String(k), v)).collect())
+    }
+}
+
+/// Make Value from Exception
+impl From<Exception> for Value {
+    fn from(e: Exception) -> Self {
+        Value::Error(e)
     }
 }
 
 /// Make Value from Function
-impl From<Function<Machine, (), Machine>> for Value {
-    fn from(f: Function<Machine, (), Machine>) -> Self {
+impl From<Function> for Value {
+    fn from(f: Function) -> Self {
         Value::Function(f)
     }
 }
 
-/// ##############################################################
-/// The following traits are for implementing operators and logic!
-/// ##############################################################
+// ##############################################################
+// The following traits are for implementing operators and logic!
+// ##############################################################
 
 /// Add two values
 impl Add<Value> for Value {
@@ -346,13 +905,24 @@ impl Add<Value> for Value {
             (Self::String(s1), Self::String(s2)) => Self::String(s1 + &s2),
             // Add two numbers
             (Self::Number(m), Self::Number(n)) => Self::Number(m + n),
+            // Add two ints, or promote the int to add it to a number
+            (Self::Int(m), Self::Int(n)) => Self::checked_int(m.checked_add(n), || {
+                format!("Integer overflow adding {} and {}", m, n)
+            }),
+            (Self::Int(m), Self::Number(n)) => Self::Number(m as f64 + n),
+            (Self::Number(m), Self::Int(n)) => Self::Number(m + n as f64),
+            // Concat two byte strings
+            (Self::Bytes(mut b1), Self::Bytes(b2)) => {
+                b1.extend(b2);
+                Self::Bytes(b1)
+            }
             // Concat two lists
             (Self::List(mut l1), Self::List(l2)) => {
                 l1.extend(l2);
                 Self::List(l1)
             }
             // Otherwise, return exception
-            (a, b) => Self::Error(format!("Could not add {} and {}", a, b)),
+            (a, b) => Self::arithmetic_error(format!("Could not add {} and {}", a, b)),
         }
     }
 }
@@ -364,8 +934,14 @@ impl Sub<Value> for Value {
         match (self, rhs) {
             // Subtract two numbers
             (Self::Number(m), Self::Number(n)) => Self::Number(m - n),
+            // Subtract two ints, or promote the int to subtract it and a number
+            (Self::Int(m), Self::Int(n)) => Self::checked_int(m.checked_sub(n), || {
+                format!("Integer overflow subtracting {} and {}", m, n)
+            }),
+            (Self::Int(m), Self::Number(n)) => Self::Number(m as f64 - n),
+            (Self::Number(m), Self::Int(n)) => Self::Number(m - n as f64),
             // Otherwise, return exception
-            (a, b) => Self::Error(format!("Could not subtract {} and {}", a, b)),
+            (a, b) => Self::arithmetic_error(format!("Could not subtract {} and {}", a, b)),
         }
     }
 }
@@ -376,11 +952,26 @@ impl Mul<Value> for Value {
     fn mul(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
             // Repeat a string
-            (Self::String(s1), Self::Number(n)) => Self::String(s1.repeat(n as usize)),
+            (Self::String(s1), Self::Number(n)) if n >= 0.0 && n as usize as f64 == n => {
+                Self::repeat(s1, n as usize)
+            }
+            (Self::String(_), Self::Number(n)) => {
+                Self::arithmetic_error(format!("Can't repeat a string {} times", n))
+            }
+            (Self::String(s1), Self::Int(n)) => match usize::try_from(n) {
+                Ok(n) => Self::repeat(s1, n),
+                Err(_) => Self::arithmetic_error(format!("Can't repeat a string {} times", n)),
+            },
             // Multiply two numbers
             (Self::Number(m), Self::Number(n)) => Self::Number(m * n),
+            // Multiply two ints, or promote the int to multiply it and a number
+            (Self::Int(m), Self::Int(n)) => Self::checked_int(m.checked_mul(n), || {
+                format!("Integer overflow multiplying {} and {}", m, n)
+            }),
+            (Self::Int(m), Self::Number(n)) => Self::Number(m as f64 * n),
+            (Self::Number(m), Self::Int(n)) => Self::Number(m * n as f64),
             // Otherwise, return exception
-            (a, b) => Self::Error(format!("Could not multiply {} and {}", a, b)),
+            (a, b) => Self::arithmetic_error(format!("Could not multiply {} and {}", a, b)),
         }
     }
 }
@@ -392,8 +983,18 @@ impl Div<Value> for Value {
         match (self, rhs) {
             // Divide two numbers
             (Self::Number(m), Self::Number(n)) => Self::Number(m / n),
+            // Divide two ints, rounding towards zero
+            (Self::Int(_), Self::Int(0)) => {
+                Self::arithmetic_error(String::from("Division by zero"))
+            }
+            (Self::Int(m), Self::Int(n)) => Self::checked_int(m.checked_div(n), || {
+                format!("Integer overflow dividing {} and {}", m, n)
+            }),
+            // Promote the int to divide it and a number
+            (Self::Int(m), Self::Number(n)) => Self::Number(m as f64 / n),
+            (Self::Number(m), Self::Int(n)) => Self::Number(m / n as f64),
             // Otherwise, return exception
-            (a, b) => Self::Error(format!("Could not divide {} and {}", a, b)),
+            (a, b) => Self::arithmetic_error(format!("Could not divide {} and {}", a, b)),
         }
     }
 }
@@ -405,8 +1006,34 @@ impl Rem<Value> for Value {
         match (self, rhs) {
             // Remainder of two numbers
             (Self::Number(m), Self::Number(n)) => Self::Number(m % n),
+            // Remainder of two ints, which has the sign of the dividend
+            (Self::Int(_), Self::Int(0)) => {
+                Self::arithmetic_error(String::from("Division by zero"))
+            }
+            (Self::Int(m), Self::Int(n)) => Self::checked_int(m.checked_rem(n), || {
+                format!("Integer overflow finding the remainder of {} and {}", m, n)
+            }),
+            // Promote the int to find the remainder of it and a number
+            (Self::Int(m), Self::Number(n)) => Self::Number(m as f64 % n),
+            (Self::Number(m), Self::Int(n)) => Self::Number(m % n as f64),
             // Otherwise, return exception
-            (a, b) => Self::Error(format!("Could not find the remainder of {} and {}", a, b)),
+            (a, b) => {
+                Self::arithmetic_error(format!("Could not find the remainder of {} and {}", a, b))
+            }
+        }
+    }
+}
+
+/// Make value negative
+impl Neg for Value {
+    type Output = Value;
+    fn neg(self) -> Self::Output {
+        match self {
+            Self::Number(n) => Self::Number(-n),
+            Self::Int(n) => Self::checked_int(n.checked_neg(), || {
+                format!("Integer overflow making {} negative", n)
+            }),
+            a => Self::arithmetic_error(format!("Could not make {} negative", a)),
         }
     }
 }
@@ -415,15 +1042,34 @@ impl Rem<Value> for Value {
 impl Not for Value {
     type Output = Value;
     fn not(self) -> Self::Output {
-        match self {
-            // Negate number
-            Self::Number(n) => match n as i32 {
-                // If number is zero, return true
-                0 => Self::Number(1.0),
-                // If number is not zero, return false
-                _ => Self::Number(0.0),
-            },
-            a => Self::Error(format!("Could not negate {}", a)),
+        // Negate the truthiness of the value
+        Self::Bool(!bool::from(self))
+    }
+}
+
+/// An iterator over the elements of a Value
+pub struct Elements(ElementsInner);
+
+enum ElementsInner {
+    Items(alloc::vec::IntoIter<Ref<Value>>),
+    // Ranges make each Int as it's visited, instead of a list of them
+    Range(core::ops::Range<i64>),
+}
+
+impl Iterator for Elements {
+    type Item = Ref<Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            ElementsInner::Items(items) => items.next(),
+            ElementsInner::Range(range) => range.next().map(Value::int),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.0 {
+            ElementsInner::Items(items) => items.size_hint(),
+            ElementsInner::Range(range) => range.size_hint(),
         }
     }
 }
@@ -431,10 +1077,11 @@ impl Not for Value {
 /// Convert Value into Iter
 impl IntoIterator for Value {
     type Item = Ref<Value>;
-    type IntoIter = alloc::vec::IntoIter<Self::Item>;
+    type IntoIter = Elements;
 
     fn into_iter(self) -> Self::IntoIter {
-        match self {
+        let items = match self {
+            Self::Range(start, end) => return Elements(ElementsInner::Range(start..end)),
             Self::List(l) => l.into_iter(),
             Self::String(s) => {
                 let mut result = vec![];
@@ -442,8 +1089,16 @@ impl IntoIterator for Value {
                     result.push(Self::string(ch.to_string()));
                 }
                 result.into_iter()
-            },
-            _ => vec![].into_iter()
-        }
+            }
+            Self::Bytes(b) => b.into_iter().map(Self::int).collect::<Vec<_>>().into_iter(),
+            // Trees are iterated as [key, value] pairs, in the order of their keys
+            Self::Tree(t) => t
+                .into_iter()
+                .map(|(key, value)| Ref::new(Self::List(vec![Ref::new(key.into()), value])))
+                .collect::<Vec<_>>()
+                .into_iter(),
+            _ => vec![].into_iter(),
+        };
+        Elements(ElementsInner::Items(items))
     }
 }
@@ -0,0 +1,459 @@
+//! The xasm assembler parses a human readable assembly
+//! syntax into programs that the Machine can run.
+//!
+//! ```text
+//! ; Store 5 in the register `n`
+//! 5 "n" store
+//!
+//! ; Push a function, and call it
+//! fn {
+//!     "n" load
+//! } call
+//! ```
+//!
+//! String and number literals are pushed onto the stack,
+//! where whole numbers like `5` are Ints and numbers with a
+//! decimal point or exponent like `5.0` or `1e3` are Numbers,
+//! `true` and `false` push booleans, `list`, `tree` and
+//! `none` push empty values, and every
+//! other word is the name of a Machine instruction method.
+//! A `fn { ... }` block pushes a function whose body is the
+//! instructions inside the braces, and a `fn [a b] { ... }`
+//! block pushes a closure that only captures the registers
+//! named in the brackets. Comments start with `;`.
+//!
+//! Programs are rendered back into xasm by the Display
+//! implementations of `Instruction` and `Literal`, or as
+//! an indented listing with offsets by `disassemble`.
+use crate::{Instruction, Literal, MAX_NESTING};
+
+// For the contents of string literals and error messages
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+// For implementing Display
+use core::fmt::{Display, Error, Formatter};
+
+/// An error produced while parsing xasm source, with
+/// the line and column (both starting at 1) where it occurred
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Parse xasm source into a program
+pub fn assemble(source: &str) -> Result<Vec<Instruction>, ParseError> {
+    let mut parser = Parser::new(source);
+    let program = parser.block()?;
+
+    // The top level block only ends at the end of the source,
+    // so anything left over is a stray closing brace
+    if parser.peek().is_some() {
+        return Err(parser.error("Unexpected `}` without a matching `fn {`"));
+    }
+
+    Ok(program)
+}
+
+/// The names of every instruction that is written as a single word
+const WORDS: &[(&str, Instruction)] = &[
+    ("list", Instruction::Push(Literal::List)),
+    ("tree", Instruction::Push(Literal::Tree)),
+    ("none", Instruction::Push(Literal::None)),
+    ("true", Instruction::Push(Literal::Bool(true))),
+    ("false", Instruction::Push(Literal::Bool(false))),
+    ("copy", Instruction::Copy),
+    ("assign", Instruction::Assign),
+    ("index", Instruction::Index),
+    ("slice", Instruction::Slice),
+    ("len", Instruction::Len),
+    ("range", Instruction::Range),
+    ("method_call", Instruction::MethodCall),
+    ("call", Instruction::Call),
+    ("for_loop", Instruction::ForLoop),
+    ("while_loop", Instruction::WhileLoop),
+    ("if_then_else", Instruction::IfThenElse),
+    ("store", Instruction::Store),
+    ("load", Instruction::Load),
+    ("throw", Instruction::Throw),
+    ("try_catch", Instruction::TryCatch),
+    ("break", Instruction::Break),
+    ("continue", Instruction::Continue),
+    ("return", Instruction::Return),
+    ("tail_call", Instruction::TailCall),
+    ("declare", Instruction::Declare),
+    ("push_scope", Instruction::PushScope),
+    ("pop_scope", Instruction::PopScope),
+    ("share", Instruction::Share),
+    ("downgrade", Instruction::Downgrade),
+    ("upgrade", Instruction::Upgrade),
+    ("coroutine", Instruction::Coroutine),
+    ("resume", Instruction::Resume),
+    ("yield", Instruction::Yield),
+    ("add", Instruction::Add),
+    ("sub", Instruction::Sub),
+    ("mul", Instruction::Mul),
+    ("div", Instruction::Div),
+    ("rem", Instruction::Rem),
+    ("neg", Instruction::Neg),
+    ("not", Instruction::Not),
+    ("eq", Instruction::Eq),
+    ("ne", Instruction::Ne),
+    ("lt", Instruction::Lt),
+    ("le", Instruction::Le),
+    ("gt", Instruction::Gt),
+    ("ge", Instruction::Ge),
+    ("and", Instruction::And),
+    ("or", Instruction::Or),
+];
+
+/// Convert the name of an instruction to the instruction itself
+fn instruction(word: &str) -> Option<Instruction> {
+    WORDS
+        .iter()
+        .find(|(name, _)| *name == word)
+        .map(|(_, instruction)| instruction.clone())
+}
+
+/// Render a program as xasm source, with one instruction per line.
+/// Each line starts with the offset of the instruction in its block,
+/// and the bodies of functions are indented under their `fn {`.
+pub fn disassemble(program: &[Instruction]) -> String {
+    let mut result = String::new();
+    disassemble_block(program, 0, &mut result);
+    result
+}
+
+/// Render a block of instructions at a given depth of indentation
+fn disassemble_block(program: &[Instruction], depth: usize, result: &mut String) {
+    let indent = "    ".repeat(depth);
+    for (offset, instruction) in program.iter().enumerate() {
+        match instruction {
+            Instruction::Push(Literal::Function(body)) => {
+                result.push_str(&format!("{:04}  {}fn {{\n", offset, indent));
+                disassemble_block(body, depth + 1, result);
+                result.push_str(&format!("      {}}}\n", indent));
+            }
+            Instruction::Push(Literal::Closure(closure)) => {
+                let (captures, body) = &**closure;
+                let captures = captures.join(" ");
+                result.push_str(&format!("{:04}  {}fn [{}] {{\n", offset, indent, captures));
+                disassemble_block(body, depth + 1, result);
+                result.push_str(&format!("      {}}}\n", indent));
+            }
+            instruction => result.push_str(&format!("{:04}  {}{}\n", offset, indent, instruction)),
+        }
+    }
+}
+
+/// Write a block of instructions on a single line, separated by spaces
+fn write_block(f: &mut Formatter, program: &[Instruction]) -> Result<(), Error> {
+    for (i, instruction) in program.iter().enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", instruction)?;
+    }
+    Ok(())
+}
+
+/// Write a function literal with its body on a single line
+pub(crate) fn write_function(f: &mut Formatter, body: &[Instruction]) -> Result<(), Error> {
+    if body.is_empty() {
+        return write!(f, "fn {{}}");
+    }
+    write!(f, "fn {{ ")?;
+    write_block(f, body)?;
+    write!(f, " }}")
+}
+
+/// Literals are displayed as the xasm source that pushes them
+impl Display for Literal {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Self::String(s) => {
+                write!(f, "\"")?;
+                for ch in s.chars() {
+                    match ch {
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\0' => write!(f, "\\0")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '"' => write!(f, "\\\"")?,
+                        ch => write!(f, "{}", ch)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            // Always render a decimal point or exponent, so that
+            // whole numbers aren't assembled back into Ints
+            Self::Number(n) => write!(f, "{:?}", n),
+            Self::Int(n) => write!(f, "{}", n),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::List => write!(f, "list"),
+            Self::Tree => write!(f, "tree"),
+            Self::None => write!(f, "none"),
+            Self::Function(body) => write_function(f, body),
+            Self::Closure(closure) => {
+                let (captures, body) = &**closure;
+                write!(f, "fn [{}] ", captures.join(" "))?;
+                if body.is_empty() {
+                    return write!(f, "{{}}");
+                }
+                write!(f, "{{ ")?;
+                write_block(f, body)?;
+                write!(f, " }}")
+            }
+        }
+    }
+}
+
+/// Instructions are displayed as their xasm source
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Self::Push(literal) => write!(f, "{}", literal),
+            instruction => {
+                let (name, _) = WORDS
+                    .iter()
+                    .find(|(_, other)| other == instruction)
+                    .expect("Every instruction has a name");
+                write!(f, "{}", name)
+            }
+        }
+    }
+}
+
+/// Returns true if this character ends a number or a word
+fn is_delimiter(ch: char) -> bool {
+    ch.is_whitespace() || "{}[]\";".contains(ch)
+}
+
+/// Keeps track of the position in the source while parsing
+struct Parser<'a> {
+    source: &'a str,
+    /// The byte offset of the next character
+    offset: usize,
+    line: usize,
+    column: usize,
+    /// How many `fn` blocks the parser is inside of
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            offset: 0,
+            line: 1,
+            column: 1,
+            depth: 0,
+        }
+    }
+
+    /// Create an error at the current position
+    fn error<S: ToString>(&self, message: S) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.column,
+            message: message.to_string(),
+        }
+    }
+
+    /// Look at the next character without consuming it
+    fn peek(&self) -> Option<char> {
+        self.source[self.offset..].chars().next()
+    }
+
+    /// Consume the next character
+    fn next(&mut self) -> Option<char> {
+        let ch = self.peek()?;
+        self.offset += ch.len_utf8();
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(ch)
+    }
+
+    /// Skip over whitespace and comments
+    fn skip_whitespace(&mut self) {
+        while let Some(ch) = self.peek() {
+            if ch == ';' {
+                while let Some(ch) = self.next() {
+                    if ch == '\n' {
+                        break;
+                    }
+                }
+            } else if ch.is_whitespace() {
+                self.next();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Consume a number or a word up to the next delimiter
+    fn word(&mut self) -> &'a str {
+        let start = self.offset;
+        while let Some(ch) = self.peek() {
+            if is_delimiter(ch) {
+                break;
+            }
+            self.next();
+        }
+        &self.source[start..self.offset]
+    }
+
+    /// Parse instructions until the end of the source or a `}`
+    fn block(&mut self) -> Result<Vec<Instruction>, ParseError> {
+        let mut program = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let ch = match self.peek() {
+                Some('}') | None => return Ok(program),
+                Some(ch) => ch,
+            };
+
+            // Remember where this token started for error messages
+            let start = self.error("");
+            if ch == '"' {
+                program.push(Instruction::Push(Literal::String(self.string()?)));
+            } else if ch == '{' {
+                return Err(self.error("Unexpected `{` without a preceding `fn`"));
+            } else if ch.is_ascii_digit() || ch == '-' || ch == '+' || ch == '.' {
+                let word = self.word();
+                // Words without a decimal point or exponent are Ints,
+                // unless they are too large to fit in an Int
+                if let Ok(n) = word.parse::<i64>() {
+                    program.push(Instruction::Push(Literal::Int(n)));
+                    continue;
+                }
+                match word.parse::<f64>() {
+                    Ok(n) => program.push(Instruction::Push(Literal::Number(n))),
+                    Err(_) => {
+                        return Err(ParseError {
+                            message: format!("Invalid number `{}`", word),
+                            ..start
+                        })
+                    }
+                }
+            } else {
+                let word = self.word();
+                if word == "fn" {
+                    program.push(Instruction::Push(self.function(start)?));
+                } else if let Some(instruction) = instruction(word) {
+                    program.push(instruction);
+                } else if let Ok(n) = word.parse::<f64>() {
+                    // Special numbers such as `inf` and `NaN`
+                    program.push(Instruction::Push(Literal::Number(n)));
+                } else {
+                    return Err(ParseError {
+                        message: format!("Unknown instruction `{}`", word),
+                        ..start
+                    });
+                }
+            }
+        }
+    }
+
+    /// Parse a function literal after the `fn` keyword, which is
+    /// a closure if its body is preceded by a `[ ... ]` capture list
+    fn function(&mut self, start: ParseError) -> Result<Literal, ParseError> {
+        self.skip_whitespace();
+        let captures = if self.peek() == Some('[') {
+            self.next();
+            Some(self.captures()?)
+        } else {
+            None
+        };
+
+        self.skip_whitespace();
+        if self.peek() != Some('{') {
+            return Err(self.error("Expected `{` after `fn`"));
+        }
+        self.next();
+
+        if self.depth == MAX_NESTING {
+            return Err(ParseError {
+                message: format!("Functions are nested deeper than {} levels", MAX_NESTING),
+                ..start
+            });
+        }
+        self.depth += 1;
+        let body = self.block();
+        self.depth -= 1;
+        let body = body?;
+        if self.next() != Some('}') {
+            return Err(ParseError {
+                message: "Unclosed `fn {` block".to_string(),
+                ..start
+            });
+        }
+        Ok(match captures {
+            Some(captures) => Literal::Closure(Box::new((captures, body))),
+            None => Literal::Function(body),
+        })
+    }
+
+    /// Parse the names of the registers a closure captures, up to the `]`
+    fn captures(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut captures = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(']') => {
+                    self.next();
+                    return Ok(captures);
+                }
+                Some(ch) if !is_delimiter(ch) => captures.push(self.word().to_string()),
+                _ => return Err(self.error("Expected a register name or `]`")),
+            }
+        }
+    }
+
+    /// Parse a string literal, with escape sequences
+    fn string(&mut self) -> Result<String, ParseError> {
+        let start = self.error("Unterminated string literal");
+        // Skip the opening quote
+        self.next();
+
+        let mut result = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(result),
+                Some('\\') => {
+                    let escape = self.error("");
+                    result.push(match self.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        Some(ch) => {
+                            return Err(ParseError {
+                                message: format!("Unknown escape sequence `\\{}`", ch),
+                                ..escape
+                            })
+                        }
+                        None => return Err(start),
+                    });
+                }
+                Some(ch) => result.push(ch),
+                None => return Err(start),
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+extern crate xmachine;
+use xmachine::{xasm, Value};
+
+mod common;
+use common::run;
+
+#[cfg(test)]
+mod boolean {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Value::from(true), Value::Bool(true));
+        assert_eq!(Value::boolean(false).get(), Value::Bool(false));
+        assert!(bool::from(Value::Bool(true)));
+        assert!(!bool::from(Value::Bool(false)));
+
+        // Bools are not numbers
+        assert_ne!(Value::from(true), Value::from(1));
+        assert_eq!(run("true 1 eq"), vec![Value::Bool(false)]);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Value::from(true).to_string(), "true");
+        assert_eq!(run("1 2 lt").pop().unwrap().to_string(), "true");
+        assert_eq!(
+            Value::from(vec![Value::boolean(false)]).to_string(),
+            "[false]"
+        );
+    }
+
+    /// Tests which values of the other variants are true
+    #[test]
+    fn truthiness() {
+        let truthy = [
+            Value::from("a"),
+            Value::from(1),
+            Value::from(-0.5),
+            Value::from(1e-12),
+            Value::from(f64::INFINITY),
+            Value::from(vec![Value::none()]),
+            Value::Function(Default::default()),
+        ];
+        let falsy = [
+            Value::from(""),
+            Value::from(0),
+            Value::from(-0.0),
+            Value::from(f64::NAN),
+            Value::list().get(),
+            Value::tree().get(),
+            Value::error("e").get(),
+            Value::None,
+        ];
+
+        // `not` agrees with the truthiness of every value
+        for value in truthy.iter() {
+            assert!(bool::from(value.clone()), "{} should be true", value);
+            assert_eq!(!value.clone(), Value::Bool(false));
+        }
+        for value in falsy.iter() {
+            assert!(!bool::from(value.clone()), "{} should be false", value);
+            assert_eq!(!value.clone(), Value::Bool(true));
+        }
+        assert_eq!(
+            run("\"\" not \"a\" not list not tree not none not"),
+            vec![
+                Value::Bool(true),
+                Value::Bool(false),
+                Value::Bool(true),
+                Value::Bool(true),
+                Value::Bool(true),
+            ]
+        );
+    }
+
+    /// Tests that logic instructions produce Bools from any operands
+    #[test]
+    fn logic() {
+        assert_eq!(
+            run("true not 0 not 0.5 not true false and 1 false or"),
+            vec![
+                Value::Bool(false),
+                Value::Bool(true),
+                Value::Bool(false),
+                Value::Bool(false),
+                Value::Bool(true),
+            ]
+        );
+        assert_eq!(
+            run("fn { \"no\" } fn { \"yes\" } fn { 2 3 gt } if_then_else"),
+            vec![Value::from("no")]
+        );
+    }
+
+    #[test]
+    fn literals() {
+        let program = xasm::assemble("true false").unwrap();
+        assert_eq!(xasm::disassemble(&program), "0000  true\n0001  false\n");
+    }
+}
@@ -0,0 +1,266 @@
+extern crate xmachine;
+use xmachine::bytecode::{self, DecodeError};
+use xmachine::{xasm, Machine, Value, MAX_NESTING};
+
+#[cfg(test)]
+mod bytecode_tests {
+    use super::*;
+
+    const SOURCE: &str = "
+        5 \"test\" store
+        fn {
+            \"test\" load
+            -1 \"test\" load \"add\" load call
+            \"test\" store
+        }
+        fn { \"test\" load }
+        while_loop
+        fn { fn { \"nested\" } list tree none } call
+    ";
+
+    #[test]
+    fn round_trip() {
+        let program = xasm::assemble(SOURCE).unwrap();
+        let bytes = bytecode::encode(&program);
+
+        assert_eq!(&bytes[..4], b"XBC\0");
+        assert_eq!(bytecode::decode(&bytes), Ok(program));
+    }
+
+    /// Tests that each constant and opcode survives encoding and decoding
+    #[test]
+    fn opcodes() {
+        let sources = [
+            "\"string\"",
+            "1.5",
+            "fn { fn {} }",
+            "list",
+            "tree",
+            "none",
+            "copy",
+            "assign",
+            "index",
+            "slice",
+            "len",
+            "range",
+            "method_call",
+            "call",
+            "for_loop",
+            "while_loop",
+            "if_then_else",
+            "store",
+            "load",
+            "throw",
+            "try_catch",
+            "share",
+            "add",
+            "sub",
+            "mul",
+            "div",
+            "rem",
+            "neg",
+            "not",
+            "eq",
+            "ne",
+            "lt",
+            "le",
+            "gt",
+            "ge",
+            "and",
+            "or",
+            "true",
+            "false",
+            "5 -3 5.0 9223372036854775807",
+            "break",
+            "continue",
+            "return",
+            "declare",
+            "push_scope",
+            "pop_scope",
+            "fn [a b] { \"a\" load } fn [] {}",
+            "downgrade",
+            "upgrade",
+            "coroutine",
+            "resume",
+            "yield",
+            "tail_call",
+        ];
+        for source in &sources {
+            let program = xasm::assemble(source).unwrap();
+            assert_eq!(bytecode::decode(&bytecode::encode(&program)), Ok(program));
+        }
+    }
+
+    /// Tests that a decoded program runs like the original
+    #[test]
+    fn run() {
+        let bytes = bytecode::encode(&xasm::assemble(SOURCE).unwrap());
+
+        let mut m = Machine::new();
+        m.push(Value::function(
+            |m: &mut Machine| {
+                let n1 = m.get_arg();
+                let n2 = m.get_arg();
+                m.return_value(n1 + n2);
+            },
+            &m,
+        ));
+        m.push(Value::string("add"));
+        m.store();
+        m.run(&bytecode::decode(&bytes).unwrap());
+
+        assert_eq!(
+            m.stack[..5],
+            [
+                Value::number(5),
+                Value::number(4),
+                Value::number(3),
+                Value::number(2),
+                Value::number(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn header() {
+        let mut bytes = bytecode::encode(&[]);
+        assert_eq!(bytecode::decode(&bytes), Ok(vec![]));
+
+        assert_eq!(bytecode::decode(b"XB"), Err(DecodeError::BadMagic));
+        assert_eq!(
+            bytecode::decode(b"XBD\0\x01\x00"),
+            Err(DecodeError::BadMagic)
+        );
+
+        bytes[4] = 3;
+        assert_eq!(
+            bytecode::decode(&bytes),
+            Err(DecodeError::UnsupportedVersion(3))
+        );
+        bytes[4] = 0;
+        assert_eq!(
+            bytecode::decode(&bytes),
+            Err(DecodeError::UnsupportedVersion(0))
+        );
+    }
+
+    /// Tests that files written with version 1 can still be decoded,
+    /// but only with the constants and opcodes that version had
+    #[test]
+    fn version_1() {
+        let program = xasm::assemble("\"x\" load 1.5 call").unwrap();
+        let mut bytes = bytecode::encode(&program);
+        assert_eq!(bytes[4], bytecode::VERSION as u8);
+        bytes[4] = 1;
+        assert_eq!(bytecode::decode(&bytes), Ok(program));
+
+        let mut bytes = bytecode::encode(&xasm::assemble("1").unwrap());
+        bytes[4] = 1;
+        assert_eq!(
+            bytecode::decode(&bytes),
+            Err(DecodeError::InvalidConstant { offset: 10 })
+        );
+
+        let mut bytes = bytecode::encode(&xasm::assemble("1.5 throw").unwrap());
+        bytes[4] = 1;
+        assert!(matches!(
+            bytecode::decode(&bytes),
+            Err(DecodeError::InvalidOpcode { opcode: 0x0f, .. })
+        ));
+    }
+
+    /// Tests that every prefix of a valid program is rejected
+    #[test]
+    fn truncated() {
+        let bytes = bytecode::encode(&xasm::assemble(SOURCE).unwrap());
+        for length in 4..bytes.len() {
+            assert_eq!(
+                bytecode::decode(&bytes[..length]),
+                Err(DecodeError::Truncated)
+            );
+        }
+
+        let mut bytes = bytes;
+        bytes.push(0);
+        assert_eq!(bytecode::decode(&bytes), Err(DecodeError::TrailingBytes));
+    }
+
+    #[test]
+    fn malformed() {
+        let header = b"XBC\0\x01\x00";
+        let decode = |rest: &[u8]| {
+            let mut bytes = header.to_vec();
+            bytes.extend_from_slice(rest);
+            bytecode::decode(&bytes)
+        };
+
+        // No functions, so no program
+        assert_eq!(
+            decode(&[0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(DecodeError::InvalidFunctionIndex(0))
+        );
+        // Unknown constant tag
+        assert_eq!(
+            decode(&[1, 0, 0, 0, 9]),
+            Err(DecodeError::InvalidConstant { offset: 10 })
+        );
+        // Invalid UTF-8 in a string constant
+        assert_eq!(
+            decode(&[1, 0, 0, 0, 0, 1, 0, 0, 0, 0xff]),
+            Err(DecodeError::InvalidConstant { offset: 10 })
+        );
+        // Unknown opcode
+        assert_eq!(
+            decode(&[0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0xee]),
+            Err(DecodeError::InvalidOpcode {
+                opcode: 0xee,
+                offset: 18
+            })
+        );
+        // Missing constant
+        assert_eq!(
+            decode(&[0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 3, 0, 0, 0]),
+            Err(DecodeError::InvalidConstantIndex(3))
+        );
+        // A function that pushes itself
+        assert_eq!(
+            decode(&[0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0]),
+            Err(DecodeError::InvalidFunctionIndex(0))
+        );
+        // A function that is pushed twice
+        assert_eq!(
+            decode(&[0, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 1, 1, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0]),
+            Err(DecodeError::InvalidFunctionIndex(1))
+        );
+    }
+
+    /// Tests that functions nested too deeply are rejected, since
+    /// the decoded program would be dropped and cloned recursively
+    #[test]
+    fn nesting() {
+        let nested = "fn { ".repeat(MAX_NESTING) + &"}".repeat(MAX_NESTING);
+        let program = xasm::assemble(&nested).unwrap();
+        assert_eq!(bytecode::decode(&bytecode::encode(&program)), Ok(program));
+
+        // Each function pushes the next one
+        let chain = |count: u32| {
+            let mut bytes = b"XBC\0\x02\x00\0\0\0\0".to_vec();
+            bytes.extend_from_slice(&count.to_le_bytes());
+            for i in 1..count {
+                bytes.extend_from_slice(&[1, 0, 0, 0, 1]);
+                bytes.extend_from_slice(&i.to_le_bytes());
+            }
+            bytes.extend_from_slice(&[0, 0, 0, 0]);
+            bytes
+        };
+        assert!(bytecode::decode(&chain(MAX_NESTING as u32 + 1)).is_ok());
+        assert_eq!(
+            bytecode::decode(&chain(MAX_NESTING as u32 + 2)),
+            Err(DecodeError::NestedTooDeeply)
+        );
+        assert_eq!(
+            bytecode::decode(&chain(500_000)),
+            Err(DecodeError::NestedTooDeeply)
+        );
+    }
+}
@@ -0,0 +1,131 @@
+extern crate xmachine;
+use xmachine::{ErrorKind, Machine, Ref, Value};
+
+mod common;
+use common::{fail, run_on};
+
+#[cfg(test)]
+mod bytes {
+    use super::*;
+
+    /// Make a machine with the given bytes in the register `data`
+    fn machine(data: &[u8]) -> Machine {
+        let mut m = Machine::new();
+        m.push(Value::bytes(data));
+        m.push(Value::string("data"));
+        m.store();
+        m
+    }
+
+    #[test]
+    fn conversions() {
+        let data: &[u8] = &[0, 1, 255];
+        assert_eq!(Value::from(data), Value::Bytes(vec![0, 1, 255]));
+        assert_eq!(Value::bytes(vec![7]).get(), Value::Bytes(vec![7]));
+        assert_eq!(Vec::<u8>::from(Value::from(data)), data);
+        assert_eq!(Vec::<u8>::from(Value::from("hi")), b"hi");
+
+        assert!(bool::from(Value::from(data)));
+        assert!(!bool::from(Value::bytes(Vec::new()).get()));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            Value::from(&b"ab\"\x00\xff\n"[..]).to_string(),
+            "b\"ab\\\"\\x00\\xff\\n\""
+        );
+    }
+
+    /// Tests that bytes are indexed as Ints, by Ints or by the name of the index
+    #[test]
+    fn index() {
+        let mut data = Value::Bytes(vec![10, 20, 30]);
+        assert_eq!(data.index(1), Value::int(20));
+        assert_eq!(data.index_value(&Value::Int(2)), Value::int(30));
+        assert_eq!(
+            data.try_index(3).unwrap_err().message,
+            "Bytes index out of bounds"
+        );
+
+        assert_eq!(
+            run_on(
+                &mut machine(&[10, 20, 30]),
+                "\"data\" load 0 index \"data\" load len"
+            ),
+            vec![Value::Int(10), Value::Int(3)]
+        );
+    }
+
+    #[test]
+    fn slice() {
+        assert_eq!(
+            run_on(
+                &mut machine(&[1, 2, 3, 4]),
+                "
+                \"data\" load 1 3 slice
+                \"data\" load 2 none slice
+                \"data\" load 4 4 slice
+                \"héllo\" 1 3 slice
+                "
+            ),
+            vec![
+                Value::Bytes(vec![2, 3]),
+                Value::Bytes(vec![3, 4]),
+                Value::Bytes(vec![]),
+                Value::from("él"),
+            ]
+        );
+        assert_eq!(
+            fail(&mut machine(&[1, 2]), "\"data\" load 1 3 slice"),
+            (
+                ErrorKind::InvalidIndex,
+                String::from("Slice 1..3 out of bounds for length 2")
+            )
+        );
+        assert_eq!(
+            fail(&mut machine(&[1, 2]), "\"data\" load -1 none slice").1,
+            "Can't slice with -1"
+        );
+        assert_eq!(fail(&mut machine(&[]), "5 0 1 slice").1, "Can't slice 5");
+    }
+
+    /// Tests that the slice of a list shares its elements with the list
+    #[test]
+    fn slice_list() {
+        let list = Value::from(vec![Value::int(1), Value::int(2), Value::int(3)]);
+        let slice = list.slice(1, 3);
+        assert_eq!(
+            slice,
+            Ref::new(Value::from(vec![Value::int(2), Value::int(3)]))
+        );
+
+        match (&list, slice.get()) {
+            (Value::List(l), Value::List(s)) => assert!(Ref::ptr_eq(&l[1], &s[0])),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn concat_and_iterate() {
+        let joined = Value::from(&[1u8, 2][..]) + Value::from(&[3u8][..]);
+        assert_eq!(joined, Value::Bytes(vec![1, 2, 3]));
+        assert_eq!(
+            joined.into_iter().collect::<Vec<_>>(),
+            vec![Value::int(1), Value::int(2), Value::int(3)]
+        );
+
+        assert_eq!(
+            run_on(
+                &mut machine(&[5, 6]),
+                "
+                0 \"sum\" store
+                fn { \"sum\" load \"byte\" load add \"sum\" store }
+                \"data\" load \"byte\" \"i\" for_loop
+                \"sum\" load
+                "
+            ),
+            vec![Value::Int(11)]
+        );
+    }
+}
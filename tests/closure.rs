@@ -1,5 +1,10 @@
+#![allow(clippy::clone_on_copy)]
+
 extern crate xmachine;
-use xmachine::{Machine, Value};
+use xmachine::{xasm, ErrorKind, Instruction, Literal, Machine, Value};
+
+mod common;
+use common::run;
 
 #[cfg(test)]
 mod closure_tests {
@@ -22,4 +27,210 @@ mod closure_tests {
 
         assert_eq!(n, 0);
     }
+
+    /// Tests that functions capture copies of registers by default
+    #[test]
+    fn copy_on_capture() {
+        assert_eq!(
+            run("
+                1 \"x\" store
+                fn { \"x\" load } \"f\" store
+                5 \"x\" store
+                \"f\" load call
+                "),
+            vec![Value::from(1)]
+        );
+    }
+
+    /// Tests that a shared register is seen by the closure and its definer
+    #[test]
+    fn shared_counter() {
+        assert_eq!(
+            run("
+                0 \"count\" store
+                fn { \"count\" load 1 add \"count\" store } \"count\" share \"inc\" store
+                fn { \"count\" load } \"count\" share \"get\" store
+                \"inc\" load call
+                \"inc\" load call
+                \"get\" load call
+                10 \"count\" store
+                \"get\" load call
+                \"count\" load
+                "),
+            vec![Value::from(2), Value::from(10), Value::from(10)]
+        );
+    }
+
+    /// Tests that each call of a function makes a new register to share
+    #[test]
+    fn counter_factory() {
+        assert_eq!(
+            run("
+                fn {
+                    0 \"count\" store
+                    fn { \"count\" load 1 add \"count\" store \"count\" load } \"count\" share
+                } \"make_counter\" store
+                \"make_counter\" load call \"a\" store
+                \"make_counter\" load call \"b\" store
+                \"a\" load call
+                \"a\" load call
+                \"b\" load call
+                "),
+            vec![Value::from(1), Value::from(2), Value::from(1)]
+        );
+    }
+
+    /// Tests that storing a shared register in another
+    /// register doesn't share the other register too
+    #[test]
+    fn unshared_copies() {
+        assert_eq!(
+            run("
+                0 \"count\" store
+                fn {} \"count\" share \"f\" store
+                \"count\" load \"alias\" store
+                10 \"alias\" store
+                \"count\" load
+                "),
+            vec![Value::from(0)]
+        );
+    }
+
+    /// Tests that assigning into a shared list or tree, in the
+    /// closure or outside of it, changes the shared register
+    #[test]
+    fn shared_collections() {
+        let stack = run("
+            tree \"obj\" store
+            list \"xs\" store
+            fn {
+                2 \"obj\" load \"j\" index assign
+                \"b\" \"xs\" load 1 index assign
+            } \"obj\" share \"xs\" share \"f\" store
+            1 \"obj\" load \"k\" index assign
+            \"a\" \"xs\" load 0 index assign
+            \"f\" load call
+            \"obj\" load \"xs\" load
+            ");
+        assert_eq!(stack[0].to_string(), "{\"j\": 2, \"k\": 1}");
+        assert_eq!(stack[1].to_string(), "[a, b]");
+    }
+
+    #[test]
+    fn share_errors() {
+        let mut m = Machine::new();
+        let e = m
+            .try_run(&xasm::assemble("fn {} \"missing\" share").unwrap())
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::UndefinedRegister);
+
+        let e = m
+            .try_run(&xasm::assemble("1 \"x\" store 2 \"x\" share").unwrap())
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::NotCallable);
+        assert_eq!(e.message, "Can't share x with non-function 2");
+
+        // A list that contains itself can still be printed in the error
+        let e = m
+            .try_run(
+                &xasm::assemble(
+                    "list \"l\" store \"l\" load \"l\" load 0 index assign \"l\" load 0 index \"x\" share",
+                )
+                .unwrap(),
+            )
+            .unwrap_err();
+        assert_eq!(e.message, "Can't share x with non-function [[[...]]]");
+    }
+
+    /// Tests that closures only capture copies of the registers they name
+    #[test]
+    fn explicit_captures() {
+        assert_eq!(
+            run("
+                1 \"x\" store
+                2 \"y\" store
+                fn [x] { \"x\" load } \"f\" store
+                5 \"x\" store
+                \"f\" load call
+                "),
+            vec![Value::from(1)]
+        );
+
+        let mut m = Machine::new();
+        let e = m
+            .try_run(
+                &xasm::assemble("1 \"x\" store 2 \"y\" store fn [x] { \"y\" load } call").unwrap(),
+            )
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::UndefinedRegister);
+
+        // Captured registers can be shared like any other
+        assert_eq!(
+            run("
+                0 \"n\" store
+                fn [n] { \"n\" load 1 add \"n\" store } \"n\" share call
+                \"n\" load
+                "),
+            vec![Value::from(1)]
+        );
+    }
+
+    #[test]
+    fn native_captures() {
+        let mut m = Machine::new();
+        m.push(Value::int(3));
+        m.push(Value::string("x"));
+        m.store();
+        m.push(Value::string("big"));
+        m.push(Value::string("y"));
+        m.store();
+
+        let f = Value::closure(
+            |m: &mut Machine| {
+                assert!(m.stack.is_empty());
+                assert_eq!(m.registers.len(), 1);
+                m.push(Value::string("x"));
+                m.load();
+            },
+            &m,
+            &["x", "missing"],
+        );
+        m.push(f);
+        m.call();
+        assert_eq!(m.pop().get(), Value::from(3));
+    }
+
+    #[test]
+    fn closure_literals() {
+        let program = xasm::assemble("fn [a b] { \"a\" load } fn [] {}").unwrap();
+        assert_eq!(
+            program,
+            vec![
+                Instruction::Push(Literal::Closure(Box::new((
+                    vec![String::from("a"), String::from("b")],
+                    vec![
+                        Instruction::Push(Literal::String(String::from("a"))),
+                        Instruction::Load,
+                    ]
+                )))),
+                Instruction::Push(Literal::Closure(Box::new((vec![], vec![])))),
+            ]
+        );
+
+        let rendered = program
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(rendered, "fn [a b] { \"a\" load } fn [] {}");
+        assert_eq!(
+            xasm::disassemble(&program),
+            "0000  fn [a b] {\n0000      \"a\"\n0001      load\n      }\n0001  fn [] {\n      }\n"
+        );
+
+        assert_eq!(
+            xasm::assemble("fn [a \"b\"] {}").unwrap_err().message,
+            "Expected a register name or `]`"
+        );
+    }
 }
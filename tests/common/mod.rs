@@ -0,0 +1,50 @@
+// Each test crate only uses some of these helpers
+#![allow(dead_code)]
+
+use xmachine::{xasm, ErrorKind, Machine, MachineError, Value};
+
+/// Run a program on the Machine, and keep what it leaves on the stack
+pub fn try_exec(m: &mut Machine, source: &str) -> Result<(), MachineError> {
+    m.try_run(&xasm::assemble(source).unwrap())
+}
+
+/// Run a program on the Machine, panicking if it raises an error
+pub fn exec(m: &mut Machine, source: &str) {
+    try_exec(m, source).unwrap();
+}
+
+/// Take every value off of the Machine's stack
+pub fn stack(m: &mut Machine) -> Vec<Value> {
+    m.stack.drain(..).map(|v| v.get()).collect()
+}
+
+/// Run a program on the Machine, and take what it leaves on
+/// the stack. The stack is cleared even if the program fails.
+pub fn try_run_on(m: &mut Machine, source: &str) -> Result<Vec<Value>, MachineError> {
+    let result = try_exec(m, source);
+    let stack = stack(m);
+    result.map(|()| stack)
+}
+
+/// Run a program on the Machine, and take what it leaves on the stack
+pub fn run_on(m: &mut Machine, source: &str) -> Vec<Value> {
+    try_run_on(m, source).unwrap()
+}
+
+/// Run a program on the Machine that should fail,
+/// and get the kind and message of its error
+pub fn fail(m: &mut Machine, source: &str) -> (ErrorKind, String) {
+    let e = try_run_on(m, source).unwrap_err();
+    (e.kind, e.message)
+}
+
+/// Run a program on a new Machine, and get what it leaves
+/// on the stack, or the kind and message of its error
+pub fn try_run(source: &str) -> Result<Vec<Value>, (ErrorKind, String)> {
+    try_run_on(&mut Machine::new(), source).map_err(|e| (e.kind, e.message))
+}
+
+/// Run a program on a new Machine, and get what it leaves on the stack
+pub fn run(source: &str) -> Vec<Value> {
+    run_on(&mut Machine::new(), source)
+}
@@ -0,0 +1,133 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Machine, Value};
+
+mod common;
+use common::run;
+
+#[cfg(test)]
+mod control {
+    use super::*;
+
+    fn ints(values: &[i64]) -> Vec<Value> {
+        values.iter().map(|n| Value::Int(*n)).collect()
+    }
+
+    #[test]
+    fn while_loop() {
+        // Counts up, skipping 2 and stopping at 4
+        assert_eq!(
+            run("
+                0 \"i\" store
+                fn {
+                    \"i\" load 1 add \"i\" store
+                    fn {} fn { continue } fn { \"i\" load 2 eq } if_then_else
+                    fn {} fn { break } fn { \"i\" load 4 eq } if_then_else
+                    \"i\" load
+                }
+                fn { true }
+                while_loop
+                "),
+            ints(&[1, 3])
+        );
+    }
+
+    #[test]
+    fn for_loop() {
+        assert_eq!(
+            run("
+                fn {
+                    fn {} fn { continue } fn { \"x\" load 1 eq } if_then_else
+                    fn {} fn { break } fn { \"x\" load 3 eq } if_then_else
+                    \"x\" load
+                }
+                0 5 range \"x\" \"i\" for_loop
+                "),
+            ints(&[0, 2])
+        );
+    }
+
+    /// Tests that `break` only stops the innermost loop
+    #[test]
+    fn nested() {
+        assert_eq!(
+            run("
+                fn {
+                    fn {
+                        fn {} fn { break } fn { \"y\" load 2 eq } if_then_else
+                        \"x\" load 10 mul \"y\" load add
+                    }
+                    0 3 range \"y\" \"j\" for_loop
+                }
+                0 2 range \"x\" \"i\" for_loop
+                "),
+            ints(&[0, 1, 10, 11])
+        );
+    }
+
+    /// Tests that `return` leaves a function from inside of a loop
+    #[test]
+    fn return_early() {
+        assert_eq!(
+            run("
+                fn {
+                    fn {
+                        fn {} fn { \"x\" load return } fn { \"x\" load 2 gt } if_then_else
+                    }
+                    0 10 range \"x\" \"i\" for_loop
+                    none
+                }
+                \"first_over_two\" store
+                \"first_over_two\" load call
+                \"after\"
+                "),
+            vec![Value::Int(3), Value::from("after")]
+        );
+
+        // A `return` at the top level ends the program
+        assert_eq!(run("1 2 return 3"), ints(&[1, 2]));
+    }
+
+    /// Tests that signals outside of loops and functions become errors
+    #[test]
+    fn escaped() {
+        let mut m = Machine::new();
+        let e = m.try_run(&xasm::assemble("break").unwrap()).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::ControlFlow);
+        assert_eq!(e.message, "`break` outside of a loop");
+
+        // A break can't leave the function it is in
+        let e = m
+            .try_run(&xasm::assemble("fn { fn { continue } call } fn { true } while_loop").unwrap())
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::ControlFlow);
+        assert_eq!(e.message, "`continue` outside of a loop");
+
+        // The lenient instructions push the error
+        let mut m = Machine::new();
+        m.break_loop();
+        match m.pop().get() {
+            Value::Error(e) => assert_eq!(e.kind, ErrorKind::ControlFlow),
+            value => panic!("expected an error, got {}", value),
+        }
+    }
+
+    /// Tests that try_catch lets signals through, but still runs `finally`
+    #[test]
+    fn try_catch() {
+        assert_eq!(
+            run("
+                fn {
+                    fn { \"finally\" }
+                    fn { \"caught\" }
+                    fn { break }
+                    try_catch
+                    \"unreachable\"
+                }
+                fn { true }
+                while_loop
+                \"done\"
+                "),
+            vec![Value::from("finally"), Value::from("done")]
+        );
+    }
+}
@@ -0,0 +1,415 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Machine, Status, Value};
+
+mod common;
+use common::{fail, run_on};
+
+#[cfg(test)]
+mod coroutine {
+    use super::*;
+
+    fn status(m: &Machine, register: &str) -> Status {
+        match m.registers[register].get() {
+            Value::Coroutine(c) => c.status(),
+            other => panic!("Expected a coroutine, got {}", other),
+        }
+    }
+
+    /// Tests a generator that yields from inside of a for_loop
+    #[test]
+    fn generator() {
+        let mut m = Machine::new();
+        run_on(
+            &mut m,
+            "
+            fn {
+                \"limit\" store
+                fn { \"i\" load yield \"sent\" store } 0 \"limit\" load range \"i\" \"n\" for_loop
+                \"done\" return
+            } coroutine \"gen\" store
+            ",
+        );
+
+        // The first value is the argument of the function
+        let resume = "none \"gen\" load resume";
+        assert_eq!(
+            run_on(&mut m, "3 \"gen\" load resume"),
+            vec![Value::from(0), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, resume),
+            vec![Value::from(1), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, resume),
+            vec![Value::from(2), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, resume),
+            vec![Value::from("done"), Value::from(false)]
+        );
+        assert_eq!(
+            fail(&mut m, resume),
+            (
+                ErrorKind::ControlFlow,
+                String::from("Can't resume a finished coroutine")
+            )
+        );
+    }
+
+    /// Tests that the stack and registers of the coroutine
+    /// are kept while it is suspended
+    #[test]
+    fn state() {
+        let mut m = Machine::new();
+        run_on(
+            &mut m,
+            "
+            fn {
+                \"x\" store
+                1 2
+                \"x\" load yield \"y\" store
+                \"x\" load \"y\" load add yield \"z\" store
+                add return
+            } coroutine \"co\" store
+            ",
+        );
+        assert_eq!(
+            run_on(&mut m, "10 \"co\" load resume"),
+            vec![Value::from(10), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, "5 \"co\" load resume"),
+            vec![Value::from(15), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, "none \"co\" load resume"),
+            vec![Value::from(3), Value::from(false)]
+        );
+
+        // The caller's registers aren't changed
+        assert_eq!(fail(&mut m, "\"x\" load").0, ErrorKind::UndefinedRegister);
+    }
+
+    /// Tests sending values into a coroutine that never returns
+    #[test]
+    fn send() {
+        let mut m = Machine::new();
+        run_on(
+            &mut m,
+            "
+            fn {
+                \"total\" store
+                fn {
+                    \"total\" load yield
+                    \"total\" load add \"total\" store
+                } fn { true } while_loop
+            } coroutine \"sum\" store
+            ",
+        );
+        assert_eq!(
+            run_on(&mut m, "0 \"sum\" load resume"),
+            vec![Value::from(0), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, "5 \"sum\" load resume"),
+            vec![Value::from(5), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, "10 \"sum\" load resume"),
+            vec![Value::from(15), Value::from(true)]
+        );
+    }
+
+    /// Tests yielding from inside of functions and
+    /// methods that the coroutine calls
+    #[test]
+    fn calls() {
+        let mut m = Machine::new();
+        run_on(
+            &mut m,
+            "
+            fn { yield } \"wait\" store
+            tree \"obj\" store
+            fn { \"self\" store \"method\" yield } \"obj\" load \"step\" index assign
+            fn {
+                \"arg\" store
+                \"a\" \"wait\" load call \"b\" store
+                \"obj\" load \"step\" method_call \"c\" store
+                \"b\" load \"c\" load add return
+            } coroutine \"co\" store
+            ",
+        );
+        assert_eq!(
+            run_on(&mut m, "none \"co\" load resume"),
+            vec![Value::from("a"), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, "\"x\" \"co\" load resume"),
+            vec![Value::from("method"), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, "\"y\" \"co\" load resume"),
+            vec![Value::from("xy"), Value::from(false)]
+        );
+    }
+
+    /// Tests break, continue and return inside of a coroutine
+    #[test]
+    fn control() {
+        let mut m = Machine::new();
+        run_on(
+            &mut m,
+            "
+            fn {
+                \"arg\" store
+                fn {
+                    fn {} fn { continue } fn { \"i\" load 1 eq } if_then_else
+                    fn {} fn { break } fn { \"i\" load 3 eq } if_then_else
+                    \"i\" load yield \"sent\" store
+                } 0 10 range \"i\" \"n\" for_loop
+                fn { \"done\" return } fn { true } while_loop
+            } coroutine \"co\" store
+            ",
+        );
+        let resume = "none \"co\" load resume";
+        assert_eq!(
+            run_on(&mut m, resume),
+            vec![Value::from(0), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, resume),
+            vec![Value::from(2), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, resume),
+            vec![Value::from("done"), Value::from(false)]
+        );
+    }
+
+    /// Tests yielding from inside of the blocks of a `try_catch`,
+    /// which still catches errors when the coroutine is resumed
+    #[test]
+    fn try_catch() {
+        let mut m = Machine::new();
+        run_on(
+            &mut m,
+            "
+            fn {
+                \"arg\" store
+                fn { \"finally\" yield }
+                fn { \"e\" store \"caught\" yield }
+                fn { \"body\" yield \"missing\" load }
+                try_catch
+            } coroutine \"co\" store
+            ",
+        );
+        let resume = "none \"co\" load resume";
+        assert_eq!(
+            run_on(&mut m, resume),
+            vec![Value::from("body"), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, resume),
+            vec![Value::from("caught"), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, resume),
+            vec![Value::from("finally"), Value::from(true)]
+        );
+        assert_eq!(
+            run_on(&mut m, resume),
+            vec![Value::None, Value::from(false)]
+        );
+    }
+
+    /// Tests iterating over the values a coroutine yields
+    #[test]
+    fn for_loop() {
+        let mut m = Machine::new();
+        assert_eq!(
+            run_on(
+                &mut m,
+                "
+                fn {
+                    \"arg\" store
+                    fn { \"i\" load 10 mul yield \"sent\" store } 1 4 range \"i\" \"c\" for_loop
+                    \"ignored\" return
+                } coroutine \"gen\" store
+                fn { \"c\" load \"x\" load } \"gen\" load \"x\" \"c\" for_loop
+                ",
+            ),
+            vec![
+                Value::from(0),
+                Value::from(10),
+                Value::from(1),
+                Value::from(20),
+                Value::from(2),
+                Value::from(30),
+            ]
+        );
+        assert_eq!(status(&m, "gen"), Status::Finished);
+    }
+
+    /// Tests that copying a coroutine makes one that carries on by itself
+    #[test]
+    fn copy() {
+        let mut m = Machine::new();
+        run_on(
+            &mut m,
+            "
+            fn {
+                \"arg\" store
+                fn { \"i\" load yield \"sent\" store } 0 10 range \"i\" \"n\" for_loop
+            } coroutine \"gen\" store
+            none \"gen\" load resume
+            \"gen\" load copy \"fork\" store
+            ",
+        );
+        assert_eq!(
+            run_on(
+                &mut m,
+                "
+                none \"gen\" load resume
+                none \"gen\" load resume
+                none \"fork\" load resume
+                "
+            ),
+            vec![
+                Value::from(1),
+                Value::from(true),
+                Value::from(2),
+                Value::from(true),
+                Value::from(1),
+                Value::from(true),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut m = Machine::new();
+        let outside = (
+            ErrorKind::ControlFlow,
+            String::from("`yield` outside of a coroutine"),
+        );
+        assert_eq!(fail(&mut m, "5 yield"), outside);
+        assert_eq!(fail(&mut m, "5 fn { yield } call"), outside);
+
+        // Functions called by native functions can't be suspended
+        m.registers.insert(
+            String::from("apply"),
+            Value::function(
+                |m: &mut Machine| {
+                    let f = m.get_arg();
+                    if let Err(e) = f.try_call(m) {
+                        m.raise(e.into());
+                    }
+                },
+                &Machine::new(),
+            ),
+        );
+        assert_eq!(
+            fail(
+                &mut m,
+                "
+                fn { fn { 1 yield } \"apply\" load call } coroutine \"co\" store
+                none \"co\" load resume
+                "
+            ),
+            outside
+        );
+        assert_eq!(status(&m, "co"), Status::Finished);
+
+        assert_eq!(
+            fail(
+                &mut m,
+                "
+                fn { \"self\" store none \"self\" load resume } coroutine \"co\" store
+                \"co\" load \"co\" load resume
+                "
+            ),
+            (
+                ErrorKind::ControlFlow,
+                String::from("Can't resume a running coroutine")
+            )
+        );
+        assert_eq!(
+            fail(&mut m, "none 5 resume"),
+            (
+                ErrorKind::NotCallable,
+                String::from("Can't resume non-coroutine 5")
+            )
+        );
+        // A list that contains itself can still be printed in the error
+        assert_eq!(
+            fail(
+                &mut m,
+                "list \"l\" store \"l\" load \"l\" load 0 index assign none \"l\" load 0 index resume"
+            ),
+            (
+                ErrorKind::NotCallable,
+                String::from("Can't resume non-coroutine [[[...]]]")
+            )
+        );
+
+        // Native functions can't be suspended
+        m.push(Value::function(|_: &mut Machine| {}, &Machine::new()));
+        assert_eq!(m.try_coroutine().unwrap_err().kind, ErrorKind::NotCallable);
+    }
+
+    /// Tests that an error inside of a coroutine finishes it,
+    /// with the coroutine in the backtrace
+    #[test]
+    fn trace() {
+        let mut m = Machine::new();
+        run_on(
+            &mut m,
+            "fn { 1 yield \"missing\" load } coroutine \"co\" store none \"co\" load resume",
+        );
+        let e = m
+            .try_run(&xasm::assemble("none \"co\" load resume").unwrap())
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::UndefinedRegister);
+        assert_eq!(e.offset, Some(3));
+        assert_eq!(e.trace.len(), 2);
+        assert_eq!(e.trace[0].function, "<main>");
+        assert_eq!(e.trace[1].offset, Some(3));
+        assert!(m.frames().is_empty());
+        assert_eq!(status(&m, "co"), Status::Finished);
+    }
+
+    /// Tests that a coroutine that refers to itself is collected
+    #[test]
+    fn cycle() {
+        let mut m = Machine::new();
+        run_on(
+            &mut m,
+            "
+            fn { \"self\" store 1 yield } coroutine \"co\" store
+            \"co\" load \"co\" load resume
+            \"co\" load downgrade \"weak\" store
+            none \"co\" store
+            ",
+        );
+        assert!(m.collect_garbage() > 0);
+        assert_eq!(run_on(&mut m, "\"weak\" load upgrade"), vec![Value::None]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = "fn { yield } coroutine none \"co\" store resume";
+        let program = xasm::assemble(source).unwrap();
+        assert_eq!(
+            program.iter().map(|i| i.to_string()).collect::<Vec<_>>(),
+            [
+                "fn { yield }",
+                "coroutine",
+                "none",
+                "\"co\"",
+                "store",
+                "resume"
+            ]
+        );
+    }
+}
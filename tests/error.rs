@@ -0,0 +1,118 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Instruction, Literal, Machine, Value};
+
+#[cfg(test)]
+mod error {
+    use super::*;
+
+    #[test]
+    fn try_pop() {
+        let mut m = Machine::new();
+        assert_eq!(m.try_pop().unwrap_err().kind, ErrorKind::StackUnderflow);
+
+        m.push(Value::number(1));
+        assert_eq!(m.try_pop(), Ok(Value::number(1)));
+    }
+
+    #[test]
+    fn try_load() {
+        let mut m = Machine::new();
+        m.push(Value::string("missing"));
+
+        let e = m.try_load().unwrap_err();
+        assert_eq!(e.kind, ErrorKind::UndefinedRegister);
+        assert_eq!(e.message, "No register named missing");
+        assert!(m.stack.is_empty());
+    }
+
+    #[test]
+    fn try_call() {
+        let mut m = Machine::new();
+        m.push(Value::number(5));
+        assert_eq!(m.try_call().unwrap_err().kind, ErrorKind::NotCallable);
+
+        m.push(Value::list());
+        m.push(Value::string("a"));
+        assert_eq!(m.try_index().unwrap_err().kind, ErrorKind::InvalidIndex);
+    }
+
+    /// Tests that the infallible instructions push errors and carry on
+    #[test]
+    fn lenient() {
+        let mut m = Machine::new();
+        m.run(&xasm::assemble("\"missing\" load 5 call 1").unwrap());
+
+        assert_eq!(
+            m.stack,
+            vec![
+                Value::exception(ErrorKind::UndefinedRegister, "No register named missing"),
+                Value::exception(ErrorKind::NotCallable, "Can't call non-function 5"),
+                Value::number(1),
+            ]
+        );
+    }
+
+    /// Tests that `try_run` stops at the first error,
+    /// and reports which instruction raised it
+    #[test]
+    fn try_run() {
+        let mut m = Machine::new();
+        let e = m
+            .try_run(&xasm::assemble("1 \"missing\" load 2").unwrap())
+            .unwrap_err();
+
+        assert_eq!(e.kind, ErrorKind::UndefinedRegister);
+        assert_eq!(e.offset, Some(2));
+        assert_eq!(e.instruction.as_deref(), Some(&Instruction::Load));
+        assert_eq!(
+            e.to_string(),
+            "UndefinedRegister: No register named missing (at instruction 2, `load`)\n    in <main> at instruction 2"
+        );
+        assert_eq!(m.stack, vec![Value::number(1)]);
+    }
+
+    /// Tests that errors inside of function bodies and loops abort the whole program
+    #[test]
+    fn nested() {
+        let mut m = Machine::new();
+        let e = m
+            .try_run(
+                &xasm::assemble(
+                    "
+                    fn {
+                        fn { \"x\" \"y\" index } call
+                        \"unreachable\"
+                    }
+                    fn { 1 }
+                    while_loop
+                    ",
+                )
+                .unwrap(),
+            )
+            .unwrap_err();
+
+        assert_eq!(e.kind, ErrorKind::InvalidIndex);
+        assert_eq!(e.offset, Some(2));
+        assert_eq!(e.instruction.as_deref(), Some(&Instruction::Index));
+        assert!(!m.stack.contains(&Value::string("unreachable")));
+
+        // Without `try_run`, a failed call pushes its error and the program carries on
+        let mut m = Machine::new();
+        m.run(&[
+            Instruction::Push(Literal::Function(vec![
+                Instruction::Push(Literal::String(String::from("missing"))),
+                Instruction::Load,
+                Instruction::Push(Literal::Number(1.0)),
+            ])),
+            Instruction::Call,
+            Instruction::Push(Literal::Number(2.0)),
+        ]);
+        assert_eq!(
+            m.stack,
+            vec![
+                Value::exception(ErrorKind::UndefinedRegister, "No register named missing"),
+                Value::number(2)
+            ]
+        );
+    }
+}
@@ -0,0 +1,168 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Exception, Machine, Ref, Value};
+
+mod common;
+use common::exec;
+
+#[cfg(test)]
+mod exception {
+    use super::*;
+
+    /// Tests that a thrown error is caught by the handler, and that
+    /// the values pushed by the body before the error are dropped
+    #[test]
+    fn throw() {
+        let mut m = Machine::new();
+        exec(
+            &mut m,
+            "
+            \"below\"
+            none
+            fn {
+                \"e\" store
+                \"e\" load \"kind\" index
+                \"e\" load \"message\" index
+                \"e\" load \"payload\" index
+            }
+            fn {
+                \"dropped\"
+                42 \"not found\" \"NotFound\" throw
+                \"unreachable\"
+            }
+            try_catch
+            ",
+        );
+
+        assert_eq!(
+            m.stack,
+            vec![
+                Value::string("below"),
+                Value::string("NotFound"),
+                Value::string("not found"),
+                Value::number(42),
+            ]
+        );
+    }
+
+    /// Tests that errors raised by instructions can be caught
+    #[test]
+    fn instruction_error() {
+        let mut m = Machine::new();
+        exec(
+            &mut m,
+            "
+            none
+            fn { \"kind\" index }
+            fn { fn { \"missing\" load } call }
+            try_catch
+            ",
+        );
+
+        assert_eq!(m.stack, vec![Value::string("UndefinedRegister")]);
+    }
+
+    /// Tests that errors from foreign functions and failed arithmetic can be caught
+    #[test]
+    fn foreign_error() {
+        let mut m = Machine::new();
+        m.push(Value::function(
+            |m: &mut Machine| {
+                let n1 = m.get_arg();
+                let n2 = m.get_arg();
+                m.return_value(n1 - n2);
+            },
+            &m,
+        ));
+        m.push(Value::string("sub"));
+        m.store();
+        m.push(Value::function(
+            |m: &mut Machine| m.raise(Value::from("custom")),
+            &m,
+        ));
+        m.push(Value::string("fail"));
+        m.store();
+
+        exec(
+            &mut m,
+            "
+            none
+            fn { \"kind\" index }
+            fn { 1 \"a\" \"sub\" load call }
+            try_catch
+            none
+            fn { \"payload\" index }
+            fn { \"fail\" load call }
+            try_catch
+            ",
+        );
+
+        assert_eq!(
+            m.stack,
+            vec![Value::string("Arithmetic"), Value::string("custom")]
+        );
+    }
+
+    /// Tests that the finally block runs with and without errors,
+    /// and that errors without a handler are raised again after it
+    #[test]
+    fn finally() {
+        let mut m = Machine::new();
+        exec(
+            &mut m,
+            "
+            fn { \"finally 1\" }
+            fn { \"handler\" }
+            fn { \"body\" }
+            try_catch
+            ",
+        );
+        assert_eq!(
+            m.stack,
+            vec![Value::string("body"), Value::string("finally 1")]
+        );
+
+        let mut m = Machine::new();
+        let e = m
+            .try_run(
+                &xasm::assemble(
+                    "
+                    fn { \"finally 2\" \"log\" store }
+                    none
+                    fn { none \"oops\" \"Custom\" throw }
+                    try_catch
+                    ",
+                )
+                .unwrap(),
+            )
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Custom(String::from("Custom")));
+        assert_eq!(e.message, "oops");
+        assert_eq!(e.payload, None);
+        assert_eq!(m.registers["log"], Value::string("finally 2"));
+    }
+
+    /// Tests that a handler can raise the caught error again
+    #[test]
+    fn rethrow() {
+        let mut m = Machine::new();
+        m.run(
+            &xasm::assemble(
+                "
+                none
+                fn { throw }
+                fn { none \"message\" \"Inner\" throw }
+                try_catch
+                ",
+            )
+            .unwrap(),
+        );
+
+        assert_eq!(
+            m.stack,
+            vec![Ref::new(Value::from(Exception::new(
+                ErrorKind::Custom(String::from("Inner")),
+                "message"
+            )))]
+        );
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::bool_assert_comparison)]
+
 extern crate xmachine;
 use xmachine::{Ref, Value};
 
@@ -37,8 +39,8 @@ mod from {
         assert_eq!(bool::from(Value::Number(5.1)), true);
         assert_eq!(bool::from(Value::Number(0.0)), false);
         assert_eq!(bool::from(Value::Number(-5.6)), true);
-        assert_eq!(bool::from((*Value::tree()).clone()), false);
-        assert_eq!(bool::from((*Value::list()).clone()), false);
+        assert_eq!(bool::from(Value::tree().get()), false);
+        assert_eq!(bool::from(Value::list().get()), false);
         assert_eq!(bool::from(Value::String(String::from("test"))), true);
         assert_eq!(bool::from(Value::String(String::from(""))), false);
     }
@@ -0,0 +1,196 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Machine, Value};
+
+mod common;
+use common::{exec, stack, try_exec};
+
+#[cfg(test)]
+mod fuel {
+    use super::*;
+
+    /// Calls the function on top of the stack from Rust
+    fn apply(m: &mut Machine) {
+        let f = m.get_arg();
+        if let Err(e) = f.try_call(m) {
+            m.raise(e.into());
+        }
+    }
+
+    /// A program that calls functions, and runs loops of each kind
+    const PROGRAM: &str = "
+        fn { \"n\" store \"n\" load \"n\" load mul } \"square\" store
+        0 \"total\" store
+        fn {
+            \"total\" load \"i\" load \"square\" load call add \"total\" store
+        } 0 10 range \"i\" \"c\" for_loop
+        fn { \"total\" load 1 sub \"total\" store } fn { \"total\" load 280 gt } while_loop
+        fn { \"small\" } fn { \"big\" } fn { \"total\" load 100 gt } if_then_else
+        \"total\" load
+    ";
+
+    /// Tests that each instruction, and the end of each block, uses fuel
+    #[test]
+    fn budget() {
+        let mut m = Machine::new();
+        assert_eq!(m.fuel(), None);
+        m.set_fuel(Some(10));
+        exec(&mut m, "1 2 add");
+        assert_eq!(m.fuel(), Some(6));
+
+        // Calling a function runs its instructions, and ends its body
+        exec(&mut m, "fn { 1 } call");
+        assert_eq!(m.fuel(), Some(1));
+        assert_eq!(stack(&mut m), vec![Value::from(3), Value::from(1)]);
+
+        m.set_fuel(None);
+        exec(&mut m, PROGRAM);
+        assert_eq!(m.fuel(), None);
+    }
+
+    /// Tests that a loop that never ends is stopped,
+    /// and carries on when it is given more fuel
+    #[test]
+    fn infinite() {
+        let mut m = Machine::new();
+        m.set_fuel(Some(1000));
+        let e = try_exec(
+            &mut m,
+            "0 \"n\" store fn { \"n\" load 1 add \"n\" store } fn { true } while_loop",
+        )
+        .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::OutOfFuel);
+        assert_eq!(e.trace[0].function, "<main>");
+        assert!(m.is_paused());
+        assert_eq!(m.fuel(), Some(0));
+        assert!(m.frames().is_empty());
+
+        // The program is between two steps, so no step runs twice
+        let n = i64::from(m.registers["n"].get());
+        m.set_fuel(Some(1000));
+        assert_eq!(m.try_continue().unwrap_err().kind, ErrorKind::OutOfFuel);
+        assert!(n + 100 < i64::from(m.registers["n"].get()));
+
+        // Running another program abandons it
+        m.set_fuel(None);
+        exec(&mut m, "\"n\" load");
+        assert!(!m.is_paused());
+        assert_eq!(m.try_continue().unwrap_err().kind, ErrorKind::ControlFlow);
+    }
+
+    /// Tests that a program that runs out of fuel over and
+    /// over finishes the same way as one that doesn't
+    #[test]
+    fn resume() {
+        let mut m = Machine::new();
+        exec(&mut m, PROGRAM);
+        let expected = stack(&mut m);
+        assert_eq!(expected, vec![Value::from("big"), Value::from(280)]);
+
+        let mut m = Machine::new();
+        m.set_fuel(Some(7));
+        let mut result = try_exec(&mut m, PROGRAM);
+        let mut pauses = 0;
+        while let Err(e) = result {
+            assert_eq!(e.kind, ErrorKind::OutOfFuel);
+            pauses += 1;
+            m.set_fuel(Some(7));
+            result = m.try_continue();
+        }
+        assert!(pauses > 10);
+        assert!(!m.is_paused());
+        assert_eq!(stack(&mut m), expected);
+    }
+
+    /// Tests that `try_catch` can't catch running out of fuel
+    #[test]
+    fn uncatchable() {
+        let mut m = Machine::new();
+        m.registers
+            .insert(String::from("apply"), Value::function(apply, &m));
+        m.set_fuel(Some(200));
+
+        // Running out of fuel inside of a native function
+        // can't be paused, so the program stops for good
+        let e = try_exec(
+            &mut m,
+            "
+            fn { \"finally\" \"ran\" store }
+            fn { \"caught\" \"ran\" store }
+            fn { fn { fn {} fn { true } while_loop } \"apply\" load call }
+            try_catch
+            ",
+        )
+        .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::OutOfFuel);
+        assert!(!m.is_paused());
+
+        m.set_fuel(None);
+        assert_eq!(
+            try_exec(&mut m, "\"ran\" load").unwrap_err().kind,
+            ErrorKind::UndefinedRegister
+        );
+        assert_eq!(
+            try_exec(&mut m, "none \"\" \"OutOfFuel\" throw")
+                .unwrap_err()
+                .kind,
+            ErrorKind::Custom(String::from("OutOfFuel"))
+        );
+    }
+
+    /// Tests running out of fuel inside of a coroutine
+    #[test]
+    fn coroutine() {
+        let mut m = Machine::new();
+        exec(
+            &mut m,
+            "
+            fn {
+                \"arg\" store
+                fn { \"i\" load yield \"sent\" store } 0 5 range \"i\" \"n\" for_loop
+            } coroutine \"gen\" store
+            ",
+        );
+
+        m.set_fuel(Some(5));
+        let mut result = try_exec(
+            &mut m,
+            "fn { \"x\" load } \"gen\" load \"x\" \"n\" for_loop",
+        );
+        while result.is_err() {
+            m.set_fuel(Some(5));
+            result = m.try_continue();
+        }
+        assert_eq!(stack(&mut m), (0..5).map(Value::from).collect::<Vec<_>>());
+
+        // Coroutines resumed by the host are paused too
+        m.set_fuel(None);
+        exec(
+            &mut m,
+            "fn { \"arg\" store 1 2 add 3 mul yield } coroutine \"co\" store",
+        );
+        m.set_fuel(Some(2));
+        m.push(Value::none());
+        let co = m.registers["co"].clone();
+        m.push(co);
+        assert_eq!(m.try_resume().unwrap_err().kind, ErrorKind::OutOfFuel);
+        m.set_fuel(None);
+        m.try_continue().unwrap();
+        assert_eq!(stack(&mut m), vec![Value::from(9), Value::from(true)]);
+    }
+
+    /// Tests that `Machine::run` stops when the fuel runs out
+    #[test]
+    fn lenient() {
+        let mut m = Machine::new();
+        m.set_fuel(Some(2));
+        m.run(&xasm::assemble("1 2 3 4").unwrap());
+        let stack = stack(&mut m);
+        assert_eq!(stack.len(), 3);
+        assert_eq!(&stack[..2], &[Value::from(1), Value::from(2)]);
+        match &stack[2] {
+            Value::Error(e) => assert_eq!(e.kind, ErrorKind::OutOfFuel),
+            other => panic!("Expected an error, got {}", other),
+        }
+        assert!(!m.is_paused());
+    }
+}
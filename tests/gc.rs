@@ -0,0 +1,200 @@
+extern crate xmachine;
+use xmachine::{Machine, Value};
+
+mod common;
+use common::{exec, stack};
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+#[cfg(test)]
+mod gc {
+    use super::*;
+
+    /// Counts how many times it was dropped
+    struct Guard(Rc<Cell<usize>>);
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    /// Make a machine with a `guard` function, which pushes a function
+    /// that increments the returned counter when it is dropped
+    fn machine() -> (Machine, Rc<Cell<usize>>) {
+        let dropped = Rc::new(Cell::new(0));
+        let mut m = Machine::new();
+        let counter = Rc::clone(&dropped);
+        m.push(Value::function(
+            move |m: &mut Machine| {
+                let guard = Guard(Rc::clone(&counter));
+                m.push(Value::function(
+                    move |_: &mut Machine| {
+                        let _ = &guard;
+                    },
+                    &Machine::new(),
+                ));
+            },
+            &m,
+        ));
+        m.push(Value::string("guard"));
+        m.store();
+        (m, dropped)
+    }
+
+    #[test]
+    fn tree_cycle() {
+        let (mut m, dropped) = machine();
+        exec(
+            &mut m,
+            "
+            tree \"o\" store
+            \"guard\" load call \"o\" load \"guard\" index assign
+            \"o\" load \"o\" load \"me\" index assign
+            none \"o\" store
+            ",
+        );
+        assert_eq!(dropped.get(), 0);
+        assert!(m.collect_garbage() > 0);
+        assert_eq!(dropped.get(), 1);
+        assert_eq!(m.collect_garbage(), 0);
+    }
+
+    #[test]
+    fn list_cycle() {
+        let (mut m, dropped) = machine();
+        exec(
+            &mut m,
+            "
+            list \"l\" store
+            \"guard\" load call \"l\" load 0 index assign
+            \"l\" load \"l\" load 1 index assign
+            none \"l\" store
+            ",
+        );
+        assert!(m.collect_garbage() > 0);
+        assert_eq!(dropped.get(), 1);
+    }
+
+    /// Tests an object that stores itself in a method
+    #[test]
+    fn method_cycle() {
+        let (mut m, dropped) = machine();
+        exec(
+            &mut m,
+            "
+            tree \"o\" store
+            fn {
+                \"self\" store
+                \"guard\" load call \"self\" load \"guard\" index assign
+                \"self\" load \"self\" load \"me\" index assign
+            } \"o\" load \"init\" index assign
+            \"o\" load \"init\" method_call
+            none \"o\" store
+            ",
+        );
+        assert!(m.collect_garbage() > 0);
+        assert_eq!(dropped.get(), 1);
+    }
+
+    /// Tests that cycles that can still be reached are kept
+    #[test]
+    fn reachable_cycle() {
+        let (mut m, dropped) = machine();
+        exec(
+            &mut m,
+            "
+            tree \"o\" store
+            \"guard\" load call \"o\" load \"guard\" index assign
+            \"o\" load \"o\" load \"me\" index assign
+            list \"l\" store
+            \"o\" load \"l\" load 0 index assign
+            ",
+        );
+        assert_eq!(m.collect_garbage(), 0);
+        assert_eq!(dropped.get(), 0);
+
+        // The cycle is still intact
+        exec(
+            &mut m,
+            "\"o\" load \"me\" index \"me\" index \"guard\" index",
+        );
+        assert!(matches!(m.pop().get(), Value::Function(_)));
+
+        exec(&mut m, "none \"o\" store");
+        assert_eq!(m.collect_garbage(), 0);
+        exec(&mut m, "none \"l\" store");
+        assert!(m.collect_garbage() > 0);
+        assert_eq!(dropped.get(), 1);
+    }
+
+    /// Tests that values that contain themselves can be copied,
+    /// captured by functions and compared while they can be reached
+    #[test]
+    fn use_cycle() {
+        let mut m = Machine::new();
+        exec(
+            &mut m,
+            "
+            tree \"t\" store
+            \"t\" load \"t\" load \"me\" index assign
+            list \"l\" store
+            \"l\" load \"l\" load 0 index assign
+            fn { \"t\" load \"me\" index \"me\" index } \"f\" store
+            \"t\" load copy \"c\" store
+            \"t\" load \"c\" load eq
+            \"l\" load \"l\" load copy eq
+            \"f\" load call \"t\" load eq
+            \"l\" load \"l\" load lt
+            ",
+        );
+        assert_eq!(
+            stack(&mut m),
+            vec![
+                Value::from(true),
+                Value::from(true),
+                Value::from(true),
+                Value::from(false)
+            ]
+        );
+
+        // The cycle in the copy refers to the copy, rather than the original
+        exec(
+            &mut m,
+            "
+            1 \"c\" load \"me\" index \"x\" index assign
+            \"c\" load \"me\" index \"me\" index \"x\" index
+            \"t\" load \"c\" load eq
+            ",
+        );
+        assert_eq!(stack(&mut m), vec![Value::from(1), Value::from(false)]);
+        assert_eq!(m.registers["t"].to_string(), "{\"me\": {\"me\": {...}}}");
+        assert_eq!(m.registers["l"].to_string(), "[[[...]]]");
+    }
+
+    /// Tests that garbage is collected automatically as values are made
+    #[test]
+    fn threshold() {
+        let program = "
+            fn {
+                tree \"o\" declare
+                \"guard\" load call \"o\" load \"guard\" index assign
+                \"o\" load \"o\" load \"me\" index assign
+            }
+            0 100 range \"x\" \"i\" for_loop
+            ";
+
+        let (mut m, dropped) = machine();
+        m.set_gc_threshold(None);
+        exec(&mut m, program);
+        assert_eq!(dropped.get(), 0);
+        m.collect_garbage();
+        assert_eq!(dropped.get(), 100);
+
+        let (mut m, dropped) = machine();
+        m.set_gc_threshold(Some(10));
+        exec(&mut m, program);
+        assert!(dropped.get() >= 90);
+    }
+}
@@ -0,0 +1,98 @@
+extern crate xmachine;
+use xmachine::{Instruction, Literal, Machine, Value};
+
+#[cfg(test)]
+mod instruction {
+    use super::*;
+
+    fn sub(m: &mut Machine) {
+        let n1 = m.get_arg();
+        let n2 = m.get_arg();
+        m.return_value(n1 - n2);
+    }
+
+    fn string(s: &str) -> Instruction {
+        Instruction::Push(Literal::String(String::from(s)))
+    }
+
+    fn number(n: f64) -> Instruction {
+        Instruction::Push(Literal::Number(n))
+    }
+
+    /// Tests that a program runs the same as calling the Machine methods
+    #[test]
+    fn run() {
+        let mut m = Machine::new();
+        m.run(&[
+            number(5.0),
+            string("test"),
+            Instruction::Store,
+            string("test"),
+            Instruction::Load,
+            Instruction::Push(Literal::Tree),
+            string("a"),
+            Instruction::Index,
+        ]);
+
+        assert_eq!(m.stack, vec![Value::number(5), Value::none()]);
+    }
+
+    /// Tests the while loop from the `iterative` tests as a program
+    #[test]
+    fn while_loop() {
+        let mut m = Machine::new();
+        m.push(Value::function(sub, &m));
+        m.push(Value::string("sub"));
+        m.store();
+
+        m.run(&[
+            number(5.0),
+            string("test"),
+            Instruction::Store,
+            Instruction::Push(Literal::Function(vec![
+                string("test"),
+                Instruction::Load,
+                number(1.0),
+                string("test"),
+                Instruction::Load,
+                string("sub"),
+                Instruction::Load,
+                Instruction::Call,
+                string("test"),
+                Instruction::Store,
+            ])),
+            Instruction::Push(Literal::Function(vec![string("test"), Instruction::Load])),
+            Instruction::WhileLoop,
+        ]);
+
+        assert_eq!(
+            m.stack,
+            vec![
+                Value::number(5),
+                Value::number(4),
+                Value::number(3),
+                Value::number(2),
+                Value::number(1),
+            ]
+        )
+    }
+
+    /// Tests that functions made from instructions capture their context
+    #[test]
+    fn program() {
+        let mut m = Machine::new();
+        m.push(Value::string("captured"));
+        m.push(Value::string("a"));
+        m.store();
+
+        let f = Value::program(vec![string("a"), Instruction::Load], &m);
+
+        m.push(Value::string("changed"));
+        m.push(Value::string("a"));
+        m.store();
+
+        m.push(f);
+        m.call();
+        assert_eq!(m.stack, vec![Value::string("captured")]);
+    }
+}
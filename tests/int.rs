@@ -0,0 +1,165 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Instruction, Literal, Machine, Value};
+
+mod common;
+use common::{fail, run};
+
+#[cfg(test)]
+mod int {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        assert_eq!(
+            xasm::assemble("5 -3 5.0 1e3 9223372036854775808").unwrap(),
+            vec![
+                Instruction::Push(Literal::Int(5)),
+                Instruction::Push(Literal::Int(-3)),
+                Instruction::Push(Literal::Number(5.0)),
+                Instruction::Push(Literal::Number(1000.0)),
+                // Too large for an Int
+                Instruction::Push(Literal::Number(9223372036854775808.0)),
+            ]
+        );
+
+        // Whole Numbers are rendered so they stay Numbers
+        let program = xasm::assemble("5 5.0 -0.0").unwrap();
+        assert_eq!(
+            xasm::disassemble(&program),
+            "0000  5\n0001  5.0\n0002  -0.0\n"
+        );
+    }
+
+    /// Tests that ints and numbers compare by their numeric value
+    #[test]
+    fn comparison() {
+        assert_eq!(Value::Int(1), Value::Number(1.0));
+        assert_ne!(Value::Int(1), Value::Number(1.5));
+        assert_ne!(Value::Int(i64::MAX), Value::Number(i64::MAX as f64));
+        assert!(Value::Int(1) < Value::Number(1.5));
+        assert!(Value::Number(-0.5) < Value::Int(0));
+        assert_eq!(
+            run("1 1.0 eq 2 1.5 gt 0 not"),
+            vec![Value::Bool(true), Value::Bool(true), Value::Bool(true)]
+        );
+    }
+
+    /// Tests that ints stay ints, and are promoted when mixed with numbers
+    #[test]
+    fn arithmetic() {
+        assert_eq!(
+            run("7 2 div -7 2 div 7 -2 rem -7 2 rem 7 2.0 div 1 0.5 add"),
+            vec![
+                Value::Int(3),
+                Value::Int(-3),
+                Value::Int(1),
+                Value::Int(-1),
+                Value::Number(3.5),
+                Value::Number(1.5),
+            ]
+        );
+
+        // Ints don't lose precision past 2^53
+        let big = 1i64 << 53;
+        let sum = Value::Int(big) + Value::Int(1);
+        assert_eq!(sum.to_string(), "9007199254740993");
+        assert_eq!(i64::from(sum), big + 1);
+
+        assert_eq!(
+            run("\"ab\" 2 mul \"ab\" 0 mul \"ab\" 2.0 mul"),
+            vec![Value::from("abab"), Value::from(""), Value::from("abab")]
+        );
+    }
+
+    #[test]
+    fn overflow() {
+        let mut m = Machine::new();
+        let (kind, message) = fail(&mut m, "9223372036854775807 1 add");
+        assert_eq!(kind, ErrorKind::Arithmetic);
+        assert_eq!(message, "Integer overflow adding 9223372036854775807 and 1");
+
+        let min = "-9223372036854775808";
+        assert_eq!(
+            fail(&mut m, &format!("{} -1 div", min)).0,
+            ErrorKind::Arithmetic
+        );
+        assert_eq!(
+            fail(&mut m, &format!("{} neg", min)).0,
+            ErrorKind::Arithmetic
+        );
+        assert_eq!(
+            fail(&mut m, &format!("{} 2 mul", min)).0,
+            ErrorKind::Arithmetic
+        );
+        assert_eq!(
+            fail(&mut m, "1 0 div"),
+            (ErrorKind::Arithmetic, String::from("Division by zero"))
+        );
+        assert_eq!(fail(&mut m, "1 0 rem").1, "Division by zero");
+        assert_eq!(
+            fail(&mut m, "\"ab\" 4611686018427387904 mul"),
+            (
+                ErrorKind::Arithmetic,
+                String::from("String overflow repeating 2 bytes 4611686018427387904 times")
+            )
+        );
+        assert_eq!(fail(&mut m, "\"ab\" 1e19 mul").0, ErrorKind::Arithmetic);
+        // Strings can only be repeated a whole number of times
+        assert_eq!(
+            fail(&mut m, "\"ab\" -1 mul"),
+            (
+                ErrorKind::Arithmetic,
+                String::from("Can't repeat a string -1 times")
+            )
+        );
+        for count in &["-1.0", "2.5", "NaN", "inf"] {
+            let source = format!("\"ab\" {} mul", count);
+            assert_eq!(fail(&mut m, &source).0, ErrorKind::Arithmetic);
+        }
+
+        // Numbers don't overflow
+        assert_eq!(run("1 0.0 div"), vec![Value::Number(f64::INFINITY)]);
+    }
+
+    /// Tests that lists and strings are indexed by ints directly
+    #[test]
+    fn index() {
+        assert_eq!(
+            run("
+                list \"xs\" store
+                5 \"xs\" load 2 index assign
+                \"xs\" load 2 index
+                \"xs\" load 2.0 index
+                \"abc\" 1 index
+                "),
+            vec![Value::Int(5), Value::Int(5), Value::from("b")]
+        );
+
+        let mut m = Machine::new();
+        let (kind, message) = fail(&mut m, "list -1 index");
+        assert_eq!(kind, ErrorKind::InvalidIndex);
+        assert_eq!(message, "Can't index with negative integer -1");
+        assert_eq!(
+            fail(&mut m, "\"abc\" 3 index").1,
+            "String index out of bounds"
+        );
+
+        // Whole Numbers index trees like Ints
+        assert_eq!(
+            run("tree \"t\" store 1 \"t\" load 2.0 index assign \"t\" load 2 index"),
+            vec![Value::Int(1)]
+        );
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Value::from(5), Value::Int(5));
+        assert_eq!(Value::from(5i64).to_string(), "5");
+        assert_eq!(i32::from(Value::Int(i64::MAX)), i32::MAX);
+        assert_eq!(i32::from(Value::Int(-5)), -5);
+        assert_eq!(f64::from(Value::Int(3)), 3.0);
+        assert_eq!(i64::from(Value::Number(2.5)), 2);
+        assert!(bool::from(Value::Int(-1)));
+        assert!(!bool::from(Value::Int(0)));
+    }
+}
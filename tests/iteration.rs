@@ -0,0 +1,146 @@
+extern crate xmachine;
+use xmachine::{ErrorKind, Ref, Value};
+
+mod common;
+use common::try_run;
+
+#[cfg(test)]
+mod iteration {
+    use super::*;
+
+    /// Tests that trees are iterated as key and value pairs, in key order
+    #[test]
+    fn tree() {
+        assert_eq!(
+            try_run(
+                "
+                tree \"t\" store
+                1 \"t\" load \"b\" index assign
+                2 \"t\" load \"a\" index assign
+                fn { \"k\" load \"v\" load } \"t\" load \"v\" \"k\" for_loop
+                "
+            ),
+            Ok(vec![
+                Value::from("a"),
+                Value::Int(2),
+                Value::from("b"),
+                Value::Int(1),
+            ])
+        );
+
+        let mut t = Value::Tree(Default::default());
+        t.index("x");
+        assert_eq!(
+            t.into_iter().collect::<Vec<_>>(),
+            vec![Ref::new(Value::from(vec![
+                Value::string("x"),
+                Value::none()
+            ]))]
+        );
+    }
+
+    #[test]
+    fn range() {
+        assert_eq!(
+            try_run("fn { \"n\" load \"i\" load } 3 5 range \"n\" \"i\" for_loop"),
+            Ok(vec![
+                Value::Int(3),
+                Value::Int(0),
+                Value::Int(4),
+                Value::Int(1),
+            ])
+        );
+        assert_eq!(
+            try_run("2 5 range 2 5 range len 2 5 range 1 index 5 2 range len"),
+            Ok(vec![
+                Value::Range(2, 5),
+                Value::Int(3),
+                Value::Int(3),
+                Value::Int(0),
+            ])
+        );
+        assert_eq!(Value::Range(2, 5).to_string(), "2..5");
+        assert_eq!(
+            try_run("0 1.5 range"),
+            Err((
+                ErrorKind::Arithmetic,
+                String::from("Can't make a range from 0 to 1.5")
+            ))
+        );
+        assert_eq!(
+            try_run("0 2 range 2 index").unwrap_err().1,
+            "Range index out of bounds"
+        );
+    }
+
+    /// Tests that a huge range is iterated without making a list of it
+    #[test]
+    fn lazy() {
+        assert_eq!(
+            try_run(
+                "
+                fn {
+                    fn {}
+                    fn { none \"stopped\" \"Stop\" throw }
+                    fn { \"n\" load 1000 ge }
+                    if_then_else
+                }
+                0 9223372036854775807 range
+                \"n\" \"i\" for_loop
+                "
+            ),
+            Err((ErrorKind::from("Stop"), String::from("stopped")))
+        );
+
+        // Iterating over the value from Rust is lazy too
+        let mut elements = Value::Range(0, i64::MAX).into_iter();
+        assert_eq!(elements.size_hint().0, i64::MAX as usize);
+        assert_eq!(elements.nth(1000).unwrap().get(), Value::Int(1000));
+        assert_eq!(
+            Value::Range(-2, 1)
+                .into_iter()
+                .map(|n| n.get())
+                .collect::<Vec<_>>(),
+            vec![Value::Int(-2), Value::Int(-1), Value::Int(0)]
+        );
+    }
+
+    /// Tests an object that counts down from 3 with the iterator protocol
+    #[test]
+    fn protocol() {
+        assert_eq!(
+            try_run(
+                "
+                tree \"countdown\" store
+                fn {
+                    \"self\" store
+                    tree \"iter\" store
+                    3 \"iter\" load \"n\" index assign
+                    fn {
+                        \"self\" store
+                        fn { false }
+                        fn {
+                            \"self\" load \"n\" index copy
+                            \"self\" load \"n\" index 1 sub \"self\" load \"n\" index assign
+                            true
+                        }
+                        fn { \"self\" load \"n\" index 0 gt }
+                        if_then_else
+                    } \"iter\" load \"next\" index assign
+                    \"iter\" load
+                } \"countdown\" load \"__iter__\" index assign
+
+                fn { \"i\" load \"n\" load } \"countdown\" load \"n\" \"i\" for_loop
+                "
+            ),
+            Ok(vec![
+                Value::Int(0),
+                Value::Int(3),
+                Value::Int(1),
+                Value::Int(2),
+                Value::Int(2),
+                Value::Int(1),
+            ])
+        );
+    }
+}
@@ -0,0 +1,160 @@
+extern crate xmachine;
+use xmachine::{ErrorKind, Key, Ref, Value};
+
+mod common;
+use common::try_run;
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use core::convert::TryFrom;
+
+#[cfg(test)]
+mod key {
+    use super::*;
+
+    /// Tests that `1` and `"1"` are different keys, but `1` and `1.0` are the same
+    #[test]
+    fn distinct() {
+        assert_eq!(
+            try_run(
+                "
+                tree \"t\" store
+                \"int\" \"t\" load 1 index assign
+                \"string\" \"t\" load \"1\" index assign
+                \"bool\" \"t\" load true index assign
+                \"t\" load 1.0 index
+                \"t\" load \"1\" index
+                \"t\" load true index
+                \"t\" load 1 1 ne index
+                "
+            ),
+            Ok(vec![
+                Value::from("int"),
+                Value::from("string"),
+                Value::from("bool"),
+                Value::None,
+            ])
+        );
+    }
+
+    /// Tests that lists are used as tuple keys, compared by their contents
+    #[test]
+    fn tuples() {
+        assert_eq!(
+            try_run(
+                "
+                tree \"grid\" store
+                list \"a\" store 1 \"a\" load 0 index assign 2 \"a\" load 1 index assign
+                list \"b\" store 1 \"b\" load 0 index assign 2 \"b\" load 1 index assign
+                \"found\" \"grid\" load \"a\" load index assign
+                \"grid\" load \"b\" load index
+                "
+            ),
+            Ok(vec![Value::from("found")])
+        );
+
+        let key = Key::try_from(&Value::from(vec![Value::int(1), Value::string("x")]));
+        assert_eq!(key, Ok(Key::Tuple(vec![Key::Int(1), Key::from("x")])));
+        assert_eq!(
+            Value::from(Key::Tuple(vec![Key::Int(1), Key::from("x")])),
+            Value::from(vec![Value::int(1), Value::string("x")])
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            try_run("tree none index"),
+            Err((
+                ErrorKind::InvalidIndex,
+                String::from("Can't use None as a key")
+            ))
+        );
+        assert_eq!(
+            try_run("tree list tree 0 index index").unwrap_err().0,
+            ErrorKind::InvalidIndex
+        );
+        assert_eq!(
+            try_run("list \"0\" index").unwrap_err().1,
+            "Can't index with non-integer \"0\""
+        );
+
+        // Lists and trees that contain themselves
+        assert_eq!(
+            try_run(
+                "
+                list \"l\" store
+                \"l\" load \"l\" load 0 index assign
+                tree \"l\" load index
+                "
+            ),
+            Err((
+                ErrorKind::InvalidIndex,
+                String::from("Can't use a list that contains itself as a key")
+            ))
+        );
+        assert_eq!(
+            try_run(
+                "
+                tree \"t\" store
+                \"t\" load \"t\" load \"me\" index assign
+                tree \"t\" load index
+                "
+            ),
+            Err((
+                ErrorKind::InvalidIndex,
+                String::from("Can't use {\"me\": {\"me\": {...}}} as a key")
+            ))
+        );
+    }
+
+    /// Tests that keys are ordered by variant, and numbers by their value
+    #[test]
+    fn order() {
+        let keys = vec![
+            Key::from("a"),
+            Key::Int(2),
+            Key::from(1.5),
+            Key::from(true),
+            Key::from(vec![1u8]),
+            Key::Tuple(vec![]),
+            Key::from(-1),
+        ];
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(
+            sorted,
+            vec![
+                Key::from(true),
+                Key::from(-1),
+                Key::from(1.5),
+                Key::Int(2),
+                Key::from("a"),
+                Key::from(vec![1u8]),
+                Key::Tuple(vec![]),
+            ]
+        );
+
+        assert_eq!(Key::from(2.0), Key::Int(2));
+        assert_eq!(Key::from(f64::NAN), Key::from(f64::NAN));
+    }
+
+    /// Tests that trees with string keys are still easy to use from Rust
+    #[test]
+    fn strings() {
+        let mut map = BTreeMap::new();
+        map.insert(String::from("name"), Value::string("xmachine"));
+        let mut tree = Value::from(map);
+
+        assert_eq!(tree.index("name"), Value::string("xmachine"));
+        assert_eq!(tree.to_string(), "{\"name\": xmachine}");
+
+        let mut tree = Value::Tree(BTreeMap::new());
+        tree.index(3);
+        tree.index(vec![Key::from("x"), Key::Int(1)]);
+        assert_eq!(tree.to_string(), "{3: None, (\"x\", 1): None}");
+
+        let map = BTreeMap::<Key, Ref<Value>>::from(tree);
+        assert!(map.contains_key(&Key::Int(3)));
+    }
+}
@@ -0,0 +1,279 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Machine, Value};
+
+mod common;
+use common::{exec, fail, run_on, try_run_on};
+
+#[cfg(test)]
+mod limits {
+    use super::*;
+
+    /// Tests that a program can't push values forever
+    #[test]
+    fn stack() {
+        let mut m = Machine::new();
+        m.set_stack_limit(Some(100));
+        assert_eq!(
+            fail(&mut m, "fn { 1 } fn { true } while_loop"),
+            (
+                ErrorKind::MemoryLimit,
+                String::from("The stack would be over the limit of 100 values")
+            )
+        );
+
+        // Inside of functions called by native functions too
+        m.registers.insert(
+            String::from("apply"),
+            Value::function(
+                |m: &mut Machine| {
+                    let f = m.get_arg();
+                    if let Err(e) = f.try_call(m) {
+                        m.raise(e.into());
+                    }
+                },
+                &Machine::new(),
+            ),
+        );
+        assert_eq!(
+            fail(
+                &mut m,
+                "fn { fn { 1 } fn { true } while_loop } \"apply\" load call"
+            )
+            .0,
+            ErrorKind::MemoryLimit
+        );
+
+        // Catching the error drops the values that were pushed
+        assert_eq!(
+            try_run_on(
+                &mut m,
+                "
+                fn {}
+                fn { \"kind\" index }
+                fn { fn { 1 } fn { true } while_loop }
+                try_catch
+                "
+            )
+            .unwrap(),
+            vec![Value::from("MemoryLimit")]
+        );
+        assert_eq!(
+            try_run_on(&mut m, "1 2 3").unwrap(),
+            vec![Value::from(1), Value::from(2), Value::from(3)]
+        );
+    }
+
+    /// Tests that lists and trees can't grow past the length limit
+    #[test]
+    fn length() {
+        let mut m = Machine::new();
+        m.set_length_limit(Some(100));
+        let too_long = (
+            ErrorKind::MemoryLimit,
+            String::from("The collection would be over the limit of 100 elements"),
+        );
+
+        // The list isn't allocated before the error is raised
+        assert_eq!(fail(&mut m, "list 1000000000000 index"), too_long);
+        assert_eq!(fail(&mut m, "list 100 index"), too_long);
+        assert_eq!(
+            try_run_on(&mut m, "list 99 index").unwrap(),
+            vec![Value::None]
+        );
+
+        try_run_on(
+            &mut m,
+            "
+            list \"xs\" store
+            tree \"t\" store
+            fn {
+                \"i\" load \"xs\" load \"i\" load index assign
+                \"i\" load \"t\" load \"i\" load index assign
+            } 0 100 range \"i\" \"n\" for_loop
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            try_run_on(&mut m, "\"t\" load 99 index").unwrap(),
+            vec![Value::from(99)]
+        );
+        assert_eq!(fail(&mut m, "\"t\" load 100 index"), too_long);
+        assert_eq!(fail(&mut m, "\"xs\" load \"xs\" load add"), too_long);
+        assert_eq!(
+            try_run_on(&mut m, "\"xs\" load len \"t\" load len").unwrap(),
+            vec![Value::from(100), Value::from(100)]
+        );
+    }
+
+    /// Tests that strings can't grow past the string limit
+    #[test]
+    fn string() {
+        let mut m = Machine::new();
+        m.set_string_limit(Some(1000));
+        let too_long = (
+            ErrorKind::MemoryLimit,
+            String::from("The string would be over the limit of 1000 bytes"),
+        );
+
+        // The string isn't repeated before the error is raised
+        assert_eq!(fail(&mut m, "\"ab\" 1000000000000 mul"), too_long);
+        assert_eq!(fail(&mut m, "\"ab\" 1e15 mul"), too_long);
+        assert_eq!(
+            try_run_on(&mut m, "\"ab\" 500 mul len").unwrap(),
+            vec![Value::from(1000)]
+        );
+
+        // Doubling a string until it is too long
+        assert_eq!(
+            fail(
+                &mut m,
+                "
+                \"x\" \"s\" store
+                fn { \"s\" load \"s\" load add \"s\" store } fn { true } while_loop
+                "
+            ),
+            too_long
+        );
+        assert_eq!(
+            try_run_on(&mut m, "\"s\" load len").unwrap(),
+            vec![Value::from(512)]
+        );
+    }
+
+    /// Tests that values are checked against the limits before they are concatenated
+    #[test]
+    fn concat() {
+        let mut m = Machine::new();
+        m.set_string_limit(Some(1000));
+        m.set_length_limit(Some(100));
+        exec(
+            &mut m,
+            "
+            \"ab\" 300 mul \"s\" store
+            list \"xs\" store
+            fn { 0 \"xs\" load \"i\" load index assign } 0 60 range \"i\" \"n\" for_loop
+            ",
+        );
+
+        assert_eq!(
+            fail(&mut m, "\"s\" load \"s\" load add"),
+            (
+                ErrorKind::MemoryLimit,
+                String::from("The string would be over the limit of 1000 bytes")
+            )
+        );
+        assert_eq!(
+            run_on(&mut m, "\"s\" load \"ab\" 200 mul add len"),
+            vec![Value::from(1000)]
+        );
+        assert_eq!(
+            fail(&mut m, "\"xs\" load \"xs\" load add"),
+            (
+                ErrorKind::MemoryLimit,
+                String::from("The collection would be over the limit of 100 elements")
+            )
+        );
+
+        // Byte strings count against the string limit too
+        m.push(Value::bytes(vec![0; 600]));
+        m.push(Value::bytes(vec![0; 600]));
+        assert_eq!(m.try_add().unwrap_err().kind, ErrorKind::MemoryLimit);
+    }
+
+    /// Tests the limit on the total memory allocated
+    #[test]
+    fn allocation() {
+        let mut m = Machine::new();
+        m.set_allocation_limit(Some(1_000_000));
+        assert_eq!(m.allocated(), 0);
+        try_run_on(&mut m, "1 2 add").unwrap();
+        assert!(m.allocated() > 0);
+
+        // Big values are refused before they are made
+        let (kind, message) = fail(&mut m, "\"x\" 10000000 mul");
+        assert_eq!(kind, ErrorKind::MemoryLimit);
+        assert_eq!(
+            message,
+            "The memory allocated would be over the limit of 1000000 bytes"
+        );
+        assert_eq!(
+            fail(&mut m, "list 10000000 index").0,
+            ErrorKind::MemoryLimit
+        );
+
+        // Values that are freed still count
+        assert_eq!(
+            fail(
+                &mut m,
+                "fn { \"xxxxxxxx\" 1000 mul \"s\" store } fn { true } while_loop"
+            )
+            .0,
+            ErrorKind::MemoryLimit
+        );
+        assert!(m.allocated() > 900_000);
+
+        // Setting the limit again starts counting from zero
+        m.set_allocation_limit(Some(1_000_000));
+        assert_eq!(m.allocated(), 0);
+        assert_eq!(try_run_on(&mut m, "1").unwrap(), vec![Value::from(1)]);
+        m.set_allocation_limit(None);
+        assert_eq!(
+            try_run_on(&mut m, "\"x\" 10000000 mul len").unwrap(),
+            vec![Value::from(10000000)]
+        );
+    }
+
+    /// Tests that values too big to allocate raise MemoryLimit
+    /// when there are no limits, instead of aborting
+    #[test]
+    fn unlimited() {
+        let mut m = Machine::new();
+        assert_eq!(
+            fail(&mut m, "list 4611686018427387904 index"),
+            (
+                ErrorKind::MemoryLimit,
+                String::from("Not enough memory for a list of 4611686018427387905 elements")
+            )
+        );
+        assert_eq!(
+            fail(&mut m, "list 1125899906842624 index").0,
+            ErrorKind::MemoryLimit
+        );
+        assert_eq!(
+            fail(&mut m, "\"ab\" 1e18 mul"),
+            (
+                ErrorKind::MemoryLimit,
+                String::from("Not enough memory to repeat 2 bytes 1000000000000000000 times")
+            )
+        );
+        assert_eq!(
+            try_run_on(&mut m, "\"ab\" 3 mul \"\" 1e18 mul \"ab\" 0 mul").unwrap(),
+            vec![Value::from("ababab"), Value::from(""), Value::from("")]
+        );
+    }
+
+    /// Tests that `Machine::run` stops at a memory limit
+    #[test]
+    fn lenient() {
+        let mut m = Machine::new();
+        m.set_stack_limit(Some(2));
+        m.run(&xasm::assemble("1 2 3 4").unwrap());
+        assert_eq!(m.stack.len(), 4);
+        match m.pop().get() {
+            Value::Error(e) => {
+                assert_eq!(e.kind, ErrorKind::MemoryLimit);
+            }
+            other => panic!("Expected an error, got {}", other),
+        }
+    }
+
+    /// Tests that programs can throw MemoryLimit errors
+    #[test]
+    fn throw() {
+        let mut m = Machine::new();
+        assert_eq!(
+            fail(&mut m, "none \"Too big\" \"MemoryLimit\" throw"),
+            (ErrorKind::MemoryLimit, String::from("Too big"))
+        );
+    }
+}
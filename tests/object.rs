@@ -1,3 +1,5 @@
+#![allow(clippy::needless_borrow)]
+
 extern crate xmachine;
 use xmachine::{Machine, Value};
 
@@ -0,0 +1,142 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Machine, Value};
+
+mod common;
+use common::run;
+
+#[cfg(test)]
+mod operator {
+    use super::*;
+
+    /// Tests that the right hand side is taken from the top of the stack
+    #[test]
+    fn arithmetic() {
+        assert_eq!(
+            run("7.0 2 add 7.0 2 sub 7.0 2 mul 7.0 2 div 7.0 2 rem 7.0 neg"),
+            vec![
+                Value::Number(9.0),
+                Value::Number(5.0),
+                Value::Number(14.0),
+                Value::Number(3.5),
+                Value::Number(1.0),
+                Value::Number(-7.0),
+            ]
+        );
+        assert_eq!(
+            run("\"ab\" \"cd\" add \"ab\" 3 mul"),
+            vec![Value::from("abcd"), Value::from("ababab")]
+        );
+    }
+
+    /// Tests that comparisons and logic push number-based bools
+    #[test]
+    fn comparison() {
+        assert_eq!(
+            run("1 2 eq 1 2 ne 1 2 lt 2 2 le 1 2 gt 2 2 ge"),
+            vec![
+                Value::from(false),
+                Value::from(true),
+                Value::from(true),
+                Value::from(true),
+                Value::from(false),
+                Value::from(true),
+            ]
+        );
+        assert_eq!(
+            run("\"a\" \"a\" eq 1 \"\" and 0 list or 0 not 5 not"),
+            vec![
+                Value::from(true),
+                Value::from(false),
+                Value::from(false),
+                Value::from(true),
+                Value::from(false),
+            ]
+        );
+    }
+
+    /// Tests comparing lists, and functions, that contain themselves
+    #[test]
+    fn cycle() {
+        assert_eq!(
+            run("
+                list \"a\" store
+                \"a\" load \"a\" load 0 index assign 1 \"a\" load 1 index assign
+                list \"b\" store
+                \"b\" load \"b\" load 0 index assign 2 \"b\" load 1 index assign
+                \"a\" load \"a\" load eq
+                \"a\" load \"a\" load copy eq
+                \"a\" load \"b\" load eq
+                \"a\" load \"b\" load lt
+                none \"f\" store
+                fn { \"f\" load call } \"f\" share \"f\" store
+                \"f\" load \"f\" load copy eq
+                "),
+            vec![
+                Value::from(true),
+                Value::from(true),
+                Value::from(false),
+                Value::from(true),
+                Value::from(true),
+            ]
+        );
+    }
+
+    /// Tests a countdown loop written without any foreign functions
+    #[test]
+    fn countdown() {
+        assert_eq!(
+            run("
+                5 \"n\" store
+                fn {
+                    \"n\" load
+                    \"n\" load 1 sub \"n\" store
+                }
+                fn { \"n\" load 0 gt }
+                while_loop
+                "),
+            vec![
+                Value::Number(5.0),
+                Value::Number(4.0),
+                Value::Number(3.0),
+                Value::Number(2.0),
+                Value::Number(1.0),
+            ]
+        );
+    }
+
+    /// Tests that unsupported operands raise arithmetic errors
+    #[test]
+    fn errors() {
+        let mut m = Machine::new();
+        let e = m
+            .try_run(&xasm::assemble("1 list sub").unwrap())
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Arithmetic);
+        assert_eq!(e.message, "Could not subtract 1 and []");
+
+        // Without `try_run`, the error is pushed as the result
+        let mut m = Machine::new();
+        m.run(&xasm::assemble("\"a\" neg 5").unwrap());
+        assert_eq!(
+            m.stack,
+            vec![
+                Value::exception(ErrorKind::Arithmetic, "Could not make a negative"),
+                Value::number(5)
+            ]
+        );
+    }
+
+    /// Tests that the operators survive assembling and rendering
+    #[test]
+    fn round_trip() {
+        let source = "add sub mul div rem neg not eq ne lt le gt ge and or";
+        let program = xasm::assemble(source).unwrap();
+        assert_eq!(program.len(), 15);
+        let rendered = program
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(rendered, source);
+    }
+}
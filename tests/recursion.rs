@@ -0,0 +1,130 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Machine, Value, DEFAULT_DEPTH_LIMIT, TRACE_LIMIT};
+
+mod common;
+use common::try_run_on;
+
+#[cfg(test)]
+mod recursion {
+    use super::*;
+
+    /// Calls the function on top of the stack from Rust
+    fn apply(m: &mut Machine) {
+        let f = m.get_arg();
+        if let Err(e) = f.try_call(m) {
+            m.raise(e.into());
+        }
+    }
+
+    /// Make a register with a function that calls itself N times,
+    /// which uses two frames for each call: the function, and the
+    /// block of its `if_then_else`
+    fn countdown(m: &mut Machine) {
+        try_run_on(
+            m,
+            "
+            none \"down\" store
+            fn {
+                \"n\" store
+                fn { \"n\" load }
+                fn { \"n\" load 1 sub \"down\" load call }
+                fn { \"n\" load 0 gt }
+                if_then_else
+            } \"down\" share \"down\" store
+            ",
+        )
+        .unwrap();
+    }
+
+    /// Tests that functions made from instructions
+    /// don't use the Rust stack when they recurse
+    #[test]
+    fn deep() {
+        let mut m = Machine::new();
+        countdown(&mut m);
+        m.set_depth_limit(None);
+        assert_eq!(
+            try_run_on(&mut m, "20000 \"down\" load call").unwrap(),
+            vec![Value::from(0)]
+        );
+    }
+
+    /// Tests that recursing too deep fails with an error that can be caught
+    #[test]
+    fn limit() {
+        let mut m = Machine::new();
+        countdown(&mut m);
+        assert_eq!(
+            try_run_on(&mut m, "1000 \"down\" load call").unwrap(),
+            vec![Value::from(0)]
+        );
+
+        let e = m
+            .try_run(&xasm::assemble("10000 \"down\" load call").unwrap())
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::RecursionLimit);
+        assert_eq!(
+            e.message,
+            format!(
+                "Calls are nested deeper than the limit of {} frames",
+                DEFAULT_DEPTH_LIMIT
+            )
+        );
+        assert_eq!(e.trace.len(), TRACE_LIMIT);
+        assert_eq!(e.omitted, DEFAULT_DEPTH_LIMIT - TRACE_LIMIT);
+        assert!(e.to_string().contains(&format!(
+            "\n    ... {} frames omitted\n",
+            DEFAULT_DEPTH_LIMIT - TRACE_LIMIT
+        )));
+        assert!(m.frames().is_empty());
+        m.stack.clear();
+
+        m.set_depth_limit(Some(100));
+        assert_eq!(
+            try_run_on(
+                &mut m,
+                "
+                fn { \"finally\" }
+                fn { \"kind\" index }
+                fn { 1000 \"down\" load call }
+                try_catch
+                "
+            )
+            .unwrap(),
+            vec![Value::from("RecursionLimit"), Value::from("finally")]
+        );
+        assert_eq!(
+            try_run_on(&mut m, "10 \"down\" load call").unwrap(),
+            vec![Value::from(0)]
+        );
+    }
+
+    /// Tests that recursing through a native function fails
+    /// with an error, instead of overflowing the Rust stack
+    #[test]
+    fn native() {
+        let mut m = Machine::new();
+        m.registers
+            .insert(String::from("apply"), Value::function(apply, &m));
+        let source = "
+            none \"f\" store
+            fn { \"f\" load \"apply\" load call } \"f\" share \"f\" store
+            \"f\" load call
+        ";
+        let e = try_run_on(&mut m, source).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::RecursionLimit);
+        assert!(e.message.starts_with("Native functions are nested deeper"));
+
+        m.set_native_depth_limit(Some(10));
+        let e = try_run_on(&mut m, source).unwrap_err();
+        assert_eq!(
+            e.message,
+            "Native functions are nested deeper than the limit of 10"
+        );
+        assert!(m.frames().is_empty());
+        assert_eq!(
+            try_run_on(&mut m, "fn { 1 } \"apply\" load call").unwrap(),
+            vec![Value::from(1)]
+        );
+    }
+}
@@ -0,0 +1,103 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Machine, Ref, Value};
+
+#[cfg(test)]
+mod reference {
+    use super::*;
+
+    /// Tests that assigning through a Ref is seen by all of its clones
+    #[test]
+    fn shared() {
+        let a = Value::number(1);
+        let b = Ref::clone(&a);
+        let c = a.copy();
+
+        let mut m = Machine::new();
+        m.push(Value::number(2));
+        m.push(b);
+        m.assign();
+
+        assert_eq!(a, Value::number(2));
+        assert_eq!(c, Value::number(1));
+        assert!(!Ref::ptr_eq(&a, &c));
+    }
+
+    /// Tests assigning to an element of a list stored in a register
+    #[test]
+    fn list_element() {
+        let mut m = Machine::new();
+        m.run(
+            &xasm::assemble(
+                "
+                list \"xs\" store
+                5 \"xs\" load 2 index assign
+                \"xs\" load 2 index
+                \"xs\" load 0 index
+                ",
+            )
+            .unwrap(),
+        );
+
+        assert_eq!(m.stack, vec![Value::number(5), Value::none()]);
+        assert_eq!(
+            m.registers["xs"],
+            Ref::new(Value::from(vec![
+                Value::none(),
+                Value::none(),
+                Value::number(5)
+            ]))
+        );
+    }
+
+    /// Tests that indexing a tree with itself fails without a double
+    /// borrow, and that a tree can be assigned into one of its own members
+    #[test]
+    fn aliasing() {
+        let mut m = Machine::new();
+        m.run(
+            &xasm::assemble(
+                "
+                tree \"t\" store
+                \"t\" load \"t\" load index
+                \"t\" load \"t\" load \"me\" index assign
+                \"t\" load \"me\" index
+                \"t\" load \"me\" index \"me\" index
+                ",
+            )
+            .unwrap(),
+        );
+
+        let inner = m.pop();
+        let outer = m.pop();
+        assert!(Ref::ptr_eq(&inner, &outer));
+        assert_eq!(
+            m.pop(),
+            Value::exception(ErrorKind::InvalidIndex, "Can't use {} as a key")
+        );
+    }
+
+    /// Tests that a method can assign to the members of `self`,
+    /// including storing a function into the member it was called from
+    #[test]
+    fn method_self() {
+        let mut m = Machine::new();
+        m.run(
+            &xasm::assemble(
+                "
+                tree \"obj\" store
+                fn {
+                    \"self\" store
+                    \"replaced\" \"self\" load \"method\" index assign
+                    1 \"self\" load \"called\" index assign
+                } \"obj\" load \"method\" index assign
+                \"obj\" load \"method\" method_call
+                \"obj\" load \"method\" index
+                \"obj\" load \"called\" index
+                ",
+            )
+            .unwrap(),
+        );
+
+        assert_eq!(m.stack, vec![Value::string("replaced"), Value::number(1)]);
+    }
+}
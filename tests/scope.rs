@@ -0,0 +1,121 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Machine, Value};
+
+mod common;
+use common::{exec, stack};
+
+#[cfg(test)]
+mod scope {
+    use super::*;
+
+    /// Tests that for_loop variables don't replace the caller's registers
+    #[test]
+    fn loop_variables() {
+        let mut m = Machine::new();
+        exec(
+            &mut m,
+            "
+            \"outer\" \"x\" store
+            fn { \"x\" load } 0 2 range \"x\" \"i\" for_loop
+            \"x\" load
+            ",
+        );
+        assert_eq!(
+            stack(&mut m),
+            vec![Value::Int(0), Value::Int(1), Value::from("outer")]
+        );
+        assert!(!m.registers.contains_key("i"));
+        assert!(m.scopes.is_empty());
+    }
+
+    /// Tests that `store` assigns to the binding it finds,
+    /// and `declare` makes a new one in the current block
+    #[test]
+    fn declare_and_store() {
+        let mut m = Machine::new();
+        exec(
+            &mut m,
+            "
+            0 \"total\" store
+            fn {
+                \"total\" load \"x\" load add \"total\" store
+                \"x\" load 2 mul \"double\" declare
+                \"found\" \"result\" store
+            }
+            1 4 range \"x\" \"i\" for_loop
+            \"total\" load
+            \"result\" load
+            ",
+        );
+        assert_eq!(stack(&mut m), vec![Value::Int(6), Value::from("found")]);
+        assert!(!m.registers.contains_key("double"));
+    }
+
+    /// Tests that declarations shadow outer registers until their scope ends
+    #[test]
+    fn shadowing() {
+        let mut m = Machine::new();
+        exec(
+            &mut m,
+            "
+            1 \"x\" store
+            push_scope
+                2 \"x\" declare
+                \"x\" load
+                3 \"x\" store
+                \"x\" load
+            pop_scope
+            \"x\" load
+            fn {} fn { 4 \"x\" declare \"x\" load } fn { true } if_then_else
+            \"x\" load
+            ",
+        );
+        assert_eq!(
+            stack(&mut m),
+            vec![
+                Value::Int(2),
+                Value::Int(3),
+                Value::Int(1),
+                Value::Int(4),
+                Value::Int(1),
+            ]
+        );
+    }
+
+    /// Tests that functions see the registers of the blocks they are made in
+    #[test]
+    fn closures() {
+        let mut m = Machine::new();
+        exec(
+            &mut m,
+            "
+            push_scope
+                5 \"local\" declare
+                fn { \"local\" load } \"f\" store
+            pop_scope
+            \"f\" load call
+            ",
+        );
+        assert_eq!(stack(&mut m), vec![Value::Int(5)]);
+    }
+
+    /// Tests that scopes end even if their block fails
+    #[test]
+    fn errors() {
+        let mut m = Machine::new();
+        let e = m
+            .try_run(
+                &xasm::assemble("fn { 1 \"x\" declare \"y\" load } fn { true } while_loop")
+                    .unwrap(),
+            )
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::UndefinedRegister);
+        assert!(m.scopes.is_empty());
+
+        let e = m
+            .try_run(&xasm::assemble("pop_scope").unwrap())
+            .unwrap_err();
+        assert_eq!(e.kind, ErrorKind::StackUnderflow);
+        assert_eq!(e.message, "No scope to pop");
+    }
+}
@@ -0,0 +1,219 @@
+extern crate xmachine;
+use xmachine::{xasm, ErrorKind, Machine, Value};
+
+mod common;
+use common::try_run_on;
+
+#[cfg(test)]
+mod tail_call {
+    use super::*;
+
+    /// Make a register with a function that calls itself N times
+    /// with CALL, which is `call` or `tail_call`
+    fn countdown(m: &mut Machine, call: &str) {
+        let source = format!(
+            "
+            none \"down\" store
+            fn {{
+                \"n\" store
+                fn {{ \"n\" load }}
+                fn {{ \"n\" load 1 sub \"down\" load {} }}
+                fn {{ \"n\" load 0 gt }}
+                if_then_else
+            }} \"down\" share \"down\" store
+            ",
+            call
+        );
+        try_run_on(m, &source).unwrap();
+    }
+
+    /// Tests that tail recursion runs in constant space
+    #[test]
+    fn constant() {
+        let mut m = Machine::new();
+        m.set_depth_limit(Some(10));
+        countdown(&mut m, "call");
+        assert_eq!(
+            try_run_on(&mut m, "10000 \"down\" load call")
+                .unwrap_err()
+                .kind,
+            ErrorKind::RecursionLimit
+        );
+
+        countdown(&mut m, "tail_call");
+        assert_eq!(
+            try_run_on(&mut m, "10000 \"down\" load call").unwrap(),
+            vec![Value::from(0)]
+        );
+    }
+
+    /// Tests a loop written as tail recursion, with an accumulator,
+    /// and two functions that tail call each other
+    #[test]
+    fn accumulate() {
+        let mut m = Machine::new();
+        m.set_depth_limit(Some(10));
+        assert_eq!(
+            try_run_on(
+                &mut m,
+                "
+                none \"sum\" store
+                fn {
+                    \"total\" store \"n\" store
+                    fn { \"total\" load return }
+                    fn {
+                        \"n\" load 1 sub
+                        \"total\" load \"n\" load add
+                        \"sum\" load tail_call
+                    }
+                    fn { \"n\" load 0 gt }
+                    if_then_else
+                } \"sum\" share \"sum\" store
+                1000 0 \"sum\" load call
+                "
+            )
+            .unwrap(),
+            vec![Value::from(500500)]
+        );
+
+        assert_eq!(
+            try_run_on(
+                &mut m,
+                "
+                none \"even\" store
+                none \"odd\" store
+                fn {
+                    \"n\" store
+                    fn { true }
+                    fn { \"n\" load 1 sub \"odd\" load tail_call }
+                    fn { \"n\" load 0 gt }
+                    if_then_else
+                } \"odd\" share \"even\" share \"even\" store
+                fn {
+                    \"n\" store
+                    fn { false }
+                    fn { \"n\" load 1 sub \"even\" load tail_call }
+                    fn { \"n\" load 0 gt }
+                    if_then_else
+                } \"even\" share \"odd\" store
+                1001 \"even\" load call
+                1001 \"odd\" load call
+                "
+            )
+            .unwrap(),
+            vec![Value::from(false), Value::from(true)]
+        );
+    }
+
+    /// Tests that the rest of the function, or program, is skipped
+    #[test]
+    fn skip() {
+        let mut m = Machine::new();
+        m.registers.insert(
+            String::from("double"),
+            Value::function(
+                |m: &mut Machine| {
+                    let n = m.get_arg();
+                    m.return_value(n * Value::Int(2));
+                },
+                &m,
+            ),
+        );
+        assert_eq!(
+            try_run_on(
+                &mut m,
+                "fn { 1 fn { 2 } tail_call 3 } call 4 fn { 5 } tail_call 6"
+            )
+            .unwrap(),
+            vec![
+                Value::from(1),
+                Value::from(2),
+                Value::from(4),
+                Value::from(5)
+            ]
+        );
+
+        // Native functions are called straight away
+        assert_eq!(
+            try_run_on(&mut m, "fn { 21 \"double\" load tail_call 99 } call").unwrap(),
+            vec![Value::from(42)]
+        );
+        assert_eq!(
+            try_run_on(&mut m, "fn { fn { 5 tail_call } call } call")
+                .unwrap_err()
+                .kind,
+            ErrorKind::NotCallable
+        );
+    }
+
+    /// Tests that a tail call leaves `try_catch` blocks first
+    #[test]
+    fn try_catch() {
+        let mut m = Machine::new();
+        try_run_on(
+            &mut m,
+            "
+            fn { \"missing\" load } \"fail\" store
+            false \"ran\" store
+            fn {
+                fn { true \"ran\" store }
+                fn { \"caught inside\" }
+                fn { \"fail\" load tail_call }
+                try_catch
+            } \"ran\" share \"f\" store
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            try_run_on(
+                &mut m,
+                "fn {} fn { \"kind\" index } fn { \"f\" load call } try_catch \"ran\" load"
+            )
+            .unwrap(),
+            vec![Value::from("UndefinedRegister"), Value::from(true)]
+        );
+
+        // The function that made the tail call isn't in the backtrace
+        let e = m
+            .try_run(&xasm::assemble("\"f\" load call").unwrap())
+            .unwrap_err();
+        assert_eq!(e.trace.len(), 2);
+        assert_eq!(e.trace[0].function, "<main>");
+        assert_eq!(e.trace[1].offset, Some(1));
+        m.stack.clear();
+    }
+
+    /// Tests a coroutine whose function tail calls
+    /// another, which carries on as the coroutine
+    #[test]
+    fn coroutine() {
+        let mut m = Machine::new();
+        try_run_on(
+            &mut m,
+            "
+            fn { \"second\" yield \"done\" return } \"next\" store
+            fn { \"arg\" store \"first\" yield \"next\" load tail_call } coroutine \"co\" store
+            ",
+        )
+        .unwrap();
+        let resume = "none \"co\" load resume";
+        assert_eq!(
+            try_run_on(&mut m, resume).unwrap(),
+            vec![Value::from("first"), Value::from(true)]
+        );
+        assert_eq!(
+            try_run_on(&mut m, resume).unwrap(),
+            vec![Value::from("second"), Value::from(true)]
+        );
+        assert_eq!(
+            try_run_on(&mut m, resume).unwrap(),
+            vec![Value::from("done"), Value::from(false)]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let program = xasm::assemble("fn { \"f\" load tail_call }").unwrap();
+        assert_eq!(program[0].to_string(), "fn { \"f\" load tail_call }");
+    }
+}
@@ -0,0 +1,162 @@
+extern crate xmachine;
+use xmachine::{xasm, Frame, Machine, Value};
+
+#[cfg(test)]
+mod trace {
+    use super::*;
+
+    fn frame(function: &str, offset: Option<usize>) -> Frame {
+        Frame {
+            function: String::from(function),
+            offset,
+        }
+    }
+
+    /// Tests that functions are named after the register they're stored
+    /// in, and that each frame records the instruction it was running
+    #[test]
+    fn named() {
+        let mut m = Machine::new();
+        let e = m
+            .try_run(
+                &xasm::assemble(
+                    "
+                    fn { 1 \"missing\" load } \"inner\" store
+                    fn { \"inner\" load call } \"outer\" store
+                    \"outer\" load call
+                    ",
+                )
+                .unwrap(),
+            )
+            .unwrap_err();
+
+        assert_eq!(
+            &*e.trace,
+            &[
+                frame("<main>", Some(8)),
+                frame("outer", Some(2)),
+                frame("inner", Some(2)),
+            ]
+        );
+        assert_eq!(
+            e.to_string(),
+            "UndefinedRegister: No register named missing (at instruction 2, `load`)
+    in inner at instruction 2
+    in outer at instruction 2
+    in <main> at instruction 8"
+        );
+
+        // The frames are popped when the error unwinds through them
+        assert!(m.frames().is_empty());
+    }
+
+    /// Tests that method calls are named after the method,
+    /// and that foreign functions get frames of their own
+    #[test]
+    fn method_and_native() {
+        let mut m = Machine::new();
+        m.push(Value::function(
+            |m: &mut Machine| m.raise(Value::from("from rust")),
+            &m,
+        ));
+        m.push(Value::string("fail"));
+        m.store();
+
+        let e = m
+            .try_run(
+                &xasm::assemble(
+                    "
+                    tree \"obj\" store
+                    fn { \"self\" store \"fail\" load call }
+                    \"obj\" load \"method\" index assign
+                    \"obj\" load \"method\" method_call
+                    ",
+                )
+                .unwrap(),
+            )
+            .unwrap_err();
+
+        assert_eq!(
+            &*e.trace,
+            &[
+                frame("<main>", Some(12)),
+                frame("method", Some(4)),
+                frame("fail", None),
+            ]
+        );
+    }
+
+    /// Tests that naming a function after its register doesn't
+    /// rename the function for the other values that refer to it
+    #[test]
+    fn aliases() {
+        let mut m = Machine::new();
+        let e = m
+            .try_run(
+                &xasm::assemble(
+                    "
+                    list \"fs\" store
+                    fn { 5 call } \"fs\" load 0 index assign
+                    \"fs\" load 0 index \"f\" store
+                    \"f\" load call
+                    ",
+                )
+                .unwrap(),
+            )
+            .unwrap_err();
+        assert_eq!(e.trace[1].function, "f");
+
+        let e = m
+            .try_run(&xasm::assemble("\"fs\" load 0 index call").unwrap())
+            .unwrap_err();
+        assert!(e.trace[1].function.starts_with("<fn at 0x"));
+    }
+
+    /// Tests that anonymous functions are named after their address,
+    /// and that errors caught by a handler keep no frames behind
+    #[test]
+    fn anonymous() {
+        let mut m = Machine::new();
+        let e = m
+            .try_run(&xasm::assemble("fn { 5 call } call").unwrap())
+            .unwrap_err();
+
+        assert_eq!(e.trace.len(), 2);
+        assert!(e.trace[1].function.starts_with("<fn at 0x"));
+        assert_eq!(e.trace[1].offset, Some(1));
+
+        m.run(
+            &xasm::assemble(
+                "
+                none
+                fn { \"message\" index }
+                fn { \"missing\" load }
+                try_catch
+                ",
+            )
+            .unwrap(),
+        );
+        assert_eq!(m.stack, vec![Value::string("No register named missing")]);
+        assert!(m.frames().is_empty());
+    }
+
+    /// Tests storing an unnamed function in a register named after itself
+    #[test]
+    fn named_after_itself() {
+        let mut m = Machine::new();
+        m.try_run(
+            &xasm::assemble(
+                "
+                fn {} \"f\" declare \"f\" load \"f\" load store
+                list \"xs\" store
+                fn {} \"xs\" load 0 index assign
+                \"xs\" load 0 index \"xs\" load 0 index store
+                ",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(m.registers.contains_key("fn {}"));
+        assert!(m.stack.is_empty());
+    }
+}
@@ -0,0 +1,95 @@
+extern crate xmachine;
+use xmachine::{Machine, Ref, Value};
+
+mod common;
+use common::run_on;
+
+#[cfg(test)]
+mod weak {
+    use super::*;
+
+    /// Tests a child with a link back to its parent
+    #[test]
+    fn parent_link() {
+        let mut m = Machine::new();
+        run_on(
+            &mut m,
+            "
+            tree \"parent\" store
+            \"p\" \"parent\" load \"name\" index assign
+            tree \"child\" store
+            \"parent\" load downgrade \"child\" load \"parent\" index assign
+            \"child\" load \"parent\" load \"child\" index assign
+            ",
+        );
+        assert_eq!(
+            run_on(
+                &mut m,
+                "\"child\" load \"parent\" index upgrade \"name\" index"
+            ),
+            vec![Value::from("p")]
+        );
+
+        // The link doesn't keep the parent alive
+        assert_eq!(
+            run_on(
+                &mut m,
+                "none \"parent\" store \"child\" load \"parent\" index upgrade"
+            ),
+            vec![Value::None]
+        );
+        assert_eq!(m.collect_garbage(), 0);
+    }
+
+    #[test]
+    fn upgrade() {
+        let mut m = Machine::new();
+        // Upgrading gives the same reference, so it can be assigned to
+        assert_eq!(
+            run_on(
+                &mut m,
+                "
+                1 \"x\" store
+                \"x\" load downgrade \"w\" store
+                2 \"w\" load upgrade assign
+                \"x\" load
+                ",
+            ),
+            vec![Value::from(2)]
+        );
+
+        // Other values are pushed back as they are
+        assert_eq!(run_on(&mut m, "5 upgrade"), vec![Value::from(5)]);
+    }
+
+    #[test]
+    fn rust_api() {
+        let target = Value::string("target");
+        let weak = Value::weak(&target);
+        let other = Value::weak(&target);
+        assert_eq!(weak, other);
+        assert!(bool::from(weak.get()));
+
+        match weak.get() {
+            Value::Weak(w) => {
+                assert!(Ref::ptr_eq(&w.upgrade().unwrap(), &target));
+                assert!(!w.is_dropped());
+                drop(target);
+                assert!(w.upgrade().is_none());
+                assert!(w.is_dropped());
+            }
+            value => panic!("expected a weak value, got {}", value),
+        }
+        assert!(!bool::from(weak.get()));
+        assert_ne!(weak, Value::weak(&Value::none()));
+    }
+
+    #[test]
+    fn display() {
+        let target = Value::tree();
+        let weak = Value::weak(&target);
+        assert!(weak.to_string().starts_with("<weak at 0x"));
+        drop(target);
+        assert_eq!(weak.to_string(), "<weak (dropped)>");
+    }
+}
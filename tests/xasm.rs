@@ -0,0 +1,181 @@
+extern crate xmachine;
+use xmachine::{xasm, Instruction, Literal, Machine, Value, MAX_NESTING};
+
+#[cfg(test)]
+mod assembler {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        assert_eq!(
+            xasm::assemble("5 -2.5 \"a \\\"b\\\"\\n\" list tree none"),
+            Ok(vec![
+                Instruction::Push(Literal::Int(5)),
+                Instruction::Push(Literal::Number(-2.5)),
+                Instruction::Push(Literal::String(String::from("a \"b\"\n"))),
+                Instruction::Push(Literal::List),
+                Instruction::Push(Literal::Tree),
+                Instruction::Push(Literal::None),
+            ])
+        );
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(
+            xasm::assemble("fn { \"a\" load ; comment\n fn {} } call"),
+            Ok(vec![
+                Instruction::Push(Literal::Function(vec![
+                    Instruction::Push(Literal::String(String::from("a"))),
+                    Instruction::Load,
+                    Instruction::Push(Literal::Function(vec![])),
+                ])),
+                Instruction::Call,
+            ])
+        );
+    }
+
+    /// Tests the while loop from the `iterative` tests as assembly
+    #[test]
+    fn run() {
+        let mut m = Machine::new();
+        m.push(Value::function(
+            |m: &mut Machine| {
+                let n1 = m.get_arg();
+                let n2 = m.get_arg();
+                m.return_value(n1 - n2);
+            },
+            &m,
+        ));
+        m.push(Value::string("sub"));
+        m.store();
+
+        let program = xasm::assemble(
+            "
+            5 \"test\" store
+            fn {
+                \"test\" load
+                1 \"test\" load \"sub\" load call
+                \"test\" store
+            }
+            fn { \"test\" load }
+            while_loop
+            ",
+        )
+        .unwrap();
+        m.run(&program);
+
+        assert_eq!(
+            m.stack,
+            vec![
+                Value::number(5),
+                Value::number(4),
+                Value::number(3),
+                Value::number(2),
+                Value::number(1),
+            ]
+        )
+    }
+
+    #[test]
+    fn errors() {
+        let error = |line, column, message: &str| {
+            Err(xasm::ParseError {
+                line,
+                column,
+                message: String::from(message),
+            })
+        };
+
+        assert_eq!(
+            xasm::assemble("1 2\n  jump"),
+            error(2, 3, "Unknown instruction `jump`")
+        );
+        assert_eq!(
+            xasm::assemble("fn {\n  1 fn { 2 }"),
+            error(1, 1, "Unclosed `fn {` block")
+        );
+        assert_eq!(
+            xasm::assemble("\"abc"),
+            error(1, 1, "Unterminated string literal")
+        );
+        assert_eq!(
+            xasm::assemble("1.2.3"),
+            error(1, 1, "Invalid number `1.2.3`")
+        );
+        assert_eq!(
+            xasm::assemble("call }"),
+            error(1, 6, "Unexpected `}` without a matching `fn {`")
+        );
+        assert_eq!(
+            xasm::assemble("fn call"),
+            error(1, 4, "Expected `{` after `fn`")
+        );
+    }
+
+    /// Tests that functions nested too deeply are an error,
+    /// instead of overflowing the stack while parsing them
+    #[test]
+    fn nesting() {
+        let nested = |depth| "fn { ".repeat(depth) + &"}".repeat(depth);
+        assert!(xasm::assemble(&nested(MAX_NESTING)).is_ok());
+
+        let e = xasm::assemble(&nested(MAX_NESTING + 1)).unwrap_err();
+        assert_eq!((e.line, e.column), (1, 5 * MAX_NESTING + 1));
+        assert_eq!(
+            e.message,
+            format!("Functions are nested deeper than {} levels", MAX_NESTING)
+        );
+        assert!(xasm::assemble(&nested(200_000)).is_err());
+    }
+
+    #[test]
+    fn disassemble() {
+        let program = xasm::assemble("5 \"n\" store fn { \"n\" load fn {} } call").unwrap();
+
+        assert_eq!(
+            xasm::disassemble(&program),
+            "\
+0000  5
+0001  \"n\"
+0002  store
+0003  fn {
+0000      \"n\"
+0001      load
+0002      fn {
+          }
+      }
+0004  call
+"
+        );
+    }
+
+    /// Tests that rendering a program and parsing it again gives the same program
+    #[test]
+    fn round_trip() {
+        let source = "\"a\\tb\\\\\" -1.5 inf list tree none fn { copy assign index } method_call \
+                      for_loop while_loop if_then_else fn {} store load";
+        let program = xasm::assemble(source).unwrap();
+        let rendered = program
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        assert_eq!(xasm::assemble(&rendered), Ok(program));
+    }
+
+    #[test]
+    fn display_function() {
+        let m = Machine::new();
+        let f = Value::program(xasm::assemble("\"n\" load fn { call }").unwrap(), &m);
+
+        assert_eq!(format!("{}", f), "fn { \"n\" load fn { call } }");
+        assert_eq!(
+            format!("{:#}", f),
+            "fn {\n0000  \"n\"\n0001  load\n0002  fn {\n0000      call\n      }\n}"
+        );
+        assert!(format!("{}", Value::function(|_: &mut Machine| {}, &m))
+            .starts_with("<native fn at 0x"));
+    }
+}